@@ -64,6 +64,76 @@ async function getClientData (id) {
   return await res.json();
 }
 
+async function getClientMetric (id, metric, seconds) {
+  const since = Math.floor(Date.now() / 1000) - seconds;
+  const res = await fetch(`/api/proxy/${id}?metric=${metric}&since=${since}`);
+  return await res.json();
+}
+
+function showHeatmap (series) {
+  const container = document.createElement("div");
+  container.className = "heatmap";
+  const containerElement = document.getElementById("container");
+  containerElement.append(container);
+
+  const cells = series.flatMap(s => s.samples.map(([t, v]) => ({ core: s.labels.core, t, v })));
+  if (cells.length === 0) {
+    container.textContent = "No per-core data yet";
+    return;
+  }
+
+  const width = 640;
+  const marginTop = 10;
+  const marginRight = 20;
+  const marginBottom = 30;
+  const marginLeft = 40;
+  const cores = series.map(s => s.labels.core).sort((a, b) => a - b);
+  const times = [...new Set(cells.map(c => c.t))].sort((a, b) => a - b);
+  const height = marginTop + marginBottom + Math.max(cores.length * 12, 40);
+
+  const x = d3.scaleBand()
+	.domain(times)
+	.range([marginLeft, width - marginRight]);
+
+  const y = d3.scaleBand()
+	.domain(cores)
+	.range([marginTop, height - marginBottom]);
+
+  const color = d3.scaleLinear()
+	.domain([0, 100])
+	.range(["var(--background)", "var(--foreground)"]);
+
+  const svg = d3.create("svg")
+	.attr("width", width)
+	.attr("height", height);
+
+  // Only label a few times so they don't overlap
+  const tickEvery = Math.ceil(times.length / 6);
+  svg.append("g")
+	.attr("transform", `translate(0,${height - marginBottom})`)
+	.call(d3.axisBottom(x)
+	      .tickValues(times.filter((_, i) => i % tickEvery === 0))
+	      .tickFormat(t => d3.utcFormat("%H:%M")(new Date(t * 1000))));
+
+  svg.append("g")
+	.attr("transform", `translate(${marginLeft},0)`)
+	.call(d3.axisLeft(y));
+
+  svg.append("g")
+    .selectAll("rect")
+    .data(cells)
+    .join("rect")
+      .attr("x", d => x(d.t))
+      .attr("y", d => y(d.core))
+      .attr("width", x.bandwidth())
+      .attr("height", y.bandwidth())
+      .attr("fill", d => color(d.v))
+    .append("title")
+      .text(d => `core ${d.core}: ${d.v.toFixed(1)}%`);
+
+  container.append(svg.node());
+}
+
 function getClientLoader(client) {
   return async function () {
     const containerElement = document.getElementById("container");
@@ -71,17 +141,305 @@ function getClientLoader(client) {
     const data = await getClientData(client.id);
     console.log(data);
     const headerElement = document.createElement("h2");
-    headerElement.textContent = client.address;
+    headerElement.textContent = clientLabel(client);
     const graphHeaderElement = document.createElement("h3");
     graphHeaderElement.textContent = "CPU Usage";
     const backElement = document.createElement("button");
     backElement.textContent = "Back";
     backElement.onclick = refreshClients;
+    const logsElement = document.createElement("button");
+    logsElement.textContent = "Logs";
+    logsElement.onclick = () => showLogs(client);
     containerElement.appendChild(backElement);
+    containerElement.appendChild(logsElement);
     containerElement.appendChild(headerElement);
     containerElement.appendChild(graphHeaderElement);
     showChart(data.reverse());
+    const coreHeaderElement = document.createElement("h3");
+    coreHeaderElement.textContent = "Per-Core Usage";
+    containerElement.appendChild(coreHeaderElement);
+    showHeatmap(await getClientMetric(client.id, "cpu_core_usage", 600));
+    if (client.watched) {
+      showWatched(client.watched);
+    }
+    if (client.checks) {
+      showChecks(client.checks);
+    }
+    showProcesses(client);
+    if (client.name) {
+      showTimeline(await getClientEvents(client.name));
+    }
+  }
+}
+
+async function getProcesses (id, at) {
+  const res = await fetch(`/api/proxy/${id}?processes_at=${at}`);
+  return await res.json();
+}
+
+function showProcesses (client) {
+  const containerElement = document.getElementById("container");
+  const headerElement = document.createElement("h3");
+  headerElement.textContent = "Processes";
+  containerElement.appendChild(headerElement);
+
+  // datetime-local works in local time, without the timezone offset
+  const toLocalInput = date => new Date(date.getTime() - date.getTimezoneOffset() * 60000)
+    .toISOString().slice(0, 16);
+  const timeElement = document.createElement("input");
+  timeElement.type = "datetime-local";
+  timeElement.value = toLocalInput(new Date());
+  const showElement = document.createElement("button");
+  showElement.textContent = "Show";
+  const snapshotElement = document.createElement("div");
+  snapshotElement.className = "processes";
+  containerElement.appendChild(timeElement);
+  containerElement.appendChild(showElement);
+  containerElement.appendChild(snapshotElement);
+
+  async function load () {
+    const at = Math.floor(new Date(timeElement.value).getTime() / 1000);
+    const snapshot = await getProcesses(client.id, at);
+    snapshotElement.innerHTML = "";
+    if (!snapshot) {
+      snapshotElement.textContent = "No snapshot before that time";
+      return;
+    }
+    const takenElement = document.createElement("p");
+    takenElement.textContent = `Snapshot taken ${new Date(snapshot.timestamp * 1000).toLocaleString()}`;
+    snapshotElement.appendChild(takenElement);
+    const tableElement = document.createElement("table");
+    const headerRow = tableElement.insertRow();
+    ["PID", "User", "CPU %", "RSS (MB)", "Command"].forEach(h => {
+      const th = document.createElement("th");
+      th.textContent = h;
+      headerRow.appendChild(th);
+    });
+    snapshot.processes.forEach(p => {
+      const row = tableElement.insertRow();
+      [
+        p.pid,
+        p.user ?? "",
+        p.cpu_usage.toFixed(1),
+        (p.memory / 1048576).toFixed(1),
+        p.command || p.name,
+      ].forEach(v => { row.insertCell().textContent = v; });
+    });
+    snapshotElement.appendChild(tableElement);
   }
+
+  showElement.onclick = load;
+  load();
+}
+
+function showWatched (processes) {
+  const containerElement = document.getElementById("container");
+  const headerElement = document.createElement("h3");
+  headerElement.textContent = "Watched Processes";
+  containerElement.appendChild(headerElement);
+  const tableElement = document.createElement("table");
+  tableElement.className = "watched";
+  const headerRow = tableElement.insertRow();
+  ["Process", "State", "PID", "Restarts", "CPU %", "RSS (MB)"].forEach(h => {
+    const th = document.createElement("th");
+    th.textContent = h;
+    headerRow.appendChild(th);
+  });
+  processes.forEach(p => {
+    const row = tableElement.insertRow();
+    row.className = p.up ? "up" : "down";
+    [
+      p.name,
+      p.up ? "up" : "down",
+      p.pid ?? "",
+      p.restarts,
+      p.cpu_usage.toFixed(1),
+      (p.memory / 1048576).toFixed(1),
+    ].forEach(v => { row.insertCell().textContent = v; });
+  });
+  containerElement.appendChild(tableElement);
+}
+
+function showChecks (checks) {
+  const containerElement = document.getElementById("container");
+  const headerElement = document.createElement("h3");
+  headerElement.textContent = "Checks";
+  containerElement.appendChild(headerElement);
+  const tableElement = document.createElement("table");
+  tableElement.className = "checks";
+  const headerRow = tableElement.insertRow();
+  ["Check", "Status", "Output", "Last Run", "Duration (ms)"].forEach(h => {
+    const th = document.createElement("th");
+    th.textContent = h;
+    headerRow.appendChild(th);
+  });
+  checks.forEach(c => {
+    const row = tableElement.insertRow();
+    row.className = c.status;
+    [
+      c.name,
+      c.status,
+      c.output,
+      new Date(c.last_run * 1000).toLocaleString(),
+      c.duration_ms.toFixed(0),
+    ].forEach(v => { row.insertCell().textContent = v; });
+  });
+  containerElement.appendChild(tableElement);
+}
+
+// The stream of the logs being followed, closed when leaving the logs view
+let logStream = null;
+
+function stopFollowing () {
+  if (logStream) {
+    logStream.close();
+    logStream = null;
+  }
+}
+
+function logParams (grep, invert, file) {
+  const params = new URLSearchParams();
+  if (grep) {
+    params.set("grep", grep);
+  }
+  if (invert) {
+    params.set("invert", "true");
+  }
+  if (file) {
+    params.set("file", file);
+  }
+  return params;
+}
+
+function showLogs (client) {
+  stopFollowing();
+  const containerElement = document.getElementById("container");
+  containerElement.innerHTML = "";
+  const backElement = document.createElement("button");
+  backElement.textContent = "Back";
+  backElement.onclick = () => {
+    stopFollowing();
+    getClientLoader(client)();
+  };
+  const headerElement = document.createElement("h2");
+  headerElement.textContent = `Logs - ${clientLabel(client)}`;
+  containerElement.appendChild(backElement);
+  containerElement.appendChild(headerElement);
+
+  const grepElement = document.createElement("input");
+  grepElement.placeholder = "grep (regular expression)";
+  const invertElement = document.createElement("input");
+  invertElement.type = "checkbox";
+  const invertLabel = document.createElement("label");
+  invertLabel.append(invertElement, " invert");
+  const fileElement = document.createElement("select");
+  const fetchElement = document.createElement("button");
+  fetchElement.textContent = "Fetch";
+  const followElement = document.createElement("button");
+  followElement.textContent = "Follow";
+  const statusElement = document.createElement("p");
+  const linesElement = document.createElement("pre");
+  linesElement.className = "logs";
+  containerElement.append(grepElement, invertLabel, fileElement, fetchElement, followElement,
+    statusElement, linesElement);
+
+  function showFiles (files) {
+    const selected = fileElement.value;
+    fileElement.innerHTML = "";
+    ["", ...files].forEach(f => {
+      const optionElement = document.createElement("option");
+      optionElement.value = f;
+      optionElement.textContent = f || "all files";
+      fileElement.appendChild(optionElement);
+    });
+    fileElement.value = selected;
+  }
+
+  function append (result) {
+    if (result.files.length === 0) {
+      statusElement.textContent = "This client doesn't tail any log files";
+    } else if (result.truncated) {
+      statusElement.textContent = "Some lines were left out";
+    }
+    if (fileElement.options.length !== result.files.length + 1) {
+      showFiles(result.files);
+    }
+    // Stay at the bottom while following, unless scrolled up to read
+    const atBottom = linesElement.scrollTop + linesElement.clientHeight >= linesElement.scrollHeight - 5;
+    result.lines.forEach(l => {
+      const time = new Date(l.timestamp * 1000).toLocaleString();
+      linesElement.append(`${time} ${l.file}: ${l.line}\n`);
+    });
+    if (atBottom) {
+      linesElement.scrollTop = linesElement.scrollHeight;
+    }
+  }
+
+  function params () {
+    return logParams(grepElement.value, invertElement.checked, fileElement.value);
+  }
+
+  async function load () {
+    stopFollowing();
+    followElement.textContent = "Follow";
+    linesElement.textContent = "";
+    statusElement.textContent = "";
+    const query = params();
+    query.set("logs", "true");
+    const res = await fetch(`/api/proxy/${client.id}?${query}`);
+    const result = await res.json();
+    if (result.error || !result.lines) {
+      statusElement.textContent = result.error ?? result.message ?? "The client could not be asked for its logs";
+      return;
+    }
+    append(result);
+  }
+
+  function follow () {
+    if (logStream) {
+      stopFollowing();
+      followElement.textContent = "Follow";
+      statusElement.textContent = "Stopped following";
+      return;
+    }
+    linesElement.textContent = "";
+    statusElement.textContent = "Following...";
+    followElement.textContent = "Stop";
+    logStream = new EventSource(`/api/logs/${client.id}?${params()}`);
+    logStream.addEventListener("lines", e => append(JSON.parse(e.data)));
+    logStream.addEventListener("failure", e => {
+      stopFollowing();
+      followElement.textContent = "Follow";
+      statusElement.textContent = e.data;
+    });
+  }
+
+  fetchElement.onclick = load;
+  followElement.onclick = follow;
+  load();
+}
+
+async function getClientEvents (name) {
+  const res = await fetch(`/api/events?client=${encodeURIComponent(name)}&limit=50`);
+  return (await res.json()).events;
+}
+
+function showTimeline (events) {
+  const containerElement = document.getElementById("container");
+  const headerElement = document.createElement("h3");
+  headerElement.textContent = "Connection History";
+  containerElement.appendChild(headerElement);
+  const listElement = document.createElement("ul");
+  listElement.className = "timeline";
+  events.forEach(e => {
+    const itemElement = document.createElement("li");
+    const time = new Date(e.timestamp * 1000).toLocaleString();
+    const reason = e.reason ? ` (${e.reason})` : "";
+    itemElement.textContent = `${time} ${e.kind} from ${e.remote_addr}${reason}`;
+    itemElement.className = e.kind;
+    listElement.appendChild(itemElement);
+  });
+  containerElement.appendChild(listElement);
 }
 
 async function getClients () {
@@ -89,15 +447,41 @@ async function getClients () {
   return (await res.json()).clients;
 }
 
+function clientLabel (client) {
+  const label = client.name ? `${client.name} (${client.address})` : client.address;
+  return client.site ? `[${client.site}] ${label}` : label;
+}
+
 async function refreshClients() {
+  stopFollowing();
   clients = await getClients();
   const containerElement = document.getElementById("container");
   containerElement.innerHTML = "";
   clients.forEach(c => {
     const clientElement = document.createElement("div");
-    clientElement.textContent = c.address;
     clientElement.className = "client";
-    clientElement.onclick = getClientLoader(c);
+    if (c.online) {
+      clientElement.textContent = clientLabel(c);
+      const down = (c.watched ?? []).filter(p => !p.up).map(p => p.name);
+      if (down.length > 0) {
+        const downElement = document.createElement("span");
+        downElement.className = "down";
+        downElement.textContent = ` - down: ${down.join(", ")}`;
+        clientElement.appendChild(downElement);
+      }
+      const failing = (c.checks ?? []).filter(check => check.status !== "ok").map(check => check.name);
+      if (failing.length > 0) {
+        const failingElement = document.createElement("span");
+        failingElement.className = "down";
+        failingElement.textContent = ` - failing: ${failing.join(", ")}`;
+        clientElement.appendChild(failingElement);
+      }
+      clientElement.onclick = getClientLoader(c);
+    } else {
+      const lastSeen = new Date(c.last_seen * 1000).toLocaleString();
+      clientElement.textContent = `${clientLabel(c)} - offline, last seen ${lastSeen}`;
+      clientElement.classList.add("offline");
+    }
     containerElement.appendChild(clientElement);
   });
 }
@@ -0,0 +1,251 @@
+use crate::config::Options;
+use crate::db;
+use crate::notifier;
+use crate::shutdown::Shutdown;
+use crate::utils;
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Arc;
+use tokio::sync::RwLock;
+use tokio::time::{self, Duration, Instant};
+
+const FLUSH_INTERVAL: u64 = 1;
+const DELIVERY_LOG_LIMIT: u32 = 100;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AlertStatus {
+    Firing,
+    Resolved,
+}
+
+impl AlertStatus {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            AlertStatus::Firing => "firing",
+            AlertStatus::Resolved => "resolved",
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct Alert {
+    pub name: String,
+    pub labels: BTreeMap<String, String>,
+    pub summary: String,
+    pub status: AlertStatus,
+    pub starts_at: i64,
+    pub ends_at: Option<i64>,
+}
+
+impl Alert {
+    /// Identifies an alert by its name and label set.
+    pub fn fingerprint(&self) -> String {
+        fingerprint(&self.name, &self.labels)
+    }
+
+    /// Labels including the implicit `alertname` label.
+    pub fn all_labels(&self) -> BTreeMap<String, String> {
+        let mut labels = self.labels.clone();
+        labels.insert("alertname".to_string(), self.name.clone());
+        labels
+    }
+}
+
+/// A batch of alerts sharing the same values for the configured `group_by` labels.
+pub struct Notification {
+    pub group_key: String,
+    pub group_labels: BTreeMap<String, String>,
+    pub status: AlertStatus,
+    pub alerts: Vec<Alert>,
+}
+
+struct Group {
+    labels: BTreeMap<String, String>,
+    alerts: BTreeMap<String, Alert>,
+    changed_at: Option<Instant>,
+    last_sent: Option<Instant>,
+}
+
+static GROUPS: Lazy<RwLock<HashMap<String, Group>>> = Lazy::new(|| RwLock::new(HashMap::new()));
+
+fn fingerprint(name: &str, labels: &BTreeMap<String, String>) -> String {
+    let pairs: Vec<String> = labels.iter().map(|(k, v)| format!("{k}={v}")).collect();
+    format!("{name}{{{}}}", pairs.join(","))
+}
+
+fn group_of(alert: &Alert, group_by: &[String]) -> (String, BTreeMap<String, String>) {
+    let all_labels = alert.all_labels();
+    let labels: BTreeMap<String, String> = group_by
+        .iter()
+        .filter_map(|k| all_labels.get(k).map(|v| (k.clone(), v.clone())))
+        .collect();
+    (fingerprint("", &labels), labels)
+}
+
+/// Marks an alert as firing. Re-firing an alert that is already firing is a no-op.
+pub async fn fire(name: &str, labels: BTreeMap<String, String>, summary: &str) {
+    let config = Options::new();
+    let alert = Alert {
+        name: name.to_string(),
+        labels,
+        summary: summary.to_string(),
+        status: AlertStatus::Firing,
+        starts_at: utils::unix_timestamp(),
+        ends_at: None,
+    };
+    let (key, group_labels) = group_of(&alert, &config.alerts.group_by);
+    let mut groups = GROUPS.write().await;
+    let group = groups.entry(key).or_insert_with(|| Group {
+        labels: group_labels,
+        alerts: BTreeMap::new(),
+        changed_at: None,
+        last_sent: None,
+    });
+    let fp = alert.fingerprint();
+    if matches!(group.alerts.get(&fp), Some(a) if a.status == AlertStatus::Firing) {
+        return;
+    }
+    println!("Alert firing: {fp}");
+    group.alerts.insert(fp, alert);
+    group.changed_at.get_or_insert_with(Instant::now);
+}
+
+/// Marks a firing alert as resolved. Resolving an unknown alert is a no-op.
+pub async fn resolve(name: &str, labels: &BTreeMap<String, String>) {
+    let fp = fingerprint(name, labels);
+    let mut groups = GROUPS.write().await;
+    for group in groups.values_mut() {
+        if let Some(alert) = group.alerts.get_mut(&fp) {
+            if alert.status == AlertStatus::Firing {
+                println!("Alert resolved: {fp}");
+                alert.status = AlertStatus::Resolved;
+                alert.ends_at = Some(utils::unix_timestamp());
+                group.changed_at.get_or_insert_with(Instant::now);
+            }
+            return;
+        }
+    }
+}
+
+/// Collects the groups that are due for a notification: new changes that have
+/// waited out `group_wait`, or still-firing groups past `repeat_interval`.
+async fn due_notifications(group_wait: Duration, repeat_interval: Duration) -> Vec<Notification> {
+    let now = Instant::now();
+    let mut groups = GROUPS.write().await;
+    let mut due = Vec::new();
+    for (key, group) in groups.iter_mut() {
+        let changed = group
+            .changed_at
+            .is_some_and(|t| now.duration_since(t) >= group_wait);
+        let firing = group
+            .alerts
+            .values()
+            .any(|a| a.status == AlertStatus::Firing);
+        let repeat = firing
+            && group.changed_at.is_none()
+            && group
+                .last_sent
+                .is_some_and(|t| now.duration_since(t) >= repeat_interval);
+        if !changed && !repeat {
+            continue;
+        }
+        due.push(Notification {
+            group_key: key.clone(),
+            group_labels: group.labels.clone(),
+            status: if firing {
+                AlertStatus::Firing
+            } else {
+                AlertStatus::Resolved
+            },
+            alerts: group.alerts.values().cloned().collect(),
+        });
+        group.changed_at = None;
+        group.last_sent = Some(now);
+        // Resolved alerts are only reported once
+        group.alerts.retain(|_, a| a.status == AlertStatus::Firing);
+    }
+    groups.retain(|_, g| !g.alerts.is_empty());
+    due
+}
+
+pub async fn run(shutdown: Shutdown) {
+    let token = shutdown.token();
+    let config = Options::new();
+    if config.alerts.channels.is_empty() {
+        println!("No alert channels configured, notifications are disabled");
+    }
+    let mut interval = time::interval(Duration::from_secs(FLUSH_INTERVAL));
+
+    loop {
+        // Wait for the next interval or until interrupted
+        tokio::select! {
+            _ = interval.tick() => {},
+            () = token.cancelled() => break,
+        }
+        for notification in
+            due_notifications(config.alerts.group_wait, config.alerts.repeat_interval).await
+        {
+            let notification = Arc::new(notification);
+            for channel in &config.alerts.channels {
+                // Tracked so deliveries under way finish before the hub exits
+                shutdown.spawn(
+                    "alert delivery",
+                    notifier::deliver(channel.clone(), notification.clone(), token.clone()),
+                );
+            }
+        }
+    }
+
+    println!("Alert notification loop exiting");
+}
+
+#[derive(Serialize)]
+struct AlertsResponse {
+    alerts: Vec<Alert>,
+}
+
+#[derive(Serialize)]
+struct DeliveriesResponse {
+    deliveries: Vec<db::Delivery>,
+}
+
+pub async fn handler() -> Result<impl warp::Reply, warp::Rejection> {
+    let groups = GROUPS.read().await;
+    let alerts = groups
+        .values()
+        .flat_map(|g| g.alerts.values().cloned())
+        .collect();
+    Ok(warp::reply::json(&AlertsResponse { alerts }))
+}
+
+pub async fn deliveries_handler() -> Result<impl warp::Reply, warp::Rejection> {
+    match db::get_deliveries(DELIVERY_LOG_LIMIT) {
+        Ok(deliveries) => Ok(warp::reply::json(&DeliveriesResponse { deliveries })),
+        Err(e) => {
+            eprintln!("Error reading delivery log: {e}");
+            Err(warp::reject::custom(db::DatabaseError))
+        }
+    }
+}
+
+#[derive(Deserialize)]
+pub struct AlertRequest {
+    name: String,
+    #[serde(default)]
+    labels: BTreeMap<String, String>,
+    #[serde(default)]
+    summary: String,
+    status: AlertStatus,
+}
+
+/// Raises or resolves an alert from outside the hub, e.g. to exercise the
+/// configured channels against a local HTTP stand-in.
+pub async fn post_handler(req: AlertRequest) -> Result<impl warp::Reply, warp::Rejection> {
+    match req.status {
+        AlertStatus::Firing => fire(&req.name, req.labels, &req.summary).await,
+        AlertStatus::Resolved => resolve(&req.name, &req.labels).await,
+    }
+    Ok(warp::reply::html("Success"))
+}
@@ -0,0 +1,100 @@
+use rand::Rng;
+use std::time::Duration;
+
+/// Exponential backoff with an upper bound and optional full jitter.
+#[derive(Clone, Copy, Debug)]
+pub struct BackoffPolicy {
+    pub base: Duration,
+    pub max: Duration,
+    pub jitter: bool,
+    pub max_retries: Option<u32>,
+}
+
+impl BackoffPolicy {
+    /// The capped exponential delay before the given retry (1-based): base, 2x base, 4x base, etc.
+    pub fn ceiling(&self, retry: u32) -> Duration {
+        let factor = 2u32.saturating_pow(retry.saturating_sub(1));
+        self.base.saturating_mul(factor).min(self.max)
+    }
+
+    /// The delay before the given retry. With jitter the delay is drawn uniformly
+    /// from zero up to the ceiling, so clients that failed together don't retry together.
+    pub fn delay(&self, retry: u32, rng: &mut impl Rng) -> Duration {
+        let ceiling = self.ceiling(retry);
+        if self.jitter {
+            ceiling.mul_f64(rng.gen::<f64>())
+        } else {
+            ceiling
+        }
+    }
+
+    /// Whether the given number of retries uses up the retry budget.
+    pub fn exhausted(&self, retries: u32) -> bool {
+        self.max_retries.is_some_and(|max| retries >= max)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+
+    fn policy(jitter: bool) -> BackoffPolicy {
+        BackoffPolicy {
+            base: Duration::from_secs(1),
+            max: Duration::from_secs(60),
+            jitter,
+            max_retries: None,
+        }
+    }
+
+    #[test]
+    fn ceiling_doubles_up_to_the_max() {
+        let policy = policy(false);
+        let ceilings: Vec<u64> = (1..=8).map(|r| policy.ceiling(r).as_secs()).collect();
+        assert_eq!(ceilings, [1, 2, 4, 8, 16, 32, 60, 60]);
+        // Retry counts past any sensible exponent saturate instead of overflowing
+        assert_eq!(policy.ceiling(u32::MAX), Duration::from_secs(60));
+    }
+
+    #[test]
+    fn delay_without_jitter_is_the_ceiling() {
+        let policy = policy(false);
+        let mut rng = StdRng::seed_from_u64(7);
+        for retry in 1..=10 {
+            assert_eq!(policy.delay(retry, &mut rng), policy.ceiling(retry));
+        }
+    }
+
+    #[test]
+    fn delay_with_jitter_stays_below_the_ceiling() {
+        let policy = policy(true);
+        let mut rng = StdRng::seed_from_u64(7);
+        let delays: Vec<Duration> = (1..=100).map(|r| policy.delay(r, &mut rng)).collect();
+        for (retry, delay) in (1..).zip(&delays) {
+            assert!(*delay <= policy.ceiling(retry));
+        }
+        // Clients drawing the same retry still spread out
+        let mut rng = StdRng::seed_from_u64(7);
+        let spread: Vec<Duration> = (0..10).map(|_| policy.delay(8, &mut rng)).collect();
+        assert!(spread.iter().any(|d| *d != spread[0]));
+        // A seeded rng makes the schedule reproducible
+        let mut rng = StdRng::seed_from_u64(7);
+        let again: Vec<Duration> = (1..=100).map(|r| policy.delay(r, &mut rng)).collect();
+        assert_eq!(delays, again);
+    }
+
+    #[test]
+    fn exhausted_after_max_retries() {
+        let unlimited = policy(false);
+        assert!(!unlimited.exhausted(u32::MAX));
+        let limited = BackoffPolicy {
+            max_retries: Some(3),
+            ..unlimited
+        };
+        assert!(!limited.exhausted(2));
+        assert!(limited.exhausted(3));
+        assert!(limited.exhausted(4));
+    }
+}
@@ -0,0 +1,463 @@
+use crate::collectors::{sample, Collector};
+use crate::config::{CheckConfig, CheckFormat};
+use crate::db::Sample;
+use crate::line_protocol;
+use crate::protocol::{CheckState, CheckStatus};
+use crate::utils;
+use once_cell::sync::Lazy;
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::io::Read;
+use std::process::{Command, Stdio};
+use std::sync::{mpsc, RwLock};
+use std::thread;
+use std::time::{Duration, Instant};
+
+pub const METRICS: &[(&str, &[&str])] = &[
+    ("check_status", &["check"]),
+    ("check_duration_seconds", &["check"]),
+    ("check_perfdata", &["check", "label", "unit"]),
+];
+
+// How often to look whether a running check has exited
+const POLL_INTERVAL: Duration = Duration::from_millis(20);
+
+// The latest run of every check, reported to the hub by the websocket client
+static STATES: Lazy<RwLock<BTreeMap<String, CheckState>>> =
+    Lazy::new(|| RwLock::new(BTreeMap::new()));
+
+pub fn states() -> Vec<CheckState> {
+    STATES.read().unwrap().values().cloned().collect()
+}
+
+struct Output {
+    /// `None` when the check was killed or died from a signal
+    code: Option<i32>,
+    stdout: String,
+    timed_out: bool,
+}
+
+/// Runs the check's command, killing it once it runs past its timeout.
+fn execute(config: &CheckConfig) -> std::io::Result<Output> {
+    let timeout = Duration::from_secs(config.timeout);
+    let deadline = Instant::now() + timeout;
+    let mut child = Command::new(&config.command)
+        .args(&config.args)
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()?;
+
+    // Read on another thread so a check printing more than the pipe holds
+    // doesn't block before exiting
+    let mut stdout = child.stdout.take().expect("stdout is piped");
+    let (tx, rx) = mpsc::channel();
+    thread::spawn(move || {
+        let mut out = Vec::new();
+        let _ = stdout.read_to_end(&mut out);
+        let _ = tx.send(String::from_utf8_lossy(&out).into_owned());
+    });
+
+    let status = loop {
+        if let Some(status) = child.try_wait()? {
+            break Some(status);
+        }
+        if Instant::now() >= deadline {
+            let _ = child.kill();
+            let _ = child.wait();
+            break None;
+        }
+        thread::sleep(POLL_INTERVAL);
+    };
+    // Anything the check started in the background may still hold its stdout open
+    let stdout = rx
+        .recv_timeout(deadline.saturating_duration_since(Instant::now()))
+        .unwrap_or_default();
+    Ok(Output {
+        code: status.and_then(|s| s.code()),
+        stdout,
+        timed_out: status.is_none(),
+    })
+}
+
+/// Splits a perfdata string into its `label=value;warn;crit;min;max` entries,
+/// keeping quoted labels with spaces in one piece.
+fn perfdata_entries(perfdata: &str) -> Vec<String> {
+    let mut entries = Vec::new();
+    let mut entry = String::new();
+    let mut quoted = false;
+    for c in perfdata.chars() {
+        if c == '\'' {
+            quoted = !quoted;
+        }
+        if c.is_whitespace() && !quoted {
+            if !entry.is_empty() {
+                entries.push(std::mem::take(&mut entry));
+            }
+        } else {
+            entry.push(c);
+        }
+    }
+    if !entry.is_empty() {
+        entries.push(entry);
+    }
+    entries
+}
+
+/// Converts a perfdata value to base units, naming the unit it ends up in.
+fn base_unit(value: f64, uom: &str) -> Option<(f64, &'static str)> {
+    Some(match uom {
+        "" => (value, ""),
+        "s" => (value, "seconds"),
+        "ms" => (value / 1e3, "seconds"),
+        "us" => (value / 1e6, "seconds"),
+        "%" => (value, "percent"),
+        "B" => (value, "bytes"),
+        "KB" => (value * 1024.0, "bytes"),
+        "MB" => (value * 1024.0 * 1024.0, "bytes"),
+        "GB" => (value * 1024.0 * 1024.0 * 1024.0, "bytes"),
+        "TB" => (value * 1024.0 * 1024.0 * 1024.0 * 1024.0, "bytes"),
+        "c" => (value, "counter"),
+        _ => return None,
+    })
+}
+
+/// One perfdata entry, e.g. `'root fs'=42.5%;80;90;0;100`, as its label, value and unit.
+fn parse_perfdata(entry: &str) -> Option<(String, f64, &'static str)> {
+    let (label, rest) = entry.rsplit_once('=')?;
+    let label = label
+        .strip_prefix('\'')
+        .and_then(|l| l.strip_suffix('\''))
+        .unwrap_or(label)
+        .replace("''", "'");
+    let value = rest.split(';').next()?;
+    let split = value
+        .find(|c: char| !(c.is_ascii_digit() || matches!(c, '.' | '-' | '+' | 'e' | 'E')))
+        .unwrap_or(value.len());
+    let (number, uom) = value.split_at(split);
+    let (value, unit) = base_unit(number.parse().ok()?, uom)?;
+    Some((label, value, unit))
+}
+
+/// The text of Nagios plugin output, and the perfdata after the `|` on its first
+/// line and in its long output.
+fn parse_nagios(check: &str, stdout: &str) -> (String, Vec<Sample>) {
+    let mut lines = stdout.lines();
+    let first = lines.next().unwrap_or_default();
+    let (text, mut perfdata) = match first.split_once('|') {
+        Some((text, perfdata)) => (text.trim(), perfdata.to_string()),
+        None => (first.trim(), String::new()),
+    };
+    // Once a line of long output has a `|`, every line after it is perfdata
+    let mut in_perfdata = false;
+    for line in lines {
+        if in_perfdata {
+            perfdata.push(' ');
+            perfdata.push_str(line);
+        } else if let Some((_, more)) = line.split_once('|') {
+            in_perfdata = true;
+            perfdata.push(' ');
+            perfdata.push_str(more);
+        }
+    }
+
+    let samples = perfdata_entries(&perfdata)
+        .iter()
+        .filter_map(|entry| parse_perfdata(entry))
+        .map(|(label, value, unit)| {
+            let mut labels = vec![("check", check), ("label", label.as_str())];
+            if !unit.is_empty() {
+                labels.push(("unit", unit));
+            }
+            sample("check_perfdata", &labels, value)
+        })
+        .collect();
+    (text.to_string(), samples)
+}
+
+#[derive(Deserialize)]
+struct JsonSample {
+    #[serde(alias = "name")]
+    metric: String,
+    #[serde(default)]
+    labels: BTreeMap<String, String>,
+    value: f64,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum JsonOutput {
+    Samples(Vec<JsonSample>),
+    Values(BTreeMap<String, f64>),
+}
+
+fn parse_json(stdout: &str) -> Result<Vec<Sample>, String> {
+    let output: JsonOutput = serde_json::from_str(stdout).map_err(|e| e.to_string())?;
+    Ok(match output {
+        JsonOutput::Samples(samples) => samples
+            .into_iter()
+            .map(|s| Sample {
+                metric: line_protocol::metric_name(&s.metric),
+                labels: s
+                    .labels
+                    .into_iter()
+                    .map(|(k, v)| (line_protocol::metric_name(&k), v))
+                    .collect(),
+                value: s.value,
+            })
+            .collect(),
+        JsonOutput::Values(values) => values
+            .into_iter()
+            .map(|(metric, value)| sample(&line_protocol::metric_name(&metric), &[], value))
+            .collect(),
+    })
+}
+
+fn parse_line_protocol(stdout: &str) -> Result<Vec<Sample>, String> {
+    let (points, errors) = line_protocol::parse(stdout);
+    if let Some(error) = errors.into_iter().next() {
+        return Err(error.to_string());
+    }
+    Ok(points
+        .iter()
+        .flat_map(line_protocol::Point::samples)
+        .collect())
+}
+
+/// Runs one configured check every interval, recording its status and whatever
+/// its output measured.
+pub struct CheckCollector {
+    config: CheckConfig,
+    interval: Duration,
+    name: String,
+}
+
+impl CheckCollector {
+    pub fn new(config: CheckConfig, interval: Duration) -> Self {
+        CheckCollector {
+            name: format!("check:{}", config.name),
+            config,
+            interval,
+        }
+    }
+
+    /// The check's status and text, and the samples its output holds.
+    fn run(&self) -> (CheckStatus, String, Vec<Sample>) {
+        let output = match execute(&self.config) {
+            Ok(output) => output,
+            Err(e) => {
+                let text = format!("could not run {}: {e}", self.config.command);
+                return (CheckStatus::Unknown, text, Vec::new());
+            }
+        };
+        if output.timed_out {
+            let text = format!("timed out after {}s", self.config.timeout);
+            return (CheckStatus::Unknown, text, Vec::new());
+        }
+
+        let status = CheckStatus::from_code(output.code);
+        let parsed = match self.config.format {
+            CheckFormat::Nagios => Ok(parse_nagios(&self.config.name, &output.stdout)),
+            CheckFormat::Json => parse_json(&output.stdout).map(|s| (String::new(), s)),
+            CheckFormat::Line => parse_line_protocol(&output.stdout).map(|s| (String::new(), s)),
+        };
+        match parsed {
+            Ok((text, mut samples)) => {
+                // Samples a check measured itself are told apart by the check's name
+                for sample in &mut samples {
+                    sample
+                        .labels
+                        .insert("check".to_string(), self.config.name.clone());
+                }
+                (status, text, samples)
+            }
+            Err(e) => (
+                CheckStatus::Unknown,
+                format!("invalid output: {e}"),
+                Vec::new(),
+            ),
+        }
+    }
+}
+
+impl Collector for CheckCollector {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn interval(&self) -> Duration {
+        self.interval
+    }
+
+    fn collect(&mut self) -> Result<Vec<Sample>, Box<dyn Error + Send + Sync>> {
+        let started = Instant::now();
+        let (status, output, mut samples) = self.run();
+        let duration = started.elapsed();
+        if status != CheckStatus::Ok {
+            println!("Check {} is {status:?}: {output}", self.config.name);
+        }
+
+        let labels = [("check", self.config.name.as_str())];
+        samples.push(sample("check_status", &labels, f64::from(status.code())));
+        samples.push(sample(
+            "check_duration_seconds",
+            &labels,
+            duration.as_secs_f64(),
+        ));
+        let state = CheckState {
+            name: self.config.name.clone(),
+            status,
+            output,
+            last_run: utils::unix_timestamp(),
+            duration_ms: duration.as_secs_f64() * 1000.0,
+        };
+        STATES
+            .write()
+            .unwrap()
+            .insert(self.config.name.clone(), state);
+        Ok(samples)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn check(command: &str, args: &[&str], timeout: u64) -> CheckConfig {
+        CheckConfig {
+            name: "test".to_string(),
+            command: command.to_string(),
+            args: args.iter().map(|a| a.to_string()).collect(),
+            format: CheckFormat::Nagios,
+            interval: None,
+            timeout,
+        }
+    }
+
+    type Flat<'a> = (&'a str, Vec<(&'a str, &'a str)>, f64);
+
+    /// The samples as `(metric, labels, value)`, for comparing in one go.
+    fn flat(samples: &[Sample]) -> Vec<Flat<'_>> {
+        samples
+            .iter()
+            .map(|s| {
+                let labels = s
+                    .labels
+                    .iter()
+                    .map(|(k, v)| (k.as_str(), v.as_str()))
+                    .collect();
+                (s.metric.as_str(), labels, s.value)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn perfdata_entries_keep_quoted_labels_whole() {
+        assert_eq!(
+            perfdata_entries("  load1=0.5;1;2  'root fs'=42%;80;90 'it''s'=1 "),
+            ["load1=0.5;1;2", "'root fs'=42%;80;90", "'it''s'=1"]
+        );
+        assert!(perfdata_entries("   ").is_empty());
+    }
+
+    #[test]
+    fn perfdata_is_converted_to_base_units() {
+        let parse = |entry| parse_perfdata(entry).unwrap();
+        assert_eq!(
+            parse("time=250ms;500;1000;0"),
+            ("time".to_string(), 0.25, "seconds")
+        );
+        assert_eq!(
+            parse("'root fs'=42.5%;80;90;0;100"),
+            ("root fs".to_string(), 42.5, "percent")
+        );
+        assert_eq!(parse("'it''s'=3c"), ("it's".to_string(), 3.0, "counter"));
+        assert_eq!(parse("used=2KB"), ("used".to_string(), 2048.0, "bytes"));
+        assert_eq!(parse("users=-1e2"), ("users".to_string(), -100.0, ""));
+        assert_eq!(parse("a=b=5s"), ("a=b".to_string(), 5.0, "seconds"));
+        for entry in ["no_value", "x=", "x=U", "x=5parsecs"] {
+            assert_eq!(parse_perfdata(entry), None, "{entry}");
+        }
+    }
+
+    #[test]
+    fn nagios_output_has_perfdata_on_the_first_line_and_in_long_output() {
+        let stdout = "DISK OK - free space: / 3326 MB | /=2643MB;5948;5958;0;5968\n\
+                      / 15272 MB (77%);\n\
+                      /boot 68 MB (69%); | /boot=68MB;88;93;0;98\n\
+                      'home dir'=69%;80;90\n";
+        let (text, samples) = parse_nagios("disk", stdout);
+        assert_eq!(text, "DISK OK - free space: / 3326 MB");
+        let mb = 1024.0 * 1024.0;
+        assert_eq!(
+            flat(&samples),
+            [
+                (
+                    "check_perfdata",
+                    vec![("check", "disk"), ("label", "/"), ("unit", "bytes")],
+                    2643.0 * mb
+                ),
+                (
+                    "check_perfdata",
+                    vec![("check", "disk"), ("label", "/boot"), ("unit", "bytes")],
+                    68.0 * mb
+                ),
+                (
+                    "check_perfdata",
+                    vec![
+                        ("check", "disk"),
+                        ("label", "home dir"),
+                        ("unit", "percent")
+                    ],
+                    69.0
+                ),
+            ]
+        );
+
+        let (text, samples) = parse_nagios("ping", "PING OK\n");
+        assert_eq!(text, "PING OK");
+        assert!(samples.is_empty());
+    }
+
+    #[test]
+    fn json_output_is_samples_or_named_values() {
+        let samples = parse_json(
+            r#"[{"name": "queue.depth", "labels": {"queue-name": "jobs"}, "value": 3},
+                {"metric": "workers", "value": 2.5}]"#,
+        )
+        .unwrap();
+        assert_eq!(
+            flat(&samples),
+            [
+                ("queue_depth", vec![("queue_name", "jobs")], 3.0),
+                ("workers", vec![], 2.5),
+            ]
+        );
+        let samples = parse_json(r#"{"up": 1, "9lives": 0}"#).unwrap();
+        assert_eq!(
+            flat(&samples),
+            [("_9lives", vec![], 0.0), ("up", vec![], 1.0)]
+        );
+        assert!(parse_json("OK").is_err());
+        assert!(parse_json(r#"[{"metric": "x"}]"#).is_err());
+    }
+
+    #[test]
+    fn execute_reports_the_exit_code_and_output() {
+        let output = execute(&check("sh", &["-c", "echo hello; exit 2"], 5)).unwrap();
+        assert_eq!(output.code, Some(2));
+        assert_eq!(output.stdout, "hello\n");
+        assert!(!output.timed_out);
+        assert!(execute(&check("/nonexistent/check", &[], 5)).is_err());
+    }
+
+    #[test]
+    fn execute_kills_a_check_past_its_timeout() {
+        let started = Instant::now();
+        let output = execute(&check("sleep", &["30"], 1)).unwrap();
+        assert!(output.timed_out);
+        assert_eq!(output.code, None);
+        assert!(started.elapsed() < Duration::from_secs(5));
+    }
+}
@@ -1,26 +1,19 @@
 use crate::db::expire_records;
 use crate::db::EXPIRE_SECONDS;
-use std::{
-    sync::{
-        atomic::{AtomicBool, Ordering},
-        Arc,
-    },
-    time::Duration,
-};
+use std::time::Duration;
 use tokio::time;
+use tokio_util::sync::CancellationToken;
 
-pub async fn run(running: Arc<AtomicBool>) {
+pub async fn run(shutdown: CancellationToken) {
     let mut interval = time::interval(Duration::from_secs(EXPIRE_SECONDS));
 
     interval.tick().await;
 
-    while running.load(Ordering::SeqCst) {
+    loop {
         // Wait for the next interval or until interrupted
         tokio::select! {
             _ = interval.tick() => {},
-            () = async { while running.load(Ordering::SeqCst) { time::sleep(Duration::from_secs(1)).await; } } => {
-                break;
-            }
+            () = shutdown.cancelled() => break,
         }
 
         // Expire old CPU usage records
@@ -0,0 +1,32 @@
+use crate::config::Options;
+use crate::line_protocol::{self, WRITE_LIMIT};
+use crate::websocket_client;
+use std::net::IpAddr;
+use tokio_util::sync::CancellationToken;
+use warp::Filter;
+
+/// Serves the client's local status and write endpoints.
+pub async fn run_server(port: u16, shutdown: CancellationToken) {
+    let config = Options::new();
+    let status_route = warp::path!("status")
+        .and(warp::get())
+        .and_then(websocket_client::status_handler);
+
+    // Accepts InfluxDB line protocol, e.g. from a local Telegraf
+    let write_route = warp::path!("write")
+        .or(warp::path!("api" / "v2" / "write"))
+        .unify()
+        .and(warp::post())
+        .and(warp::query::<line_protocol::WriteQuery>())
+        .and(warp::body::content_length_limit(WRITE_LIMIT))
+        .and(warp::body::bytes())
+        .and_then(line_protocol::write_handler);
+
+    let host: IpAddr = config.host.parse().expect("Failed to parse");
+    let (addr, server_future) = warp::serve(status_route.or(write_route))
+        .bind_with_graceful_shutdown((host, port), async move { shutdown.cancelled().await });
+
+    println!("Status server is listening on: http://{addr}");
+
+    server_future.await;
+}
@@ -1,32 +1,113 @@
+use crate::alerts;
+use crate::config::Options;
+use crate::db;
+use crate::liveness;
+use crate::protocol::ClientInfo;
+use crate::registry;
+use crate::utils;
 use crate::websocket_server::Users;
 use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
 
 #[derive(Serialize, Deserialize)]
-struct Client {
-    address: String,
-    id: usize,
+struct Response {
+    clients: Vec<ClientInfo>,
 }
 
-#[derive(Serialize, Deserialize)]
-struct Response {
-    clients: Vec<Client>,
+/// Every client this hub knows about: its own connected and offline clients,
+/// the clients advertised by downstream hubs, and the clients connected to
+/// other instances of this hub.
+pub async fn list(users: &Users) -> Result<Vec<ClientInfo>, db::Error> {
+    let config = Options::new();
+    let now = utils::unix_timestamp();
+    let mut clients = Vec::new();
+    for (&id, client) in users.read().await.iter() {
+        if client.hub {
+            let site = client.name.clone();
+            clients.extend(client.children.iter().cloned().map(|c| ClientInfo {
+                id: c.id.map(|child_id| format!("{id}.{child_id}")),
+                site: c.site.or_else(|| site.clone()),
+                ..c
+            }));
+            continue;
+        }
+        clients.push(ClientInfo {
+            address: client.address(),
+            id: Some(id.to_string()),
+            latency_ms: client.latency.map(|l| l.as_secs_f64() * 1000.0),
+            name: client.name.clone(),
+            online: true,
+            last_seen: now,
+            site: config.site.clone(),
+            labels: client.labels.clone(),
+            watched: client.watched.clone(),
+            checks: client.checks.clone(),
+        });
+    }
+
+    let remote = registry::call(|registry| registry.remote_clients())
+        .await
+        .unwrap_or_else(|e| {
+            eprintln!("Error reading hub registry: {e}");
+            Vec::new()
+        });
+    clients.extend(remote.into_iter().map(|c| ClientInfo {
+        address: c.address,
+        id: Some(c.id.to_string()),
+        latency_ms: None,
+        name: c.name,
+        online: true,
+        last_seen: now,
+        site: config.site.clone(),
+        labels: c.labels,
+        watched: Vec::new(),
+        checks: Vec::new(),
+    }));
+
+    // Known clients that aren't connected are listed as offline
+    let online = liveness::online_names(users).await;
+    let known = db::get_known_clients()?;
+    clients.extend(
+        known
+            .into_iter()
+            .filter(|k| !online.contains(&k.name))
+            .map(|k| ClientInfo {
+                address: k.address,
+                id: None,
+                latency_ms: None,
+                name: Some(k.name),
+                online: false,
+                last_seen: k.last_seen,
+                site: config.site.clone(),
+                labels: BTreeMap::new(),
+                watched: Vec::new(),
+                checks: Vec::new(),
+            }),
+    );
+    Ok(clients)
 }
 
 pub async fn handler(users: Users) -> Result<impl warp::Reply, warp::Rejection> {
-    let user_map = users.read().await;
-    let client_ids: Vec<usize> = user_map.keys().copied().collect();
-    let clients = client_ids
-        .into_iter()
-        .map(|id| Client {
-            address: format!(
-                "{}:{}",
-                user_map.get(&id).unwrap().addr.unwrap().ip(),
-                user_map.get(&id).unwrap().addr.unwrap().port(),
-            ),
-            id,
-        })
-        .collect();
+    let clients = list(&users).await.map_err(|e| {
+        eprintln!("Error reading known clients: {e}");
+        warp::reject::custom(db::DatabaseError)
+    })?;
     let res = Response { clients };
 
     Ok(warp::reply::json(&res))
 }
+
+/// Forgets a known client, e.g. after a host has been decommissioned.
+pub async fn delete_handler(name: String) -> Result<impl warp::Reply, warp::Rejection> {
+    match db::delete_known_client(&name) {
+        Ok(true) => {
+            alerts::resolve(liveness::HOST_DOWN, &liveness::alert_labels(&name)).await;
+            Ok(warp::reply::html("Success"))
+        }
+        Ok(false) => Err(warp::reject::not_found()),
+        Err(e) => {
+            eprintln!("Error deleting known client: {e}");
+            Err(warp::reject::custom(db::DatabaseError))
+        }
+    }
+}
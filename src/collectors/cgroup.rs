@@ -0,0 +1,424 @@
+use super::{sample, Collector};
+use crate::config::CgroupProps;
+use crate::db::Sample;
+use std::collections::HashMap;
+use std::error::Error;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+pub const METRICS: &[(&str, &[&str])] = &[
+    ("cgroup_cpu_usage", &["cgroup"]),
+    ("cgroup_cpu_limit_cores", &["cgroup"]),
+    ("cgroup_cpu_limit_utilization", &["cgroup"]),
+    ("cgroup_cpu_throttled_percent", &["cgroup"]),
+    ("cgroup_cpu_throttled_seconds_total", &["cgroup"]),
+    ("cgroup_memory_bytes", &["cgroup"]),
+    ("cgroup_memory_limit_bytes", &["cgroup"]),
+    ("cgroup_memory_limit_utilization", &["cgroup"]),
+    ("cgroup_io_read_bytes_total", &["cgroup"]),
+    ("cgroup_io_write_bytes_total", &["cgroup"]),
+    ("cgroup_pids", &["cgroup"]),
+    ("cgroup_pids_limit", &["cgroup"]),
+];
+
+#[derive(Clone, Copy)]
+struct CpuStat {
+    usage_usec: u64,
+    nr_periods: u64,
+    nr_throttled: u64,
+    throttled_usec: u64,
+}
+
+/// What one cgroup's interface files said at a point in time. The files of
+/// controllers that aren't enabled for the cgroup are missing, leaving the
+/// matching fields unset, as are limits set to `max`.
+struct Reading {
+    taken: Instant,
+    cpu: Option<CpuStat>,
+    /// How many cores the cgroup may use, from cpu.max
+    cpu_limit: Option<f64>,
+    memory: Option<u64>,
+    memory_limit: Option<u64>,
+    /// Bytes read and written, summed over every device
+    io: Option<(u64, u64)>,
+    pids: Option<u64>,
+    pids_limit: Option<u64>,
+}
+
+fn read_file(dir: &Path, name: &str) -> Option<String> {
+    fs::read_to_string(dir.join(name)).ok()
+}
+
+/// A file holding a single number, or `max` for no limit.
+fn read_value(dir: &Path, name: &str) -> Option<u64> {
+    read_file(dir, name)?.trim().parse().ok()
+}
+
+/// A flat keyed file such as cpu.stat, with one `key value` pair per line.
+fn read_keyed(dir: &Path, name: &str) -> Option<HashMap<String, u64>> {
+    let text = read_file(dir, name)?;
+    Some(
+        text.lines()
+            .filter_map(|line| {
+                let (key, value) = line.split_once(' ')?;
+                Some((key.to_string(), value.trim().parse().ok()?))
+            })
+            .collect(),
+    )
+}
+
+fn read_cpu_stat(dir: &Path) -> Option<CpuStat> {
+    let stat = read_keyed(dir, "cpu.stat")?;
+    let get = |key: &str| stat.get(key).copied().unwrap_or_default();
+    Some(CpuStat {
+        usage_usec: *stat.get("usage_usec")?,
+        nr_periods: get("nr_periods"),
+        nr_throttled: get("nr_throttled"),
+        throttled_usec: get("throttled_usec"),
+    })
+}
+
+/// cpu.max holds the quota and the period it applies to, e.g. `50000 100000`
+/// for half a core.
+fn read_cpu_limit(dir: &Path) -> Option<f64> {
+    let text = read_file(dir, "cpu.max")?;
+    let mut fields = text.split_whitespace();
+    let quota: f64 = fields.next()?.parse().ok()?;
+    let period: f64 = fields.next()?.parse().ok()?;
+    (period > 0.0).then_some(quota / period)
+}
+
+/// io.stat has a line per device, e.g. `8:0 rbytes=1024 wbytes=0 rios=1 ...`.
+fn read_io(dir: &Path) -> Option<(u64, u64)> {
+    let text = read_file(dir, "io.stat")?;
+    let mut totals = (0, 0);
+    for field in text.split_whitespace() {
+        let Some((key, value)) = field.split_once('=') else {
+            continue;
+        };
+        let value: u64 = value.parse().unwrap_or_default();
+        match key {
+            "rbytes" => totals.0 += value,
+            "wbytes" => totals.1 += value,
+            _ => {}
+        }
+    }
+    Some(totals)
+}
+
+fn read(dir: &Path) -> Reading {
+    Reading {
+        taken: Instant::now(),
+        cpu: read_cpu_stat(dir),
+        cpu_limit: read_cpu_limit(dir),
+        memory: read_value(dir, "memory.current"),
+        memory_limit: read_value(dir, "memory.max"),
+        io: read_io(dir),
+        pids: read_value(dir, "pids.current"),
+        pids_limit: read_value(dir, "pids.max"),
+    }
+}
+
+/// The samples for one cgroup. CPU usage and throttling are rates, so they
+/// need a previous reading to compare against.
+fn samples(cgroup: &str, previous: Option<&Reading>, current: &Reading) -> Vec<Sample> {
+    let mut values: Vec<(&'static str, f64)> = Vec::new();
+    if let Some(memory) = current.memory {
+        values.push(("cgroup_memory_bytes", memory as f64));
+        if let Some(limit) = current.memory_limit.filter(|&l| l > 0) {
+            values.push(("cgroup_memory_limit_bytes", limit as f64));
+            values.push((
+                "cgroup_memory_limit_utilization",
+                memory as f64 / limit as f64 * 100.0,
+            ));
+        }
+    }
+    if let Some(pids) = current.pids {
+        values.push(("cgroup_pids", pids as f64));
+    }
+    if let Some(limit) = current.pids_limit {
+        values.push(("cgroup_pids_limit", limit as f64));
+    }
+    if let Some((read, written)) = current.io {
+        values.push(("cgroup_io_read_bytes_total", read as f64));
+        values.push(("cgroup_io_write_bytes_total", written as f64));
+    }
+    if let Some(limit) = current.cpu_limit {
+        values.push(("cgroup_cpu_limit_cores", limit));
+    }
+    if let Some(cpu) = current.cpu {
+        values.push((
+            "cgroup_cpu_throttled_seconds_total",
+            cpu.throttled_usec as f64 / 1_000_000.0,
+        ));
+    }
+    if let (Some(now), Some(before)) = (current.cpu, previous.and_then(|p| p.cpu)) {
+        let elapsed = current
+            .taken
+            .duration_since(previous.map_or(current.taken, |p| p.taken))
+            .as_micros() as f64;
+        if elapsed > 0.0 {
+            let cores = now.usage_usec.saturating_sub(before.usage_usec) as f64 / elapsed;
+            values.push(("cgroup_cpu_usage", cores * 100.0));
+            if let Some(limit) = current.cpu_limit.filter(|&l| l > 0.0) {
+                values.push(("cgroup_cpu_limit_utilization", cores / limit * 100.0));
+            }
+        }
+        let periods = now.nr_periods.saturating_sub(before.nr_periods);
+        if periods > 0 {
+            let throttled = now.nr_throttled.saturating_sub(before.nr_throttled);
+            values.push((
+                "cgroup_cpu_throttled_percent",
+                throttled as f64 / periods as f64 * 100.0,
+            ));
+        }
+    }
+
+    values
+        .into_iter()
+        .map(|(metric, value)| sample(metric, &[("cgroup", cgroup)], value))
+        .collect()
+}
+
+/// The agent's own cgroup, from the `0::/path` line cgroup v2 adds to
+/// /proc/self/cgroup.
+fn own_cgroup() -> Option<String> {
+    let text = fs::read_to_string("/proc/self/cgroup").ok()?;
+    text.lines()
+        .find_map(|line| line.strip_prefix("0::"))
+        .map(str::to_string)
+}
+
+fn descendants(dir: &Path, found: &mut Vec<PathBuf>) {
+    let Ok(entries) = fs::read_dir(dir) else {
+        return;
+    };
+    for entry in entries.flatten() {
+        if entry.file_type().is_ok_and(|t| t.is_dir()) {
+            found.push(entry.path());
+            descendants(&entry.path(), found);
+        }
+    }
+}
+
+/// The usage and limits of the agent's cgroup, and of every cgroup below it
+/// when configured to.
+pub struct CgroupCollector {
+    interval: Duration,
+    root: PathBuf,
+    own: PathBuf,
+    children: bool,
+    previous: HashMap<String, Reading>,
+}
+
+impl CgroupCollector {
+    /// Returns `None` when there's no cgroup v2 hierarchy to read.
+    pub fn new(config: &CgroupProps, interval: Duration) -> Option<Self> {
+        let root = PathBuf::from(&config.root);
+        let Some(path) = config.path.clone().or_else(own_cgroup) else {
+            println!("Not in a cgroup v2 hierarchy, cgroup metrics are disabled");
+            return None;
+        };
+        let own = root.join(path.trim_start_matches('/'));
+        // Only cgroup v2 has cgroup.controllers
+        if !own.join("cgroup.controllers").exists() {
+            println!(
+                "No cgroup v2 hierarchy at {}, cgroup metrics are disabled",
+                own.display()
+            );
+            return None;
+        }
+        println!("Collecting cgroup metrics from {}", own.display());
+        Some(CgroupCollector {
+            interval,
+            root,
+            own,
+            children: config.children,
+            previous: HashMap::new(),
+        })
+    }
+}
+
+impl Collector for CgroupCollector {
+    fn name(&self) -> &str {
+        "cgroup"
+    }
+
+    fn interval(&self) -> Duration {
+        self.interval
+    }
+
+    fn collect(&mut self) -> Result<Vec<Sample>, Box<dyn Error + Send + Sync>> {
+        let mut dirs = vec![self.own.clone()];
+        if self.children {
+            descendants(&self.own, &mut dirs);
+        }
+        let mut readings = HashMap::new();
+        let mut all_samples = Vec::new();
+        for dir in dirs {
+            // Labelled like /proc/self/cgroup names them, relative to the root
+            let name = format!(
+                "/{}",
+                dir.strip_prefix(&self.root)
+                    .unwrap_or(&dir)
+                    .to_string_lossy()
+            );
+            let reading = read(&dir);
+            all_samples.extend(samples(&name, self.previous.get(&name), &reading));
+            readings.insert(name, reading);
+        }
+        // Cgroups that have gone away are dropped along with their readings
+        self.previous = readings;
+        Ok(all_samples)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    const CGROUP: &str = "/kubepods/pod1";
+
+    /// A cgroup v2 hierarchy holding the agent's cgroup with the given files.
+    fn fixture(files: &[(&str, &str)]) -> (TempDir, PathBuf) {
+        let root = TempDir::new().unwrap();
+        let dir = root.path().join(CGROUP.trim_start_matches('/'));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("cgroup.controllers"), "cpu io memory pids\n").unwrap();
+        write(&dir, files);
+        (root, dir)
+    }
+
+    fn write(dir: &Path, files: &[(&str, &str)]) {
+        for (name, contents) in files {
+            fs::write(dir.join(name), contents).unwrap();
+        }
+    }
+
+    /// A collector configured as `CGROUP_ROOT` and `CGROUP_PATH` would configure it.
+    fn collector(root: &TempDir, children: bool) -> Option<CgroupCollector> {
+        let config = CgroupProps {
+            children,
+            path: Some(CGROUP.to_string()),
+            root: root.path().to_string_lossy().into_owned(),
+        };
+        CgroupCollector::new(&config, Duration::from_secs(15))
+    }
+
+    fn value(samples: &[Sample], metric: &str, cgroup: &str) -> Option<f64> {
+        samples
+            .iter()
+            .find(|s| s.metric == metric && s.labels["cgroup"] == cgroup)
+            .map(|s| s.value)
+    }
+
+    fn cpu_stat(usage_usec: u64, nr_periods: u64, nr_throttled: u64) -> String {
+        format!(
+            "usage_usec {usage_usec}\nuser_usec 0\nsystem_usec 0\n\
+             nr_periods {nr_periods}\nnr_throttled {nr_throttled}\nthrottled_usec 500000\n"
+        )
+    }
+
+    #[test]
+    fn reads_usage_and_limits() {
+        let (root, dir) = fixture(&[
+            ("cpu.stat", &cpu_stat(1_000_000, 100, 25)),
+            ("cpu.max", "50000 100000\n"),
+            ("memory.current", "268435456\n"),
+            ("memory.max", "536870912\n"),
+            ("pids.current", "12\n"),
+            ("pids.max", "100\n"),
+            (
+                "io.stat",
+                "8:0 rbytes=1024 wbytes=2048 rios=1 wios=2\n8:16 rbytes=1 wbytes=2\n",
+            ),
+        ]);
+        let mut collector = collector(&root, false).unwrap();
+
+        let samples = collector.collect().unwrap();
+        let get = |metric| value(&samples, metric, CGROUP);
+        assert_eq!(get("cgroup_memory_bytes"), Some(268_435_456.0));
+        assert_eq!(get("cgroup_memory_limit_bytes"), Some(536_870_912.0));
+        assert_eq!(get("cgroup_memory_limit_utilization"), Some(50.0));
+        assert_eq!(get("cgroup_cpu_limit_cores"), Some(0.5));
+        assert_eq!(get("cgroup_cpu_throttled_seconds_total"), Some(0.5));
+        assert_eq!(get("cgroup_pids"), Some(12.0));
+        assert_eq!(get("cgroup_pids_limit"), Some(100.0));
+        assert_eq!(get("cgroup_io_read_bytes_total"), Some(1025.0));
+        assert_eq!(get("cgroup_io_write_bytes_total"), Some(2050.0));
+        // Rates need a second reading
+        assert_eq!(get("cgroup_cpu_usage"), None);
+        assert_eq!(get("cgroup_cpu_throttled_percent"), None);
+
+        // Usage is measured against the time between readings, in whole microseconds
+        std::thread::sleep(Duration::from_millis(10));
+        write(&dir, &[("cpu.stat", &cpu_stat(1_500_000, 200, 35))]);
+        let samples = collector.collect().unwrap();
+        let get = |metric| value(&samples, metric, CGROUP);
+        assert_eq!(get("cgroup_cpu_throttled_percent"), Some(10.0));
+        assert!(get("cgroup_cpu_usage").is_some_and(|usage| usage > 0.0));
+        assert!(get("cgroup_cpu_limit_utilization").is_some_and(|u| u > 0.0));
+    }
+
+    #[test]
+    fn unlimited_cgroups_have_no_limits() {
+        let (root, _dir) = fixture(&[
+            ("cpu.stat", &cpu_stat(1_000_000, 0, 0)),
+            ("cpu.max", "max 100000\n"),
+            ("memory.current", "4096\n"),
+            ("memory.max", "max\n"),
+            ("pids.current", "3\n"),
+            ("pids.max", "max\n"),
+        ]);
+        let samples = collector(&root, false).unwrap().collect().unwrap();
+        let get = |metric| value(&samples, metric, CGROUP);
+        assert_eq!(get("cgroup_memory_bytes"), Some(4096.0));
+        assert_eq!(get("cgroup_pids"), Some(3.0));
+        for metric in [
+            "cgroup_memory_limit_bytes",
+            "cgroup_memory_limit_utilization",
+            "cgroup_cpu_limit_cores",
+            "cgroup_pids_limit",
+        ] {
+            assert_eq!(get(metric), None, "{metric}");
+        }
+    }
+
+    #[test]
+    fn missing_controllers_are_left_out() {
+        let (root, _dir) = fixture(&[("memory.current", "4096\n")]);
+        let samples = collector(&root, false).unwrap().collect().unwrap();
+        let metrics: Vec<&str> = samples.iter().map(|s| s.metric.as_str()).collect();
+        assert_eq!(metrics, ["cgroup_memory_bytes"]);
+    }
+
+    #[test]
+    fn children_are_labelled_with_their_path() {
+        let (root, dir) = fixture(&[("memory.current", "4096\n")]);
+        let child = dir.join("app");
+        fs::create_dir(&child).unwrap();
+        write(&child, &[("memory.current", "1024\n")]);
+
+        let samples = collector(&root, false).unwrap().collect().unwrap();
+        assert_eq!(
+            value(&samples, "cgroup_memory_bytes", "/kubepods/pod1/app"),
+            None
+        );
+
+        let samples = collector(&root, true).unwrap().collect().unwrap();
+        assert_eq!(value(&samples, "cgroup_memory_bytes", CGROUP), Some(4096.0));
+        assert_eq!(
+            value(&samples, "cgroup_memory_bytes", "/kubepods/pod1/app"),
+            Some(1024.0)
+        );
+    }
+
+    #[test]
+    fn needs_a_cgroup_v2_hierarchy() {
+        let (root, dir) = fixture(&[]);
+        fs::remove_file(dir.join("cgroup.controllers")).unwrap();
+        assert!(collector(&root, false).is_none());
+    }
+}
@@ -0,0 +1,125 @@
+use super::{sample, Collector};
+use crate::db::{insert_cpu_usage, Sample};
+use std::error::Error;
+use std::time::Duration;
+use sysinfo::System;
+
+pub const METRICS: &[(&str, &[&str])] = &[
+    ("cpu_core_usage", &["core"]),
+    ("cpu_mode_usage", &["mode"]),
+    ("cpu_frequency_mhz", &["core"]),
+    ("load1", &[]),
+    ("load5", &[]),
+    ("load15", &[]),
+];
+
+// The columns of the `cpu` line of /proc/stat, in order. Guest time is left
+// out since it is already counted in user and nice.
+const CPU_MODES: [&str; 8] = [
+    "user", "nice", "system", "idle", "iowait", "irq", "softirq", "steal",
+];
+
+type CpuTimes = [u64; CPU_MODES.len()];
+
+/// Reads the time all CPUs have spent in each mode since boot. Only Linux has
+/// /proc/stat, so elsewhere there is no breakdown.
+fn read_cpu_times() -> Option<CpuTimes> {
+    let stat = std::fs::read_to_string("/proc/stat").ok()?;
+    let line = stat.lines().find(|l| l.starts_with("cpu "))?;
+    let mut fields = line.split_whitespace().skip(1);
+    let mut times = CpuTimes::default();
+    for time in &mut times {
+        // Older kernels don't report the later columns
+        *time = fields.next().map_or(Ok(0), str::parse).ok()?;
+    }
+    Some(times)
+}
+
+/// The percentage of time spent in each mode between two readings.
+fn mode_samples(previous: &CpuTimes, current: &CpuTimes) -> Vec<Sample> {
+    let deltas: Vec<u64> = current
+        .iter()
+        .zip(previous)
+        .map(|(c, p)| c.saturating_sub(*p))
+        .collect();
+    let total: u64 = deltas.iter().sum();
+    if total == 0 {
+        return Vec::new();
+    }
+    CPU_MODES
+        .iter()
+        .zip(deltas)
+        .map(|(mode, delta)| {
+            let percent = delta as f64 / total as f64 * 100.0;
+            sample("cpu_mode_usage", &[("mode", mode)], percent)
+        })
+        .collect()
+}
+
+/// Per-core usage and frequency, and the load averages.
+fn system_samples(sys: &System) -> Vec<Sample> {
+    let mut samples = Vec::new();
+    for (core, cpu) in sys.cpus().iter().enumerate() {
+        let core = core.to_string();
+        let labels = [("core", core.as_str())];
+        samples.push(sample(
+            "cpu_core_usage",
+            &labels,
+            f64::from(cpu.cpu_usage()),
+        ));
+        samples.push(sample("cpu_frequency_mhz", &labels, cpu.frequency() as f64));
+    }
+    let load = System::load_average();
+    samples.push(sample("load1", &[], load.one));
+    samples.push(sample("load5", &[], load.five));
+    samples.push(sample("load15", &[], load.fifteen));
+    samples
+}
+
+/// Overall, per-core and per-mode usage, core frequencies and load averages.
+pub struct CpuCollector {
+    interval: Duration,
+    sys: System,
+    cpu_times: Option<CpuTimes>,
+}
+
+impl CpuCollector {
+    pub fn new(interval: Duration) -> Self {
+        CpuCollector {
+            interval,
+            sys: System::new_all(),
+            cpu_times: read_cpu_times(),
+        }
+    }
+}
+
+impl Collector for CpuCollector {
+    fn name(&self) -> &str {
+        "cpu"
+    }
+
+    fn interval(&self) -> Duration {
+        self.interval
+    }
+
+    fn collect(&mut self) -> Result<Vec<Sample>, Box<dyn Error + Send + Sync>> {
+        // Refresh CPU data
+        self.sys.refresh_cpu_all();
+
+        // Get CPU usage
+        let cpu_usage = self.sys.global_cpu_usage();
+
+        println!("CPU Usage: {cpu_usage:.2}%");
+
+        // The overall usage has its own table, which the dashboard's chart reads
+        insert_cpu_usage(cpu_usage)?;
+
+        let mut samples = system_samples(&self.sys);
+        let current = read_cpu_times();
+        if let (Some(previous), Some(current)) = (&self.cpu_times, &current) {
+            samples.extend(mode_samples(previous, current));
+        }
+        self.cpu_times = current;
+        Ok(samples)
+    }
+}
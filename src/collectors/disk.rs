@@ -0,0 +1,55 @@
+use super::{sample, Collector};
+use crate::db::Sample;
+use std::error::Error;
+use std::time::Duration;
+use sysinfo::Disks;
+
+pub const METRICS: &[(&str, &[&str])] = &[
+    ("disk_total_bytes", &["mount"]),
+    ("disk_available_bytes", &["mount"]),
+    ("disk_usage", &["mount"]),
+];
+
+/// Space on every mounted filesystem.
+pub struct DiskCollector {
+    interval: Duration,
+    disks: Disks,
+}
+
+impl DiskCollector {
+    pub fn new(interval: Duration) -> Self {
+        DiskCollector {
+            interval,
+            disks: Disks::new(),
+        }
+    }
+}
+
+impl Collector for DiskCollector {
+    fn name(&self) -> &str {
+        "disk"
+    }
+
+    fn interval(&self) -> Duration {
+        self.interval
+    }
+
+    fn collect(&mut self) -> Result<Vec<Sample>, Box<dyn Error + Send + Sync>> {
+        // Filesystems come and go, so list them again each time
+        self.disks.refresh_list();
+        let mut samples = Vec::new();
+        for disk in self.disks.list() {
+            let mount = disk.mount_point().to_string_lossy();
+            let labels = [("mount", mount.as_ref())];
+            let total = disk.total_space();
+            let available = disk.available_space();
+            samples.push(sample("disk_total_bytes", &labels, total as f64));
+            samples.push(sample("disk_available_bytes", &labels, available as f64));
+            if total > 0 {
+                let usage = total.saturating_sub(available) as f64 / total as f64 * 100.0;
+                samples.push(sample("disk_usage", &labels, usage));
+            }
+        }
+        Ok(samples)
+    }
+}
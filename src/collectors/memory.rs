@@ -0,0 +1,58 @@
+use super::{sample, Collector};
+use crate::db::Sample;
+use std::error::Error;
+use std::time::Duration;
+use sysinfo::System;
+
+pub const METRICS: &[(&str, &[&str])] = &[
+    ("memory_total_bytes", &[]),
+    ("memory_used_bytes", &[]),
+    ("memory_available_bytes", &[]),
+    ("memory_usage", &[]),
+    ("swap_total_bytes", &[]),
+    ("swap_used_bytes", &[]),
+];
+
+/// Memory and swap use of the whole host.
+pub struct MemoryCollector {
+    interval: Duration,
+    sys: System,
+}
+
+impl MemoryCollector {
+    pub fn new(interval: Duration) -> Self {
+        MemoryCollector {
+            interval,
+            sys: System::new(),
+        }
+    }
+}
+
+impl Collector for MemoryCollector {
+    fn name(&self) -> &str {
+        "memory"
+    }
+
+    fn interval(&self) -> Duration {
+        self.interval
+    }
+
+    fn collect(&mut self) -> Result<Vec<Sample>, Box<dyn Error + Send + Sync>> {
+        self.sys.refresh_memory();
+        let total = self.sys.total_memory();
+        let available = self.sys.available_memory();
+        let mut samples = vec![
+            sample("memory_total_bytes", &[], total as f64),
+            sample("memory_used_bytes", &[], self.sys.used_memory() as f64),
+            sample("memory_available_bytes", &[], available as f64),
+            sample("swap_total_bytes", &[], self.sys.total_swap() as f64),
+            sample("swap_used_bytes", &[], self.sys.used_swap() as f64),
+        ];
+        if total > 0 {
+            // Used as in not available, which counts reclaimable caches as free
+            let usage = total.saturating_sub(available) as f64 / total as f64 * 100.0;
+            samples.push(sample("memory_usage", &[], usage));
+        }
+        Ok(samples)
+    }
+}
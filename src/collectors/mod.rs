@@ -0,0 +1,226 @@
+mod cgroup;
+mod cpu;
+mod disk;
+mod memory;
+mod network;
+mod probe;
+
+use crate::checks::{self, CheckCollector};
+use crate::config::{CheckConfig, Options, ProbeConfig};
+use crate::db::{insert_samples, Sample};
+use crate::utils;
+use crate::watch::{self, WatchCollector};
+use cgroup::CgroupCollector;
+use cpu::CpuCollector;
+use disk::DiskCollector;
+use memory::MemoryCollector;
+use network::NetworkCollector;
+use probe::ProbeCollector;
+use std::collections::{BTreeMap, HashSet};
+use std::error::Error;
+use std::panic::{self, AssertUnwindSafe};
+use std::time::{Duration, Instant};
+use tokio::task;
+use tokio::time;
+use tokio_util::sync::CancellationToken;
+
+/// A source of metrics the client samples on its own schedule.
+pub trait Collector: Send {
+    /// Names the collector in config, logs and its timing metrics.
+    fn name(&self) -> &str;
+
+    fn interval(&self) -> Duration;
+
+    /// Takes one round of samples. Runs on a blocking thread, so it may read
+    /// files or call into `sysinfo` directly.
+    fn collect(&mut self) -> Result<Vec<Sample>, Box<dyn Error + Send + Sync>>;
+}
+
+// Every collector records how its runs went, labelled with its name
+const TIMING_METRICS: &[(&str, &[&str])] = &[
+    ("collector_duration_seconds", &["collector"]),
+    ("collector_samples", &["collector"]),
+    ("collector_errors_total", &["collector"]),
+];
+
+/// The metrics collectors record, with the labels that tell apart their series.
+pub fn metrics() -> impl Iterator<Item = (&'static str, &'static [&'static str])> {
+    [
+        cpu::METRICS,
+        memory::METRICS,
+        disk::METRICS,
+        network::METRICS,
+        cgroup::METRICS,
+        watch::METRICS,
+        checks::METRICS,
+        probe::METRICS,
+        TIMING_METRICS,
+    ]
+    .into_iter()
+    .flatten()
+    .copied()
+}
+
+/// A sample labelled with the given pairs.
+pub fn sample(metric: &str, labels: &[(&str, &str)], value: f64) -> Sample {
+    Sample {
+        metric: metric.to_string(),
+        labels: labels
+            .iter()
+            .map(|(k, v)| ((*k).to_string(), (*v).to_string()))
+            .collect::<BTreeMap<_, _>>(),
+        value,
+    }
+}
+
+fn default_interval(name: &str) -> Option<u64> {
+    Some(match name {
+        "cpu" => 5,
+        "memory" | "network" | "cgroup" => 15,
+        "disk" => 60,
+        "watch" => 10,
+        "checks" => 60,
+        "probes" => 30,
+        _ => return None,
+    })
+}
+
+/// Builds the collectors named in `COLLECTORS`. Collectors with nothing to
+/// collect on this host, such as `cgroup` outside a cgroup v2 hierarchy, are left out,
+/// and `checks` and `probes` become one collector per configured check or probe.
+pub fn build() -> Vec<Box<dyn Collector>> {
+    let config = Options::new();
+    let mut collectors: Vec<Box<dyn Collector>> = Vec::new();
+    for name in &config.collectors.enabled {
+        let Some(default) = default_interval(name) else {
+            eprintln!("Ignoring unknown collector {name}");
+            continue;
+        };
+        let interval = config
+            .collectors
+            .intervals
+            .get(name)
+            .copied()
+            .unwrap_or(Duration::from_secs(default));
+        let collector: Option<Box<dyn Collector>> = match name.as_str() {
+            "cpu" => Some(Box::new(CpuCollector::new(interval))),
+            "memory" => Some(Box::new(MemoryCollector::new(interval))),
+            "disk" => Some(Box::new(DiskCollector::new(interval))),
+            "network" => Some(Box::new(NetworkCollector::new(interval))),
+            "cgroup" => CgroupCollector::new(&config.cgroup, interval)
+                .map(|c| Box::new(c) as Box<dyn Collector>),
+            "watch" => WatchCollector::new(&config.watch, interval)
+                .map(|c| Box::new(c) as Box<dyn Collector>),
+            "checks" => {
+                collectors.extend(check_collectors(&config.checks, interval));
+                None
+            }
+            "probes" => {
+                collectors.extend(probe_collectors(&config.probes, interval));
+                None
+            }
+            _ => None,
+        };
+        collectors.extend(collector);
+    }
+    collectors
+}
+
+/// The configured items, leaving out any that reuse an earlier one's name.
+fn unique<'a, T>(items: &'a [T], name: impl Fn(&T) -> &str, kind: &str) -> Vec<&'a T> {
+    let mut seen = HashSet::new();
+    items
+        .iter()
+        .filter(|item| {
+            let first = seen.insert(name(item).to_string());
+            if !first {
+                eprintln!("Ignoring duplicate {kind} {}", name(item));
+            }
+            first
+        })
+        .collect()
+}
+
+fn check_collectors(checks: &[CheckConfig], interval: Duration) -> Vec<Box<dyn Collector>> {
+    unique(checks, |c| &c.name, "check")
+        .into_iter()
+        .map(|check| {
+            let interval = check.interval.map_or(interval, Duration::from_secs);
+            Box::new(CheckCollector::new(check.clone(), interval)) as Box<dyn Collector>
+        })
+        .collect()
+}
+
+fn probe_collectors(probes: &[ProbeConfig], interval: Duration) -> Vec<Box<dyn Collector>> {
+    unique(probes, |p| &p.name, "probe")
+        .into_iter()
+        .filter_map(|probe| {
+            let interval = probe.interval.map_or(interval, Duration::from_secs);
+            ProbeCollector::new(probe, interval).map(|c| Box::new(c) as Box<dyn Collector>)
+        })
+        .collect()
+}
+
+/// Runs a collector every interval until shutdown. A collector that fails or
+/// panics only loses that round; the others carry on regardless.
+pub async fn run(mut collector: Box<dyn Collector>, shutdown: CancellationToken) {
+    let name = collector.name().to_string();
+    let mut interval = time::interval(collector.interval());
+    let mut errors: u64 = 0;
+
+    // Collectors that measure change need a full interval before their first round
+    interval.tick().await;
+
+    loop {
+        // Wait for the next interval or until interrupted
+        tokio::select! {
+            _ = interval.tick() => {},
+            () = shutdown.cancelled() => break,
+        }
+
+        let started = Instant::now();
+        let round = task::spawn_blocking(move || {
+            let result = panic::catch_unwind(AssertUnwindSafe(|| collector.collect()));
+            (collector, result)
+        })
+        .await;
+        let Ok((returned, result)) = round else {
+            eprintln!("Collector {name} stopped");
+            return;
+        };
+        collector = returned;
+        let mut samples = match result {
+            Ok(Ok(samples)) => samples,
+            Ok(Err(e)) => {
+                errors += 1;
+                eprintln!("Collector {name} failed: {e}");
+                Vec::new()
+            }
+            Err(_) => {
+                errors += 1;
+                eprintln!("Collector {name} panicked");
+                Vec::new()
+            }
+        };
+
+        let labels = [("collector", name.as_str())];
+        let timing = [
+            (
+                "collector_duration_seconds",
+                started.elapsed().as_secs_f64(),
+            ),
+            ("collector_samples", samples.len() as f64),
+            ("collector_errors_total", errors as f64),
+        ];
+        samples.extend(
+            timing
+                .into_iter()
+                .map(|(metric, value)| sample(metric, &labels, value)),
+        );
+        if let Err(e) = insert_samples(utils::unix_timestamp(), &samples) {
+            eprintln!("Error inserting {name} samples: {e}");
+        }
+    }
+
+    println!("Collector {name} exiting");
+}
@@ -0,0 +1,62 @@
+use super::{sample, Collector};
+use crate::db::Sample;
+use std::error::Error;
+use std::time::Duration;
+use sysinfo::Networks;
+
+pub const METRICS: &[(&str, &[&str])] = &[
+    ("network_received_bytes_total", &["interface"]),
+    ("network_transmitted_bytes_total", &["interface"]),
+    ("network_receive_errors_total", &["interface"]),
+    ("network_transmit_errors_total", &["interface"]),
+];
+
+/// Traffic on every network interface, as counters since boot to be read with `rate`.
+pub struct NetworkCollector {
+    interval: Duration,
+    networks: Networks,
+}
+
+impl NetworkCollector {
+    pub fn new(interval: Duration) -> Self {
+        NetworkCollector {
+            interval,
+            networks: Networks::new(),
+        }
+    }
+}
+
+impl Collector for NetworkCollector {
+    fn name(&self) -> &str {
+        "network"
+    }
+
+    fn interval(&self) -> Duration {
+        self.interval
+    }
+
+    fn collect(&mut self) -> Result<Vec<Sample>, Box<dyn Error + Send + Sync>> {
+        // Interfaces come and go, so list them again each time
+        self.networks.refresh_list();
+        let mut samples = Vec::new();
+        for (interface, data) in &self.networks {
+            let labels = [("interface", interface.as_str())];
+            let counters = [
+                ("network_received_bytes_total", data.total_received()),
+                ("network_transmitted_bytes_total", data.total_transmitted()),
+                (
+                    "network_receive_errors_total",
+                    data.total_errors_on_received(),
+                ),
+                (
+                    "network_transmit_errors_total",
+                    data.total_errors_on_transmitted(),
+                ),
+            ];
+            for (metric, value) in counters {
+                samples.push(sample(metric, &labels, value as f64));
+            }
+        }
+        Ok(samples)
+    }
+}
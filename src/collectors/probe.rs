@@ -0,0 +1,359 @@
+use super::{sample, Collector};
+use crate::config::{ProbeConfig, ProbeTarget};
+use crate::db::Sample;
+use regex::Regex;
+use reqwest::Method;
+use std::error::Error;
+use std::net::IpAddr;
+use std::time::{Duration, Instant};
+use tokio::net::{lookup_host, TcpStream};
+use tokio::runtime::Handle;
+use tokio::time;
+
+pub const METRICS: &[(&str, &[&str])] = &[
+    ("probe_success", &["probe"]),
+    ("probe_duration_seconds", &["probe"]),
+    ("probe_http_status_code", &["probe"]),
+    ("probe_dns_addresses", &["probe"]),
+];
+
+enum Target {
+    Http {
+        client: reqwest::Client,
+        method: Method,
+        url: String,
+        expect_status: Option<u16>,
+        expect_body: Option<Regex>,
+    },
+    Tcp {
+        address: String,
+    },
+    Dns {
+        host: String,
+        expect_address: Option<IpAddr>,
+    },
+}
+
+/// How a probe went: why it failed, if it did, and what else it measured.
+struct Outcome {
+    failure: Option<String>,
+    values: Vec<(&'static str, f64)>,
+}
+
+impl Outcome {
+    fn failed(reason: impl Into<String>) -> Self {
+        Outcome {
+            failure: Some(reason.into()),
+            values: Vec::new(),
+        }
+    }
+}
+
+async fn probe_http(
+    client: &reqwest::Client,
+    method: &Method,
+    url: &str,
+    expect_status: Option<u16>,
+    expect_body: Option<&Regex>,
+) -> Outcome {
+    let response = match client.request(method.clone(), url).send().await {
+        Ok(response) => response,
+        Err(e) => return Outcome::failed(e.to_string()),
+    };
+    let status = response.status();
+    let mut outcome = Outcome {
+        failure: None,
+        values: vec![("probe_http_status_code", f64::from(status.as_u16()))],
+    };
+    if !expect_status.map_or(status.is_success(), |s| status.as_u16() == s) {
+        outcome.failure = Some(format!("unexpected status {status}"));
+    } else if let Some(expect_body) = expect_body {
+        outcome.failure = match response.text().await {
+            Ok(body) if expect_body.is_match(&body) => None,
+            Ok(_) => Some(format!("body does not match {expect_body}")),
+            Err(e) => Some(e.to_string()),
+        };
+    }
+    outcome
+}
+
+async fn probe_dns(host: &str, expect_address: Option<IpAddr>) -> Outcome {
+    // The port is only there because the resolver wants one
+    let addresses: Vec<IpAddr> = match lookup_host((host, 0)).await {
+        Ok(addresses) => addresses.map(|a| a.ip()).collect(),
+        Err(e) => return Outcome::failed(e.to_string()),
+    };
+    let mut outcome = Outcome {
+        failure: None,
+        values: vec![("probe_dns_addresses", addresses.len() as f64)],
+    };
+    if let Some(expected) = expect_address.filter(|a| !addresses.contains(a)) {
+        outcome.failure = Some(format!("{host} does not resolve to {expected}"));
+    }
+    outcome
+}
+
+/// Probes one configured endpoint every interval, from where this client sits
+/// on the network.
+pub struct ProbeCollector {
+    interval: Duration,
+    name: String,
+    probe: String,
+    target: Target,
+    timeout: Duration,
+}
+
+impl ProbeCollector {
+    /// Returns `None` for a probe that can't be set up, e.g. one whose
+    /// `expect_body` isn't a valid regular expression.
+    pub fn new(config: &ProbeConfig, interval: Duration) -> Option<Self> {
+        let invalid = |what: &str| eprintln!("Ignoring probe {}: {what}", config.name);
+        let target = match &config.target {
+            ProbeTarget::Http {
+                url,
+                method,
+                expect_status,
+                expect_body,
+            } => {
+                let method = method.as_deref().unwrap_or("GET").to_uppercase();
+                let Ok(method) = Method::from_bytes(method.as_bytes()) else {
+                    invalid(&format!("invalid method {method}"));
+                    return None;
+                };
+                let expect_body = match expect_body.as_deref().map(Regex::new).transpose() {
+                    Ok(expect_body) => expect_body,
+                    Err(e) => {
+                        invalid(&e.to_string());
+                        return None;
+                    }
+                };
+                Target::Http {
+                    client: reqwest::Client::new(),
+                    method,
+                    url: url.clone(),
+                    expect_status: *expect_status,
+                    expect_body,
+                }
+            }
+            ProbeTarget::Tcp { address } => Target::Tcp {
+                address: address.clone(),
+            },
+            ProbeTarget::Dns {
+                host,
+                expect_address,
+            } => {
+                let expect_address = match expect_address.as_deref().map(str::parse).transpose() {
+                    Ok(expect_address) => expect_address,
+                    Err(e) => {
+                        invalid(&format!("invalid expect_address: {e}"));
+                        return None;
+                    }
+                };
+                Target::Dns {
+                    host: host.clone(),
+                    expect_address,
+                }
+            }
+        };
+        Some(ProbeCollector {
+            interval,
+            name: format!("probe:{}", config.name),
+            probe: config.name.clone(),
+            target,
+            timeout: Duration::from_secs(config.timeout),
+        })
+    }
+
+    async fn probe(&self) -> Outcome {
+        match &self.target {
+            Target::Http {
+                client,
+                method,
+                url,
+                expect_status,
+                expect_body,
+            } => probe_http(client, method, url, *expect_status, expect_body.as_ref()).await,
+            Target::Tcp { address } => match TcpStream::connect(address.as_str()).await {
+                Ok(_) => Outcome {
+                    failure: None,
+                    values: Vec::new(),
+                },
+                Err(e) => Outcome::failed(e.to_string()),
+            },
+            Target::Dns {
+                host,
+                expect_address,
+            } => probe_dns(host, *expect_address).await,
+        }
+    }
+}
+
+impl Collector for ProbeCollector {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn interval(&self) -> Duration {
+        self.interval
+    }
+
+    fn collect(&mut self) -> Result<Vec<Sample>, Box<dyn Error + Send + Sync>> {
+        let started = Instant::now();
+        // Collectors run on a blocking thread of the runtime, which can wait on it
+        let outcome = Handle::current().block_on(async {
+            time::timeout(self.timeout, self.probe())
+                .await
+                .unwrap_or_else(|_| {
+                    Outcome::failed(format!("timed out after {}s", self.timeout.as_secs()))
+                })
+        });
+        let duration = started.elapsed();
+        if let Some(failure) = &outcome.failure {
+            println!("Probe {} failed: {failure}", self.probe);
+        }
+
+        let labels = [("probe", self.probe.as_str())];
+        let success = f64::from(u8::from(outcome.failure.is_none()));
+        let mut samples = vec![
+            sample("probe_success", &labels, success),
+            sample("probe_duration_seconds", &labels, duration.as_secs_f64()),
+        ];
+        samples.extend(
+            outcome
+                .values
+                .into_iter()
+                .map(|(metric, value)| sample(metric, &labels, value)),
+        );
+        Ok(samples)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use std::net::SocketAddr;
+    use tokio::net::TcpListener;
+    use tokio::task;
+    use warp::http::StatusCode;
+    use warp::Filter;
+
+    /// Probes once, as the collector's blocking thread would.
+    async fn probe(config: serde_json::Value) -> Vec<Sample> {
+        let config: ProbeConfig = serde_json::from_value(config).unwrap();
+        let mut collector = ProbeCollector::new(&config, Duration::from_secs(30)).unwrap();
+        task::spawn_blocking(move || collector.collect().unwrap())
+            .await
+            .unwrap()
+    }
+
+    fn value(samples: &[Sample], metric: &str) -> Option<f64> {
+        samples.iter().find(|s| s.metric == metric).map(|s| s.value)
+    }
+
+    /// A local HTTP server answering `/health` with 200 and `/down` with 503.
+    fn http_server() -> SocketAddr {
+        let health = warp::path("health").map(|| "status: healthy");
+        let down = warp::path("down")
+            .map(|| warp::reply::with_status("status: down", StatusCode::SERVICE_UNAVAILABLE));
+        let (addr, server) = warp::serve(health.or(down)).bind_ephemeral(([127, 0, 0, 1], 0));
+        tokio::spawn(server);
+        addr
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn tcp_up() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        let samples = probe(json!({ "name": "db", "kind": "tcp", "address": address })).await;
+        assert_eq!(value(&samples, "probe_success"), Some(1.0));
+        assert!(samples.iter().all(|s| s.labels["probe"] == "db"));
+        assert!(value(&samples, "probe_duration_seconds").is_some());
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn tcp_down() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        drop(listener);
+        let samples = probe(json!({ "name": "db", "kind": "tcp", "address": address })).await;
+        assert_eq!(value(&samples, "probe_success"), Some(0.0));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn http_up() {
+        let url = format!("http://{}/health", http_server());
+        let samples = probe(json!({
+            "name": "site",
+            "kind": "http",
+            "url": url,
+            "expect_body": "heal+thy",
+        }))
+        .await;
+        assert_eq!(value(&samples, "probe_success"), Some(1.0));
+        assert_eq!(value(&samples, "probe_http_status_code"), Some(200.0));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn http_down() {
+        let url = format!("http://{}/down", http_server());
+        let samples = probe(json!({ "name": "site", "kind": "http", "url": url })).await;
+        assert_eq!(value(&samples, "probe_success"), Some(0.0));
+        assert_eq!(value(&samples, "probe_http_status_code"), Some(503.0));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn http_status_mismatch() {
+        let addr = http_server();
+        // A 2xx that isn't the one expected fails, and an expected non-2xx succeeds
+        let samples = probe(json!({
+            "name": "site",
+            "kind": "http",
+            "url": format!("http://{addr}/health"),
+            "expect_status": 204,
+        }))
+        .await;
+        assert_eq!(value(&samples, "probe_success"), Some(0.0));
+        assert_eq!(value(&samples, "probe_http_status_code"), Some(200.0));
+        let samples = probe(json!({
+            "name": "site",
+            "kind": "http",
+            "url": format!("http://{addr}/down"),
+            "expect_status": 503,
+        }))
+        .await;
+        assert_eq!(value(&samples, "probe_success"), Some(1.0));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn http_body_mismatch() {
+        let url = format!("http://{}/health", http_server());
+        let samples = probe(json!({
+            "name": "site",
+            "kind": "http",
+            "url": url,
+            "expect_body": "ready",
+        }))
+        .await;
+        assert_eq!(value(&samples, "probe_success"), Some(0.0));
+        assert_eq!(value(&samples, "probe_http_status_code"), Some(200.0));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn http_timeout() {
+        // Accepts connections but never answers them
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/", listener.local_addr().unwrap());
+        tokio::spawn(async move {
+            let mut held = Vec::new();
+            while let Ok((stream, _)) = listener.accept().await {
+                held.push(stream);
+            }
+        });
+        let samples =
+            probe(json!({ "name": "slow", "kind": "http", "url": url, "timeout": 1 })).await;
+        assert_eq!(value(&samples, "probe_success"), Some(0.0));
+        assert_eq!(value(&samples, "probe_http_status_code"), None);
+        assert!(value(&samples, "probe_duration_seconds").is_some_and(|d| d >= 1.0));
+    }
+}
@@ -1,20 +1,343 @@
+use crate::backoff::BackoffPolicy;
+use once_cell::sync::Lazy;
+use serde::Deserialize;
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::env;
+use std::sync::Mutex;
+use std::time::Duration;
+use sysinfo::System;
 
 pub struct ConnectOptions {
     pub port: u16,
 }
 
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum HubSelection {
+    /// Prefer hubs in the order they are listed
+    Ordered,
+    /// Pick hubs in a random order, spreading clients across them
+    Random,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum HubMode {
+    /// Stay connected to one hub at a time, failing over to the next
+    Failover,
+    /// Stay connected to every hub at once
+    All,
+}
+
 pub struct HubProps {
-    pub proxy_response_uri: String,
-    pub ws_uri: String,
+    pub mode: HubMode,
+    pub proxy_response_uris: Vec<String>,
+    pub redirect_uri: Option<String>,
+    pub reconnect_delay: Duration,
+    pub selection: HubSelection,
+    pub ws_uris: Vec<String>,
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ChannelKind {
+    Webhook,
+    Slack,
+    Alertmanager,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct AlertChannel {
+    pub name: String,
+    pub kind: ChannelKind,
+    pub url: String,
+}
+
+pub struct AlertProps {
+    pub channels: Vec<AlertChannel>,
+    pub group_by: Vec<String>,
+    pub group_wait: Duration,
+    pub repeat_interval: Duration,
+    pub max_attempts: u32,
+    pub retry_delay: Duration,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RegistryBackend {
+    /// Clients are only known to the hub instance they are connected to
+    Local,
+    /// Hub instances share a SQLite database of which instance holds which client
+    Sqlite,
+    /// Hub instances know each other's URLs and ask each other
+    Peers,
+}
+
+pub struct RegistryProps {
+    pub backend: RegistryBackend,
+    /// How other hub instances reach this one
+    pub instance_url: String,
+    pub path: String,
+    /// Every hub instance's URL, this one's included, in the same order on each
+    pub peers: Vec<String>,
+}
+
+pub struct ProcessProps {
+    pub interval: Duration,
+    pub retention: Duration,
+    pub top_n: usize,
+}
+
+pub struct CgroupProps {
+    /// Also report every cgroup below the agent's own
+    pub children: bool,
+    /// The agent's cgroup relative to `root`, read from /proc/self/cgroup when unset
+    pub path: Option<String>,
+    /// Where the cgroup v2 hierarchy is mounted
+    pub root: String,
+}
+
+pub struct CollectorProps {
+    pub enabled: Vec<String>,
+    /// Overrides of the collectors' default intervals
+    pub intervals: HashMap<String, Duration>,
+}
+
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum CheckFormat {
+    /// An exit code of 0 to 3 and a line of text, optionally followed by `|` and perfdata
+    #[default]
+    Nagios,
+    /// A JSON array of `{"metric", "labels", "value"}` objects, or an object of numbers
+    Json,
+    /// InfluxDB line protocol
+    Line,
+}
+
+fn default_check_timeout() -> u64 {
+    10
+}
+
+/// An executable the client runs on a schedule, configured in `CHECKS`.
+#[derive(Clone, Debug, Deserialize)]
+pub struct CheckConfig {
+    pub name: String,
+    pub command: String,
+    #[serde(default)]
+    pub args: Vec<String>,
+    #[serde(default)]
+    pub format: CheckFormat,
+    /// Seconds between runs, otherwise the `checks` collector's interval
+    pub interval: Option<u64>,
+    /// Seconds the check may run before it is killed
+    #[serde(default = "default_check_timeout")]
+    pub timeout: u64,
+}
+
+fn default_probe_timeout() -> u64 {
+    5
+}
+
+/// What a probe checks, configured by its `kind`.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(tag = "kind", rename_all = "lowercase")]
+pub enum ProbeTarget {
+    /// Succeeds when the request gets the expected status, any 2xx by default,
+    /// and its body matches `expect_body` if set
+    Http {
+        url: String,
+        #[serde(default)]
+        method: Option<String>,
+        #[serde(default)]
+        expect_status: Option<u16>,
+        /// A regular expression the body must match
+        #[serde(default)]
+        expect_body: Option<String>,
+    },
+    /// Succeeds when a TCP connection to `address`, e.g. `db:5432`, can be opened
+    Tcp { address: String },
+    /// Succeeds when `host` resolves, and to `expect_address` among others if set
+    Dns {
+        host: String,
+        #[serde(default)]
+        expect_address: Option<String>,
+    },
+}
+
+/// An endpoint the client probes on a schedule, configured in `PROBES`.
+#[derive(Clone, Debug, Deserialize)]
+pub struct ProbeConfig {
+    pub name: String,
+    #[serde(flatten)]
+    pub target: ProbeTarget,
+    /// Seconds between probes, otherwise the `probes` collector's interval
+    pub interval: Option<u64>,
+    /// Seconds to wait before counting the probe as failed
+    #[serde(default = "default_probe_timeout")]
+    pub timeout: u64,
+}
+
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum LogMetricKind {
+    /// Counts matching lines, or adds up their `value`
+    #[default]
+    Counter,
+    /// The `value` of the latest matching line
+    Gauge,
+    /// Counts the `value`s of matching lines into buckets, along with their sum
+    Histogram,
+}
+
+fn default_log_metric_scale() -> f64 {
+    1.0
+}
+
+/// A metric the client derives from the log lines matching `pattern`,
+/// configured in `LOG_METRICS`.
+#[derive(Clone, Debug, Deserialize)]
+pub struct LogMetricConfig {
+    pub name: String,
+    /// A regular expression whose named groups, apart from `value`, become labels
+    pub pattern: String,
+    #[serde(default)]
+    pub kind: LogMetricKind,
+    /// Only match the lines of this file rather than of every file
+    #[serde(default)]
+    pub file: Option<String>,
+    /// What `value` is multiplied by, e.g. `0.001` to record milliseconds as seconds
+    #[serde(default = "default_log_metric_scale")]
+    pub scale: f64,
+    /// The upper bounds of a histogram's buckets, otherwise Prometheus' defaults
+    #[serde(default)]
+    pub buckets: Vec<f64>,
+}
+
+pub struct LogProps {
+    /// How many lines the client keeps, across every file
+    pub buffer_lines: usize,
+    pub files: Vec<String>,
+    pub metrics: Vec<LogMetricConfig>,
+    pub metrics_interval: Duration,
+    pub poll_interval: Duration,
+}
+
+pub struct StatsdProps {
+    pub flush_interval: Duration,
+    /// The quantiles reported for timers and histograms, in percent
+    pub percentiles: Vec<f64>,
+    /// The client only listens for StatsD when a port is configured
+    pub port: Option<u16>,
+}
+
+pub struct WatchProps {
+    pub pid_files: Vec<String>,
+    pub processes: Vec<String>,
+}
+
+pub struct HeartbeatProps {
+    pub interval: Duration,
+    pub timeout: Duration,
 }
 
 pub struct Options {
+    pub alerts: AlertProps,
+    pub client_status_port: Option<u16>,
+    pub auth_token: Option<String>,
+    pub cgroup: CgroupProps,
+    pub checks: Vec<CheckConfig>,
+    pub client_name: String,
+    pub collectors: CollectorProps,
+    pub heartbeat: HeartbeatProps,
+    pub host_down_grace: Duration,
+    pub labels: BTreeMap<String, String>,
+    pub logs: LogProps,
+    pub probes: Vec<ProbeConfig>,
+    pub processes: ProcessProps,
+    pub reconnect: BackoffPolicy,
+    pub registry: RegistryProps,
+    pub shutdown_drain: Duration,
+    pub site: Option<String>,
+    pub statsd: StatsdProps,
+    pub upstream_uris: Vec<String>,
+    pub watch: WatchProps,
     pub host: String,
     pub hub: HubProps,
     pub http_server: ConnectOptions,
 }
 
+fn env_u64(name: &str, default: u64) -> u64 {
+    env::var(name)
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(default)
+}
+
+/// Prints a warning about the configuration the first time it comes up, as
+/// the options are read again wherever they're needed.
+fn warn_once(message: String) {
+    static WARNED: Lazy<Mutex<HashSet<String>>> = Lazy::new(|| Mutex::new(HashSet::new()));
+    if WARNED.lock().unwrap().insert(message.clone()) {
+        eprintln!("{message}");
+    }
+}
+
+/// Like `env_u64`, for intervals, which fall back to the default when set to
+/// zero as there's no running something every zero seconds.
+fn env_interval(name: &str, default: u64) -> u64 {
+    match env_u64(name, default) {
+        0 => {
+            warn_once(format!("Ignoring {name}=0, intervals must be positive"));
+            default
+        }
+        value => value,
+    }
+}
+
+/// The interval a check or probe was given, unless it's zero, in which case
+/// the collector's interval applies.
+fn own_interval(interval: Option<u64>, kind: &str, name: &str) -> Option<u64> {
+    if interval == Some(0) {
+        warn_once(format!(
+            "Ignoring interval 0 of {kind} {name}, intervals must be positive"
+        ));
+        return None;
+    }
+    interval
+}
+
+fn env_list(name: &str, default: &str) -> Vec<String> {
+    env::var(name)
+        .unwrap_or_else(|_| default.to_string())
+        .split(',')
+        .map(str::trim)
+        .filter(|s| !s.is_empty())
+        .map(String::from)
+        .collect()
+}
+
+/// A JSON array from the variable, or from the file named by `<NAME>_FILE`.
+fn env_json_list<T: serde::de::DeserializeOwned>(name: &str) -> Vec<T> {
+    let file = format!("{name}_FILE");
+    let json = match env::var(&file).ok().filter(|p| !p.is_empty()) {
+        Some(path) => std::fs::read_to_string(&path)
+            .map_err(|e| warn_once(format!("Ignoring unreadable {file} {path}: {e}")))
+            .ok(),
+        None => env::var(name).ok(),
+    };
+    json.map(|v| {
+        serde_json::from_str(&v).unwrap_or_else(|e| {
+            warn_once(format!("Ignoring invalid {name}: {e}"));
+            Vec::new()
+        })
+    })
+    .unwrap_or_default()
+}
+
+fn env_bool(name: &str, default: bool) -> bool {
+    env::var(name).ok().map_or(default, |v| {
+        matches!(v.to_lowercase().as_str(), "1" | "true" | "yes")
+    })
+}
+
 impl Options {
     pub fn new() -> Self {
         let http_server_port = env::var("HTTP_SERVER_PORT")
@@ -24,21 +347,248 @@ impl Options {
 
         let host = env::var("WS_HOST").unwrap_or_else(|_| "127.0.0.1".to_string());
 
-        let ws_uri =
-            env::var("HUB_WS_URI").unwrap_or_else(|_| "ws://127.0.0.1:8890/ws".to_string());
+        // Both accept a comma separated list, one entry per hub
+        let ws_uris = env_list("HUB_WS_URI", "ws://127.0.0.1:8890/ws");
+        // Unset response URIs are derived from the matching websocket URI
+        let proxy_response_uris = env_list("HUB_PROXY_RESPONSE_URI", "");
 
-        let proxy_response_uri = env::var("HUB_PROXY_RESPONSE_URI")
-            .unwrap_or_else(|_| "http://127.0.0.1:8890/api/proxy/response".to_string());
+        let selection = match env::var("HUB_SELECTION").as_deref() {
+            Ok("random") => HubSelection::Random,
+            _ => HubSelection::Ordered,
+        };
+
+        let mode = match env::var("HUB_MODE").as_deref() {
+            Ok("all") => HubMode::All,
+            _ => HubMode::Failover,
+        };
+
+        // The client only serves its status endpoint when a port is configured
+        let client_status_port = env::var("CLIENT_STATUS_PORT")
+            .ok()
+            .and_then(|p| p.parse().ok());
+
+        // Where clients should reconnect to while this hub is going away, and the
+        // longest they should wait before doing so
+        let redirect_uri = env::var("HUB_REDIRECT_URI").ok().filter(|u| !u.is_empty());
+        let reconnect_delay = Duration::from_secs(env_u64("HUB_RECONNECT_DELAY_SECONDS", 10));
 
         let http_server = ConnectOptions {
             port: http_server_port,
         };
 
+        let client_name = env::var("CLIENT_NAME")
+            .ok()
+            .or_else(System::host_name)
+            .unwrap_or_else(|| "unknown".to_string());
+
+        // CLIENT_LABELS is a comma separated list of key=value pairs, e.g. role=web,zone=a
+        let labels = env_list("CLIENT_LABELS", "")
+            .into_iter()
+            .filter_map(|pair| {
+                let (key, value) = pair.split_once('=')?;
+                Some((key.trim().to_string(), value.trim().to_string()))
+            })
+            .collect();
+
+        // Clients snapshot the busiest processes by CPU and by memory every interval
+        let processes = ProcessProps {
+            interval: Duration::from_secs(env_interval("PROCESS_SNAPSHOT_INTERVAL_SECONDS", 30)),
+            retention: Duration::from_secs(env_u64("PROCESS_SNAPSHOT_RETENTION_SECONDS", 86400)),
+            top_n: usize::try_from(env_u64("PROCESS_TOP_N", 10)).unwrap_or(10),
+        };
+
+        // Inside a container the host-wide numbers are misleading, so clients
+        // also report the limits and usage of their own cgroup
+        let cgroup = CgroupProps {
+            children: env_bool("CGROUP_CHILDREN", false),
+            path: env::var("CGROUP_PATH").ok().filter(|p| !p.is_empty()),
+            root: env::var("CGROUP_ROOT").unwrap_or_else(|_| "/sys/fs/cgroup".to_string()),
+        };
+
+        // Which collectors the client runs, each every <NAME>_INTERVAL_SECONDS if set
+        let enabled = env_list(
+            "COLLECTORS",
+            "cpu,memory,disk,network,cgroup,watch,checks,probes",
+        );
+        let intervals = enabled
+            .iter()
+            .filter_map(|name| {
+                let var = format!("{}_INTERVAL_SECONDS", name.to_uppercase());
+                let secs = env::var(&var).ok()?.parse().ok()?;
+                if secs == 0 {
+                    warn_once(format!("Ignoring {var}=0, intervals must be positive"));
+                    return None;
+                }
+                Some((name.clone(), Duration::from_secs(secs)))
+            })
+            .collect();
+        let collectors = CollectorProps { enabled, intervals };
+
+        // Processes that must be running, by name or by the pid file they write
+        let watch = WatchProps {
+            pid_files: env_list("WATCH_PID_FILES", ""),
+            processes: env_list("WATCH_PROCESSES", ""),
+        };
+
+        // CHECKS is a JSON array, or CHECKS_FILE the path of a file holding one, e.g.
+        // [{"name": "disk", "command": "/usr/lib/nagios/plugins/check_disk", "args": ["-w", "20%"]}]
+        let mut checks: Vec<CheckConfig> = env_json_list("CHECKS");
+        for check in &mut checks {
+            check.interval = own_interval(check.interval, "check", &check.name);
+        }
+
+        // PROBES is configured the same way, e.g.
+        // [{"name": "site", "kind": "http", "url": "https://example.com", "expect_status": 200}]
+        let mut probes: Vec<ProbeConfig> = env_json_list("PROBES");
+        for probe in &mut probes {
+            probe.interval = own_interval(probe.interval, "probe", &probe.name);
+        }
+
+        // Applications on the host can send the client StatsD metrics over UDP,
+        // which it aggregates and records every flush interval
+        let statsd = StatsdProps {
+            flush_interval: Duration::from_secs(env_interval("STATSD_FLUSH_INTERVAL_SECONDS", 10)),
+            percentiles: env_list("STATSD_PERCENTILES", "50,90,99")
+                .iter()
+                .filter_map(|p| p.parse().ok())
+                .filter(|p| (0.0..=100.0).contains(p))
+                .collect(),
+            port: env::var("STATSD_PORT").ok().and_then(|p| p.parse().ok()),
+        };
+
+        // Clients tail these files, keeping their latest lines for the hub to show.
+        // LOG_METRICS (or LOG_METRICS_FILE) is a JSON array of metrics to derive
+        // from them, e.g. [{"name": "app_errors", "pattern": "ERROR (?P<module>\\w+)"}]
+        let logs = LogProps {
+            buffer_lines: usize::try_from(env_u64("LOG_BUFFER_LINES", 10_000)).unwrap_or(10_000),
+            files: env_list("LOG_FILES", ""),
+            metrics: env_json_list("LOG_METRICS"),
+            metrics_interval: Duration::from_secs(env_interval("LOG_METRICS_INTERVAL_SECONDS", 15)),
+            poll_interval: Duration::from_millis(env_interval("LOG_POLL_INTERVAL_MS", 1000)),
+        };
+
+        // Shared secret clients must present to the hub, unset to allow any client
+        let auth_token = env::var("HUB_AUTH_TOKEN").ok().filter(|t| !t.is_empty());
+
+        // The hub pings every interval; either side drops a connection that has
+        // been silent for longer than the timeout
+        let heartbeat_interval = env_interval("HEARTBEAT_INTERVAL_SECONDS", 15);
+        // A timeout no longer than the interval would drop every connection at its
+        // first tick, so it defaults to, and falls back on, a few missed pings
+        let default_timeout = heartbeat_interval.saturating_mul(3).max(45);
+        let heartbeat_timeout = match env_u64("HEARTBEAT_TIMEOUT_SECONDS", default_timeout) {
+            timeout if timeout > heartbeat_interval => timeout,
+            timeout => {
+                warn_once(format!(
+                    "Ignoring HEARTBEAT_TIMEOUT_SECONDS={timeout}, it must be longer than the \
+                     {heartbeat_interval}s heartbeat interval"
+                ));
+                default_timeout
+            }
+        };
+        let heartbeat = HeartbeatProps {
+            interval: Duration::from_secs(heartbeat_interval),
+            timeout: Duration::from_secs(heartbeat_timeout),
+        };
+
+        let host_down_grace = Duration::from_secs(env_u64("HOST_DOWN_GRACE_SECONDS", 300));
+
+        // A zero delay would have clients hammer the hub, and the backoff starts
+        // no higher than its ceiling
+        let reconnect_max = Duration::from_secs(env_interval("RECONNECT_MAX_DELAY_SECONDS", 60));
+        let mut reconnect_base =
+            Duration::from_millis(env_interval("RECONNECT_BASE_DELAY_MS", 1000));
+        if reconnect_base > reconnect_max {
+            warn_once(format!(
+                "Lowering RECONNECT_BASE_DELAY_MS to RECONNECT_MAX_DELAY_SECONDS ({}s)",
+                reconnect_max.as_secs()
+            ));
+            reconnect_base = reconnect_max;
+        }
+        let reconnect = BackoffPolicy {
+            base: reconnect_base,
+            max: reconnect_max,
+            jitter: env_bool("RECONNECT_JITTER", true),
+            max_retries: env::var("RECONNECT_MAX_RETRIES")
+                .ok()
+                .and_then(|v| v.parse().ok()),
+        };
+
+        // Federation: a hub with upstream URIs joins those hubs as a client,
+        // and labels its clients with the site name
+        let site = env::var("SITE").ok().filter(|s| !s.is_empty());
+        let upstream_uris = env_list("UPSTREAM_HUB_WS_URI", "");
+
+        // Running more than one hub instance needs a registry they all share
+        let registry = RegistryProps {
+            backend: match env::var("HUB_REGISTRY").as_deref() {
+                Ok("sqlite") => RegistryBackend::Sqlite,
+                Ok("peers") => RegistryBackend::Peers,
+                _ => RegistryBackend::Local,
+            },
+            instance_url: env::var("HUB_INSTANCE_URL")
+                .ok()
+                .filter(|u| !u.is_empty())
+                .unwrap_or_else(|| format!("http://{host}:{http_server_port}")),
+            path: env::var("HUB_REGISTRY_PATH").unwrap_or_else(|_| "registry.db".to_string()),
+            peers: env_list("HUB_PEERS", ""),
+        };
+
+        // How long tasks get to finish once shutdown begins
+        let shutdown_drain = Duration::from_secs(env_u64("SHUTDOWN_DRAIN_SECONDS", 10));
+
+        // ALERT_CHANNELS is a JSON array, e.g.
+        // [{"name": "oncall", "kind": "slack", "url": "https://hooks.slack.com/..."}]
+        let channels = env::var("ALERT_CHANNELS")
+            .ok()
+            .map(|v| {
+                serde_json::from_str(&v).unwrap_or_else(|e| {
+                    warn_once(format!("Ignoring invalid ALERT_CHANNELS: {e}"));
+                    Vec::new()
+                })
+            })
+            .unwrap_or_default();
+
+        let group_by = env_list("ALERT_GROUP_BY", "alertname");
+
+        let alerts = AlertProps {
+            channels,
+            group_by,
+            group_wait: Duration::from_secs(env_u64("ALERT_GROUP_WAIT_SECONDS", 30)),
+            repeat_interval: Duration::from_secs(env_u64("ALERT_REPEAT_INTERVAL_SECONDS", 14400)),
+            max_attempts: u32::try_from(env_u64("ALERT_MAX_ATTEMPTS", 5)).unwrap_or(5),
+            retry_delay: Duration::from_secs(env_u64("ALERT_RETRY_DELAY_SECONDS", 2)),
+        };
+
         Options {
+            alerts,
+            auth_token,
+            cgroup,
+            checks,
+            client_status_port,
+            client_name,
+            collectors,
+            heartbeat,
+            host_down_grace,
+            labels,
+            logs,
+            probes,
+            processes,
+            reconnect,
+            registry,
+            shutdown_drain,
+            site,
+            statsd,
+            upstream_uris,
+            watch,
             host,
             hub: HubProps {
-                proxy_response_uri,
-                ws_uri,
+                mode,
+                proxy_response_uris,
+                redirect_uri,
+                reconnect_delay,
+                selection,
+                ws_uris,
             },
             http_server,
         }
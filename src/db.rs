@@ -1,7 +1,10 @@
+use crate::protocol::{MetricSeries, ProcessInfo, ProcessSnapshot, SeriesInfo};
 use once_cell::sync::Lazy;
 use r2d2::{Pool, PooledConnection};
 use r2d2_sqlite::SqliteConnectionManager;
 use rusqlite::{params, Result};
+use serde::Serialize;
+use std::collections::BTreeMap;
 use std::error;
 use std::fmt;
 use std::time::SystemTime;
@@ -32,6 +35,11 @@ impl error::Error for Error {
     }
 }
 
+#[derive(Debug)]
+pub struct DatabaseError;
+
+impl warp::reject::Reject for DatabaseError {}
+
 // Implement `From` traits for automatic error conversion
 impl From<rusqlite::Error> for Error {
     fn from(err: rusqlite::Error) -> Error {
@@ -69,6 +77,72 @@ pub fn init() -> Result<(), Error> {
         )",
         [],
     )?;
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS samples (
+            timestamp   INTEGER,
+            metric      TEXT,
+            labels      TEXT,
+            value       REAL
+        )",
+        [],
+    )?;
+    conn.execute(
+        "CREATE INDEX IF NOT EXISTS samples_metric ON samples (metric, timestamp)",
+        [],
+    )?;
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS process_snapshots (
+            timestamp   INTEGER,
+            pid         INTEGER,
+            name        TEXT,
+            command     TEXT,
+            user        TEXT,
+            cpu_usage   REAL,
+            memory      INTEGER
+        )",
+        [],
+    )?;
+    conn.execute(
+        "CREATE INDEX IF NOT EXISTS process_snapshots_timestamp ON process_snapshots (timestamp)",
+        [],
+    )?;
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS alert_deliveries (
+            id          INTEGER PRIMARY KEY AUTOINCREMENT,
+            timestamp   INTEGER,
+            channel     TEXT,
+            group_key   TEXT,
+            status      TEXT,
+            attempt     INTEGER,
+            success     INTEGER,
+            detail      TEXT
+        )",
+        [],
+    )?;
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS known_clients (
+            name        TEXT PRIMARY KEY,
+            address     TEXT,
+            first_seen  INTEGER,
+            last_seen   INTEGER
+        )",
+        [],
+    )?;
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS connection_events (
+            id          INTEGER PRIMARY KEY AUTOINCREMENT,
+            timestamp   INTEGER,
+            client      TEXT,
+            kind        TEXT,
+            remote_addr TEXT,
+            reason      TEXT
+        )",
+        [],
+    )?;
+    conn.execute(
+        "CREATE INDEX IF NOT EXISTS connection_events_client ON connection_events (client, timestamp)",
+        [],
+    )?;
     Ok(())
 }
 
@@ -107,6 +181,169 @@ pub fn get_all_stats() -> Result<Vec<(i64, f32)>, Error> {
     Ok(stats)
 }
 
+// Function to retrieve the CPU usage stats recorded since a given time
+pub fn get_stats_since(since: i64) -> Result<Vec<(i64, f32)>, Error> {
+    let conn = get_connection()?;
+    let mut stmt = conn.prepare(
+        "SELECT timestamp, cpu_usage FROM stats WHERE timestamp >= ?1 ORDER BY timestamp DESC",
+    )?;
+    let stats = stmt
+        .query_map(params![since], |row| Ok((row.get(0)?, row.get(1)?)))?
+        .collect::<Result<Vec<_>, _>>()?;
+    Ok(stats)
+}
+
+// A single value of a labelled metric, e.g. `cpu_core_usage` of core 3
+pub struct Sample {
+    pub metric: String,
+    pub labels: BTreeMap<String, String>,
+    pub value: f64,
+}
+
+// Function to insert the samples of one collection run, all at the same time
+pub fn insert_samples(timestamp: i64, samples: &[Sample]) -> Result<(), Error> {
+    let mut conn = get_connection()?;
+    let tx = conn.transaction()?;
+    {
+        let mut stmt = tx.prepare(
+            "INSERT INTO samples (timestamp, metric, labels, value) VALUES (?1, ?2, ?3, ?4)",
+        )?;
+        for sample in samples {
+            // BTreeMap serializes in key order, so equal label sets give equal text
+            let labels = serde_json::to_string(&sample.labels).unwrap_or_default();
+            stmt.execute(params![timestamp, sample.metric, labels, sample.value])?;
+        }
+    }
+    tx.commit()?;
+    Ok(())
+}
+
+// Function to retrieve every series of a metric recorded since a given time
+pub fn get_samples_since(metric: &str, since: i64) -> Result<Vec<MetricSeries>, Error> {
+    let conn = get_connection()?;
+    let mut stmt = conn.prepare(
+        "SELECT labels, timestamp, value FROM samples
+         WHERE metric = ?1 AND timestamp >= ?2 ORDER BY timestamp DESC",
+    )?;
+    let rows = stmt.query_map(params![metric, since], |row| {
+        Ok((
+            row.get::<_, String>(0)?,
+            row.get::<_, i64>(1)?,
+            row.get::<_, f64>(2)?,
+        ))
+    })?;
+
+    let mut series: BTreeMap<String, Vec<(i64, f64)>> = BTreeMap::new();
+    for row in rows {
+        let (labels, timestamp, value) = row?;
+        series.entry(labels).or_default().push((timestamp, value));
+    }
+    Ok(series
+        .into_iter()
+        .map(|(labels, samples)| MetricSeries {
+            labels: serde_json::from_str(&labels).unwrap_or_default(),
+            samples,
+        })
+        .collect())
+}
+
+// Function to list the name of every metric with a sample since a given time
+pub fn get_metrics_since(since: i64) -> Result<Vec<String>, Error> {
+    let conn = get_connection()?;
+    let mut stmt = conn.prepare("SELECT DISTINCT metric FROM samples WHERE timestamp >= ?1")?;
+    let metrics = stmt
+        .query_map(params![since], |row| row.get(0))?
+        .collect::<Result<Vec<_>, _>>()?;
+    Ok(metrics)
+}
+
+// Function to list the name and labels of every series with a sample since a given time
+pub fn get_series_since(since: i64) -> Result<Vec<SeriesInfo>, Error> {
+    let conn = get_connection()?;
+    let mut stmt =
+        conn.prepare("SELECT DISTINCT metric, labels FROM samples WHERE timestamp >= ?1")?;
+    let series = stmt
+        .query_map(params![since], |row| {
+            Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?))
+        })?
+        .map(|row| {
+            row.map(|(metric, labels)| SeriesInfo {
+                metric,
+                labels: serde_json::from_str(&labels).unwrap_or_default(),
+            })
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    Ok(series)
+}
+
+// Function to record the processes in one snapshot, all at the same time
+pub fn insert_process_snapshot(timestamp: i64, processes: &[ProcessInfo]) -> Result<(), Error> {
+    let mut conn = get_connection()?;
+    let tx = conn.transaction()?;
+    {
+        let mut stmt = tx.prepare(
+            "INSERT INTO process_snapshots (timestamp, pid, name, command, user, cpu_usage, memory)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+        )?;
+        for process in processes {
+            stmt.execute(params![
+                timestamp,
+                process.pid,
+                process.name,
+                process.command,
+                process.user,
+                process.cpu_usage,
+                // SQLite integers are signed
+                i64::try_from(process.memory).unwrap_or(i64::MAX),
+            ])?;
+        }
+    }
+    tx.commit()?;
+    Ok(())
+}
+
+// Function to retrieve the latest process snapshot taken at or before a given time
+pub fn get_process_snapshot(at: i64) -> Result<Option<ProcessSnapshot>, Error> {
+    let conn = get_connection()?;
+    let timestamp: Option<i64> = conn.query_row(
+        "SELECT MAX(timestamp) FROM process_snapshots WHERE timestamp <= ?1",
+        [at],
+        |row| row.get(0),
+    )?;
+    let Some(timestamp) = timestamp else {
+        return Ok(None);
+    };
+    let mut stmt = conn.prepare(
+        "SELECT pid, name, command, user, cpu_usage, memory FROM process_snapshots
+         WHERE timestamp = ?1 ORDER BY cpu_usage DESC",
+    )?;
+    let processes = stmt
+        .query_map([timestamp], |row| {
+            Ok(ProcessInfo {
+                pid: row.get(0)?,
+                name: row.get(1)?,
+                command: row.get(2)?,
+                user: row.get(3)?,
+                cpu_usage: row.get(4)?,
+                memory: u64::try_from(row.get::<_, i64>(5)?).unwrap_or_default(),
+            })
+        })?
+        .collect::<Result<Vec<_>, _>>()?;
+    Ok(Some(ProcessSnapshot {
+        timestamp,
+        processes,
+    }))
+}
+
+pub fn expire_process_snapshots(before: i64) -> Result<(), Error> {
+    let conn = get_connection()?;
+    conn.execute(
+        "DELETE FROM process_snapshots WHERE timestamp < ?1",
+        [before],
+    )?;
+    Ok(())
+}
+
 pub fn expire_records() -> Result<(), Error> {
     let conn = get_connection()?;
     let q = format!("DELETE FROM stats WHERE timestamp < (unixepoch() - {EXPIRE_SECONDS})");
@@ -115,5 +352,205 @@ pub fn expire_records() -> Result<(), Error> {
         Ok(_) => eprintln!("Expiration Successful"),
         Err(e) => eprintln!("An error occurred: {e}"),
     };
+    conn.execute(
+        &format!("DELETE FROM samples WHERE timestamp < (unixepoch() - {EXPIRE_SECONDS})"),
+        [],
+    )?;
+    conn.execute(
+        &format!(
+            "DELETE FROM connection_events WHERE timestamp < (unixepoch() - {EXPIRE_SECONDS})"
+        ),
+        [],
+    )?;
+    conn.execute(
+        &format!("DELETE FROM alert_deliveries WHERE timestamp < (unixepoch() - {EXPIRE_SECONDS})"),
+        [],
+    )?;
+    Ok(())
+}
+
+#[derive(Serialize)]
+pub struct Delivery {
+    pub timestamp: i64,
+    pub channel: String,
+    pub group_key: String,
+    pub status: String,
+    pub attempt: u32,
+    pub success: bool,
+    pub detail: String,
+}
+
+// Function to record a single notification delivery attempt
+pub fn insert_delivery(delivery: &Delivery) -> Result<(), Error> {
+    let conn = get_connection()?;
+    conn.execute(
+        "INSERT INTO alert_deliveries (timestamp, channel, group_key, status, attempt, success, detail)
+         VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+        params![
+            delivery.timestamp,
+            delivery.channel,
+            delivery.group_key,
+            delivery.status,
+            delivery.attempt,
+            delivery.success,
+            delivery.detail,
+        ],
+    )?;
     Ok(())
 }
+
+// Function to retrieve the most recent notification delivery attempts
+pub fn get_deliveries(limit: u32) -> Result<Vec<Delivery>, Error> {
+    let conn = get_connection()?;
+    let mut stmt = conn.prepare(
+        "SELECT timestamp, channel, group_key, status, attempt, success, detail
+         FROM alert_deliveries ORDER BY id DESC LIMIT ?1",
+    )?;
+    let rows = stmt.query_map([limit], |row| {
+        Ok(Delivery {
+            timestamp: row.get(0)?,
+            channel: row.get(1)?,
+            group_key: row.get(2)?,
+            status: row.get(3)?,
+            attempt: row.get(4)?,
+            success: row.get(5)?,
+            detail: row.get(6)?,
+        })
+    })?;
+
+    let mut deliveries = Vec::new();
+    for delivery in rows {
+        deliveries.push(delivery?);
+    }
+    Ok(deliveries)
+}
+
+#[derive(Serialize)]
+pub struct KnownClient {
+    pub name: String,
+    pub address: String,
+    pub first_seen: i64,
+    pub last_seen: i64,
+}
+
+// Function to remember a client, or refresh the address and last-seen time of a known one
+pub fn upsert_known_client(name: &str, address: &str, timestamp: i64) -> Result<(), Error> {
+    let conn = get_connection()?;
+    conn.execute(
+        "INSERT INTO known_clients (name, address, first_seen, last_seen) VALUES (?1, ?2, ?3, ?3)
+         ON CONFLICT(name) DO UPDATE SET address = excluded.address, last_seen = excluded.last_seen",
+        params![name, address, timestamp],
+    )?;
+    Ok(())
+}
+
+pub fn touch_known_client(name: &str, timestamp: i64) -> Result<(), Error> {
+    let conn = get_connection()?;
+    conn.execute(
+        "UPDATE known_clients SET last_seen = ?2 WHERE name = ?1",
+        params![name, timestamp],
+    )?;
+    Ok(())
+}
+
+pub fn get_known_clients() -> Result<Vec<KnownClient>, Error> {
+    let conn = get_connection()?;
+    let mut stmt = conn
+        .prepare("SELECT name, address, first_seen, last_seen FROM known_clients ORDER BY name")?;
+    let rows = stmt.query_map([], |row| {
+        Ok(KnownClient {
+            name: row.get(0)?,
+            address: row.get(1)?,
+            first_seen: row.get(2)?,
+            last_seen: row.get(3)?,
+        })
+    })?;
+
+    let mut clients = Vec::new();
+    for client in rows {
+        clients.push(client?);
+    }
+    Ok(clients)
+}
+
+pub fn is_known_client(name: &str) -> Result<bool, Error> {
+    let conn = get_connection()?;
+    let count: i64 = conn.query_row(
+        "SELECT COUNT(*) FROM known_clients WHERE name = ?1",
+        [name],
+        |row| row.get(0),
+    )?;
+    Ok(count > 0)
+}
+
+// Returns whether a client with the given name was known
+pub fn delete_known_client(name: &str) -> Result<bool, Error> {
+    let conn = get_connection()?;
+    let deleted = conn.execute("DELETE FROM known_clients WHERE name = ?1", [name])?;
+    Ok(deleted > 0)
+}
+
+#[derive(Serialize)]
+pub struct ConnectionEvent {
+    pub timestamp: i64,
+    pub client: Option<String>,
+    pub kind: String,
+    pub remote_addr: String,
+    pub reason: String,
+}
+
+pub fn insert_connection_event(event: &ConnectionEvent) -> Result<(), Error> {
+    let conn = get_connection()?;
+    conn.execute(
+        "INSERT INTO connection_events (timestamp, client, kind, remote_addr, reason)
+         VALUES (?1, ?2, ?3, ?4, ?5)",
+        params![
+            event.timestamp,
+            event.client,
+            event.kind,
+            event.remote_addr,
+            event.reason,
+        ],
+    )?;
+    Ok(())
+}
+
+// Function to retrieve connection events, newest first. Unset filters match everything.
+pub fn get_connection_events(
+    client: Option<&str>,
+    kind: Option<&str>,
+    since: Option<i64>,
+    until: Option<i64>,
+    limit: u32,
+) -> Result<Vec<ConnectionEvent>, Error> {
+    let conn = get_connection()?;
+    // Spelled out rather than `(?1 IS NULL OR client = ?1)` so SQLite can use the client index
+    let client_filter = if client.is_some() {
+        "client = ?1"
+    } else {
+        "?1 IS NULL"
+    };
+    let mut stmt = conn.prepare(&format!(
+        "SELECT timestamp, client, kind, remote_addr, reason FROM connection_events
+         WHERE {client_filter}
+           AND (?2 IS NULL OR kind = ?2)
+           AND (?3 IS NULL OR timestamp >= ?3)
+           AND (?4 IS NULL OR timestamp <= ?4)
+         ORDER BY id DESC LIMIT ?5"
+    ))?;
+    let rows = stmt.query_map(params![client, kind, since, until, limit], |row| {
+        Ok(ConnectionEvent {
+            timestamp: row.get(0)?,
+            client: row.get(1)?,
+            kind: row.get(2)?,
+            remote_addr: row.get(3)?,
+            reason: row.get(4)?,
+        })
+    })?;
+
+    let mut events = Vec::new();
+    for event in rows {
+        events.push(event?);
+    }
+    Ok(events)
+}
@@ -0,0 +1,69 @@
+use crate::db;
+use crate::utils;
+use serde::{Deserialize, Serialize};
+
+const DEFAULT_LIMIT: u32 = 200;
+const MAX_LIMIT: u32 = 5000;
+
+#[derive(Clone, Copy, Debug)]
+pub enum EventKind {
+    Connect,
+    Disconnect,
+    Reconnect,
+    AuthFailure,
+}
+
+impl EventKind {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            EventKind::Connect => "connect",
+            EventKind::Disconnect => "disconnect",
+            EventKind::Reconnect => "reconnect",
+            EventKind::AuthFailure => "auth_failure",
+        }
+    }
+}
+
+/// Persists a connection event. Failures are logged rather than returned so
+/// that bookkeeping never interferes with the connection itself.
+pub fn record(kind: EventKind, client: Option<&str>, remote_addr: &str, reason: &str) {
+    let event = db::ConnectionEvent {
+        timestamp: utils::unix_timestamp(),
+        client: client.map(String::from),
+        kind: kind.as_str().to_string(),
+        remote_addr: remote_addr.to_string(),
+        reason: reason.to_string(),
+    };
+    if let Err(e) = db::insert_connection_event(&event) {
+        eprintln!("Error recording connection event: {e}");
+    }
+}
+
+#[derive(Deserialize)]
+pub struct EventFilter {
+    client: Option<String>,
+    kind: Option<String>,
+    since: Option<i64>,
+    until: Option<i64>,
+    limit: Option<u32>,
+}
+
+#[derive(Serialize)]
+struct Response {
+    events: Vec<db::ConnectionEvent>,
+}
+
+pub async fn handler(filter: EventFilter) -> Result<impl warp::Reply, warp::Rejection> {
+    let events = db::get_connection_events(
+        filter.client.as_deref(),
+        filter.kind.as_deref(),
+        filter.since,
+        filter.until,
+        filter.limit.unwrap_or(DEFAULT_LIMIT).min(MAX_LIMIT),
+    )
+    .map_err(|e| {
+        eprintln!("Error reading connection events: {e}");
+        warp::reject::custom(db::DatabaseError)
+    })?;
+    Ok(warp::reply::json(&Response { events }))
+}
@@ -0,0 +1,193 @@
+use crate::clients;
+use crate::db;
+use crate::protocol::{ClientInfo, ProxyParams};
+use crate::proxy;
+use crate::utils;
+use crate::websocket_server::Users;
+use futures_util::future::join_all;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+const DEFAULT_WINDOW: u64 = 60;
+const DEFAULT_TIMEOUT: u64 = 5;
+// Longer waits are cut short, as the answer is on hold until every client has had its time
+const MAX_TIMEOUT: u64 = 60;
+const DEFAULT_TOP: usize = 5;
+const PERCENTILES: [u8; 4] = [50, 90, 95, 99];
+
+#[derive(Deserialize)]
+pub struct FleetQuery {
+    /// Comma separated `key=value` pairs a client must match, e.g. `role=web`.
+    /// `name` and `site` match the client's name and site, anything else its labels.
+    selector: Option<String>,
+    /// Seconds of stats to average for each client
+    window: Option<u64>,
+    /// Seconds to wait for each client
+    timeout: Option<u64>,
+    /// How many of the busiest clients to list
+    top: Option<usize>,
+}
+
+#[derive(Clone, Serialize)]
+struct ClientValue {
+    id: String,
+    name: Option<String>,
+    value: f64,
+}
+
+#[derive(Serialize)]
+struct Missing {
+    id: String,
+    name: Option<String>,
+    reason: &'static str,
+}
+
+#[derive(Serialize)]
+struct Aggregate {
+    count: usize,
+    sum: f64,
+    avg: f64,
+    min: f64,
+    max: f64,
+    percentiles: BTreeMap<String, f64>,
+}
+
+#[derive(Serialize)]
+struct Response {
+    metric: &'static str,
+    window: u64,
+    aggregate: Option<Aggregate>,
+    top: Vec<ClientValue>,
+    clients: Vec<ClientValue>,
+    missing: Vec<Missing>,
+}
+
+fn parse_selector(selector: Option<&str>) -> Vec<(String, String)> {
+    selector
+        .unwrap_or_default()
+        .split(',')
+        .filter_map(|pair| {
+            let (key, value) = pair.split_once('=')?;
+            Some((key.trim().to_string(), value.trim().to_string()))
+        })
+        .collect()
+}
+
+fn matches(client: &ClientInfo, selector: &[(String, String)]) -> bool {
+    selector.iter().all(|(key, value)| {
+        let actual = match key.as_str() {
+            "name" => client.name.as_ref(),
+            "site" => client.site.as_ref(),
+            _ => client.labels.get(key),
+        };
+        actual == Some(value)
+    })
+}
+
+/// The value at the given percentile of sorted values, using the nearest rank.
+fn percentile(sorted: &[f64], p: u8) -> f64 {
+    let rank = (f64::from(p) / 100.0 * sorted.len() as f64).ceil() as usize;
+    sorted[rank.clamp(1, sorted.len()) - 1]
+}
+
+fn aggregate(values: &[f64]) -> Option<Aggregate> {
+    if values.is_empty() {
+        return None;
+    }
+    let mut sorted = values.to_vec();
+    sorted.sort_by(f64::total_cmp);
+    let sum: f64 = sorted.iter().sum();
+    Some(Aggregate {
+        count: sorted.len(),
+        sum,
+        avg: sum / sorted.len() as f64,
+        min: sorted[0],
+        max: sorted[sorted.len() - 1],
+        percentiles: PERCENTILES
+            .iter()
+            .map(|&p| (format!("p{p}"), percentile(&sorted, p)))
+            .collect(),
+    })
+}
+
+/// Asks one client for its stats over the window and averages them.
+async fn client_value(id: &str, users: &Users, params: ProxyParams) -> Result<f64, &'static str> {
+    let response = proxy::request(id, users, params)
+        .await
+        .map_err(|e| proxy::failure_reason(&e))?;
+    let stats: Vec<(i64, f32)> =
+        serde_json::from_value(response).map_err(|_| "invalid response")?;
+    if stats.is_empty() {
+        return Err("no data");
+    }
+    let sum: f64 = stats.iter().map(|&(_, cpu)| f64::from(cpu)).sum();
+    Ok(sum / stats.len() as f64)
+}
+
+/// Queries every matching client at once and aggregates their CPU usage.
+pub async fn handler(query: FleetQuery, users: Users) -> Result<impl warp::Reply, warp::Rejection> {
+    let selector = parse_selector(query.selector.as_deref());
+    let window = query.window.unwrap_or(DEFAULT_WINDOW);
+    let params = ProxyParams {
+        since: Some(utils::unix_timestamp() - i64::try_from(window).unwrap_or(i64::MAX)),
+        timeout_ms: Some(
+            query
+                .timeout
+                .unwrap_or(DEFAULT_TIMEOUT)
+                .min(MAX_TIMEOUT)
+                .saturating_mul(1000),
+        ),
+        ..ProxyParams::default()
+    };
+
+    let targets: Vec<ClientInfo> = clients::list(&users)
+        .await
+        .map_err(|e| {
+            eprintln!("Error reading known clients: {e}");
+            warp::reject::custom(db::DatabaseError)
+        })?
+        .into_iter()
+        .filter(|c| c.online && c.id.is_some() && matches(c, &selector))
+        .collect();
+
+    let results = join_all(
+        targets
+            .iter()
+            .map(|c| client_value(c.id.as_deref().unwrap_or_default(), &users, params.clone())),
+    )
+    .await;
+
+    let mut clients = Vec::new();
+    let mut missing = Vec::new();
+    for (client, result) in targets.into_iter().zip(results) {
+        let id = client.id.unwrap_or_default();
+        match result {
+            Ok(value) => clients.push(ClientValue {
+                id,
+                name: client.name,
+                value,
+            }),
+            Err(reason) => missing.push(Missing {
+                id,
+                name: client.name,
+                reason,
+            }),
+        }
+    }
+    clients.sort_by(|a, b| b.value.total_cmp(&a.value));
+
+    let values: Vec<f64> = clients.iter().map(|c| c.value).collect();
+    let res = Response {
+        metric: "cpu_usage",
+        window,
+        aggregate: aggregate(&values),
+        top: clients
+            .iter()
+            .take(query.top.unwrap_or(DEFAULT_TOP))
+            .cloned()
+            .collect(),
+        clients,
+        missing,
+    };
+    Ok(warp::reply::json(&res))
+}
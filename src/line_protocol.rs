@@ -0,0 +1,438 @@
+use crate::clients;
+use crate::db::{insert_samples, DatabaseError, Sample};
+use crate::protocol::{LineError, Precision, ProxyParams, WriteResult};
+use crate::proxy;
+use crate::utils;
+use crate::websocket_server::Users;
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use warp::http::StatusCode;
+use warp::hyper::body::Bytes;
+
+/// The largest write body accepted, in bytes.
+pub const WRITE_LIMIT: u64 = 16 * 1024 * 1024;
+
+// How long the hub waits for a client to store a write it relays
+const RELAY_TIMEOUT_MS: u64 = 10_000;
+
+/// One line of InfluxDB line protocol, e.g.
+/// `disk,mount=/var used=1.5e9,inodes=120i 1700000000000000000`.
+/// Fields that aren't numbers or booleans are left out.
+pub struct Point {
+    pub measurement: String,
+    pub tags: BTreeMap<String, String>,
+    pub fields: Vec<(String, f64)>,
+    /// In whatever precision the sender used
+    pub timestamp: Option<i64>,
+}
+
+impl Point {
+    /// One sample per field, named `<measurement>_<field>`, or just after the
+    /// measurement for a field called `value`.
+    pub fn samples(&self) -> Vec<Sample> {
+        self.fields
+            .iter()
+            .map(|(field, value)| {
+                let name = if field == "value" {
+                    self.measurement.clone()
+                } else {
+                    format!("{}_{field}", self.measurement)
+                };
+                Sample {
+                    metric: metric_name(&name),
+                    labels: self
+                        .tags
+                        .iter()
+                        .map(|(k, v)| (metric_name(k), v.clone()))
+                        .collect(),
+                    value: *value,
+                }
+            })
+            .collect()
+    }
+}
+
+/// Makes a name usable as a Prometheus metric or label name.
+pub fn metric_name(name: &str) -> String {
+    let mut name: String = name
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || c == '_' || c == ':' {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect();
+    if name.is_empty() || name.starts_with(|c: char| c.is_ascii_digit()) {
+        name.insert(0, '_');
+    }
+    name
+}
+
+/// Splits on `sep` where it is not escaped with a backslash, nor inside double
+/// quotes when `quotes` is set, as only field values are quoted. Escapes are
+/// kept for `unescape` to remove.
+fn split_unescaped(s: &str, sep: char, quotes: bool) -> Vec<&str> {
+    let mut parts = Vec::new();
+    let mut start = 0;
+    let mut escaped = false;
+    let mut quoted = false;
+    for (i, c) in s.char_indices() {
+        if escaped {
+            escaped = false;
+        } else if c == '\\' {
+            escaped = true;
+        } else if c == '"' && quotes {
+            quoted = !quoted;
+        } else if c == sep && !quoted {
+            parts.push(&s[start..i]);
+            start = i + c.len_utf8();
+        }
+    }
+    parts.push(&s[start..]);
+    parts
+}
+
+fn unescape(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    let mut chars = s.chars();
+    while let Some(c) = chars.next() {
+        if c == '\\' {
+            out.extend(chars.next());
+        } else {
+            out.push(c);
+        }
+    }
+    out
+}
+
+fn key_value(pair: &str, quotes: bool) -> Result<(String, &str), String> {
+    match split_unescaped(pair, '=', quotes)[..] {
+        [key, value] if !key.is_empty() => Ok((unescape(key), value)),
+        _ => Err(format!("invalid key=value pair \"{pair}\"")),
+    }
+}
+
+/// The value of a numeric or boolean field, or `None` for a string field.
+fn field_value(value: &str) -> Result<Option<f64>, String> {
+    if value.starts_with('"') {
+        return Ok(None);
+    }
+    let number = value
+        .strip_suffix('i')
+        .or_else(|| value.strip_suffix('u'))
+        .unwrap_or(value);
+    match value {
+        "t" | "T" | "true" | "True" | "TRUE" => Ok(Some(1.0)),
+        "f" | "F" | "false" | "False" | "FALSE" => Ok(Some(0.0)),
+        _ => number
+            .parse()
+            .ok()
+            .filter(|v: &f64| v.is_finite())
+            .map(Some)
+            .ok_or_else(|| format!("invalid field value \"{value}\"")),
+    }
+}
+
+pub fn parse_line(line: &str) -> Result<Point, String> {
+    // Quotes only delimit anything once past the measurement and tags
+    let line = line.trim();
+    let series = split_unescaped(line, ' ', false)[0];
+    let parts: Vec<&str> = split_unescaped(&line[series.len()..], ' ', true)
+        .into_iter()
+        .filter(|p| !p.is_empty())
+        .collect();
+    let (fields, timestamp) = match parts[..] {
+        [fields] => (fields, None),
+        [fields, timestamp] => (fields, Some(timestamp)),
+        _ => return Err("expected a measurement, fields and an optional timestamp".to_string()),
+    };
+
+    let mut series = split_unescaped(series, ',', false).into_iter();
+    let measurement = unescape(series.next().unwrap_or_default());
+    if measurement.is_empty() {
+        return Err("missing measurement".to_string());
+    }
+    let tags = series
+        .map(|tag| key_value(tag, false).map(|(key, value)| (key, unescape(value))))
+        .collect::<Result<BTreeMap<_, _>, _>>()?;
+
+    let mut values = Vec::new();
+    for field in split_unescaped(fields, ',', true) {
+        let (key, value) = key_value(field, true)?;
+        if let Some(value) = field_value(value)? {
+            values.push((key, value));
+        }
+    }
+
+    let timestamp = timestamp
+        .map(|t| t.parse().map_err(|_| format!("invalid timestamp \"{t}\"")))
+        .transpose()?;
+
+    Ok(Point {
+        measurement,
+        tags,
+        fields: values,
+        timestamp,
+    })
+}
+
+/// Parses every line, skipping blank lines and `#` comments. Lines that can't be
+/// parsed are reported by their line number.
+pub fn parse(text: &str) -> (Vec<Point>, Vec<LineError>) {
+    let mut points = Vec::new();
+    let mut errors = Vec::new();
+    for (number, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        match parse_line(line) {
+            Ok(point) => points.push(point),
+            Err(error) => errors.push(LineError {
+                line: number + 1,
+                error,
+            }),
+        }
+    }
+    (points, errors)
+}
+
+/// A timestamp in the given precision, in whole seconds.
+fn to_seconds(timestamp: i64, precision: Precision) -> i64 {
+    match precision {
+        Precision::Nanoseconds => timestamp.div_euclid(1_000_000_000),
+        Precision::Microseconds => timestamp.div_euclid(1_000_000),
+        Precision::Milliseconds => timestamp.div_euclid(1_000),
+        Precision::Seconds => timestamp,
+        Precision::Minutes => timestamp.saturating_mul(60),
+        Precision::Hours => timestamp.saturating_mul(3600),
+    }
+}
+
+/// Stores every point that parses as samples, at its own time or else now.
+pub fn write(text: &str, precision: Precision) -> Result<WriteResult, crate::db::Error> {
+    let (points, errors) = parse(text);
+    let now = utils::unix_timestamp();
+    let mut by_time: BTreeMap<i64, Vec<Sample>> = BTreeMap::new();
+    for point in &points {
+        let timestamp = point.timestamp.map_or(now, |t| to_seconds(t, precision));
+        by_time
+            .entry(timestamp)
+            .or_default()
+            .extend(point.samples());
+    }
+    for (timestamp, samples) in &by_time {
+        insert_samples(*timestamp, samples)?;
+    }
+    Ok(WriteResult {
+        written: points.len(),
+        errors,
+    })
+}
+
+/// Query parameters of `/write`, named like InfluxDB's. On the hub, `client`
+/// names the client to write to by id or name; Telegraf's `db` (v1) or
+/// `bucket` (v2) can be used instead.
+#[derive(Deserialize)]
+pub struct WriteQuery {
+    precision: Option<String>,
+    client: Option<String>,
+    db: Option<String>,
+    bucket: Option<String>,
+}
+
+/// Answers the way InfluxDB does, with an `error` Telegraf can log.
+fn error_reply(code: StatusCode, error: &str, errors: &[LineError]) -> Box<dyn warp::Reply> {
+    let mut body = serde_json::json!({ "error": error });
+    if !errors.is_empty() {
+        body["errors"] = serde_json::json!(errors);
+    }
+    Box::new(warp::reply::with_status(warp::reply::json(&body), code))
+}
+
+fn write_reply(result: &WriteResult) -> Box<dyn warp::Reply> {
+    if result.errors.is_empty() {
+        return Box::new(StatusCode::NO_CONTENT);
+    }
+    let error = format!(
+        "partial write: {} line(s) rejected, {} point(s) written; first error at {}",
+        result.errors.len(),
+        result.written,
+        result.errors[0]
+    );
+    error_reply(StatusCode::BAD_REQUEST, &error, &result.errors)
+}
+
+fn parse_precision(precision: Option<&str>) -> Result<Precision, Box<dyn warp::Reply>> {
+    precision.map_or(Ok(Precision::default()), |p| {
+        serde_json::from_value(serde_json::Value::from(p)).map_err(|_| {
+            let error = format!("invalid precision \"{p}\", expected ns, us, ms, s, m or h");
+            error_reply(StatusCode::BAD_REQUEST, &error, &[])
+        })
+    })
+}
+
+/// Stores line protocol written to the client.
+pub async fn write_handler(
+    query: WriteQuery,
+    body: Bytes,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let precision = match parse_precision(query.precision.as_deref()) {
+        Ok(precision) => precision,
+        Err(reply) => return Ok(reply),
+    };
+    let result = write(&String::from_utf8_lossy(&body), precision).map_err(|e| {
+        eprintln!("Error storing written points: {e}");
+        warp::reject::custom(DatabaseError)
+    })?;
+    Ok(write_reply(&result))
+}
+
+/// Passes line protocol written to the hub on to the client it names.
+pub async fn relay_handler(
+    query: WriteQuery,
+    body: Bytes,
+    users: Users,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let precision = match parse_precision(query.precision.as_deref()) {
+        Ok(precision) => precision,
+        Err(reply) => return Ok(reply),
+    };
+    let Some(name) = query.client.or(query.db).or(query.bucket) else {
+        let error = "missing client parameter naming the client to write to";
+        return Ok(error_reply(StatusCode::BAD_REQUEST, error, &[]));
+    };
+    let clients = clients::list(&users).await.map_err(|e| {
+        eprintln!("Error reading known clients: {e}");
+        warp::reject::custom(DatabaseError)
+    })?;
+    let id = clients
+        .into_iter()
+        .filter(|c| c.online)
+        .find(|c| c.id.as_ref() == Some(&name) || c.name.as_ref() == Some(&name))
+        .and_then(|c| c.id);
+    let Some(id) = id else {
+        let error = format!("client {name} is not connected");
+        return Ok(error_reply(StatusCode::NOT_FOUND, &error, &[]));
+    };
+
+    let params = ProxyParams {
+        timeout_ms: Some(RELAY_TIMEOUT_MS),
+        write: Some(String::from_utf8_lossy(&body).into_owned()),
+        precision: Some(precision),
+        ..ProxyParams::default()
+    };
+    let response = proxy::request(&id, &users, params).await?;
+    // Older clients answer with their stats instead
+    match serde_json::from_value::<WriteResult>(response) {
+        Ok(result) => Ok(write_reply(&result)),
+        Err(_) => {
+            let error = format!("client {name} does not accept writes");
+            Ok(error_reply(StatusCode::BAD_GATEWAY, &error, &[]))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn non_finite_fields_are_line_errors() {
+        for value in ["inf", "-inf", "nan", "NaN", "infinity", "+Infinity"] {
+            let line = format!("cpu,host=a usage={value}");
+            assert!(parse_line(&line).is_err(), "{value} was accepted");
+        }
+        let point = parse_line("cpu,host=a usage=1.5,cores=4i,up=t").unwrap();
+        assert_eq!(point.samples().len(), 3);
+    }
+
+    #[test]
+    fn escapes_keep_separators_in_names() {
+        let point =
+            parse_line(r"disk\ io,mount=/var\ log,dev\,ice=sd\=a read\ bytes=5 10").unwrap();
+        assert_eq!(point.measurement, "disk io");
+        assert_eq!(
+            point.tags,
+            BTreeMap::from([
+                ("mount".to_string(), "/var log".to_string()),
+                ("dev,ice".to_string(), "sd=a".to_string()),
+            ])
+        );
+        assert_eq!(point.fields, [("read bytes".to_string(), 5.0)]);
+        assert_eq!(point.timestamp, Some(10));
+        let samples = point.samples();
+        assert_eq!(samples[0].metric, "disk_io_read_bytes");
+        assert_eq!(samples[0].labels["dev_ice"], "sd=a");
+    }
+
+    #[test]
+    fn string_fields_are_skipped_whole() {
+        let point = parse_line(r#"log,app=web msg="a, b=c \"d\" e",value=2,note="x y" 1700000000"#)
+            .unwrap();
+        assert_eq!(point.fields, [("value".to_string(), 2.0)]);
+        assert_eq!(point.timestamp, Some(1_700_000_000));
+        assert_eq!(point.samples()[0].metric, "log");
+    }
+
+    #[test]
+    fn quotes_only_delimit_field_values() {
+        let point = parse_line(r#"m,t=a"b f=1"#).unwrap();
+        assert_eq!(point.tags["t"], "a\"b");
+        assert_eq!(point.fields, [("f".to_string(), 1.0)]);
+        let point = parse_line(r#"m"x,t=" f=1 5"#).unwrap();
+        assert_eq!(point.measurement, "m\"x");
+        assert_eq!(point.tags["t"], "\"");
+        assert_eq!(point.timestamp, Some(5));
+    }
+
+    #[test]
+    fn timestamps_convert_to_seconds() {
+        assert_eq!(
+            to_seconds(1_700_000_000_123_456_789, Precision::Nanoseconds),
+            1_700_000_000
+        );
+        assert_eq!(
+            to_seconds(1_700_000_000_123_456, Precision::Microseconds),
+            1_700_000_000
+        );
+        assert_eq!(
+            to_seconds(1_700_000_000_999, Precision::Milliseconds),
+            1_700_000_000
+        );
+        assert_eq!(to_seconds(1_700_000_000, Precision::Seconds), 1_700_000_000);
+        assert_eq!(to_seconds(2, Precision::Minutes), 120);
+        assert_eq!(to_seconds(2, Precision::Hours), 7200);
+        // Times before 1970 round down, and huge ones don't overflow
+        assert_eq!(to_seconds(-1, Precision::Milliseconds), -1);
+        assert_eq!(to_seconds(i64::MAX, Precision::Hours), i64::MAX);
+    }
+
+    #[test]
+    fn errors_are_numbered_by_line() {
+        let text = "# comment\n\
+                    cpu value=1\n\
+                    \n\
+                    cpu\n\
+                    cpu value=x\n\
+                    cpu,host value=1\n\
+                    ,host=a value=1\n\
+                    cpu value=1 soon\n\
+                    cpu value=2 5\n";
+        let (points, errors) = parse(text);
+        assert_eq!(points.len(), 2);
+        let errors: Vec<String> = errors.iter().map(ToString::to_string).collect();
+        assert_eq!(
+            errors,
+            [
+                "line 4: expected a measurement, fields and an optional timestamp",
+                "line 5: invalid field value \"x\"",
+                "line 6: invalid key=value pair \"host\"",
+                "line 7: missing measurement",
+                "line 8: invalid timestamp \"soon\"",
+            ]
+        );
+    }
+}
@@ -0,0 +1,75 @@
+use crate::alerts;
+use crate::config::Options;
+use crate::db;
+use crate::registry;
+use crate::utils;
+use crate::websocket_server::Users;
+use std::collections::{BTreeMap, HashSet};
+use tokio::time::{self, Duration};
+use tokio_util::sync::CancellationToken;
+
+pub const HOST_DOWN: &str = "HostDown";
+const CHECK_INTERVAL: u64 = 10;
+
+pub fn alert_labels(name: &str) -> BTreeMap<String, String> {
+    BTreeMap::from([("client".to_string(), name.to_string())])
+}
+
+/// Names of the clients that are currently connected, to this or another
+/// instance of the hub, and have said hello.
+pub async fn online_names(users: &Users) -> HashSet<String> {
+    let mut names: HashSet<String> = users
+        .read()
+        .await
+        .values()
+        .filter_map(|c| c.name.clone())
+        .collect();
+    match registry::call(|registry| registry.remote_clients()).await {
+        Ok(remote) => names.extend(remote.into_iter().filter_map(|c| c.name)),
+        Err(e) => eprintln!("Error reading hub registry: {e}"),
+    }
+    names
+}
+
+/// Refreshes the last-seen time of connected clients and raises a host-down
+/// alert for known clients that have been gone longer than the grace period.
+pub async fn run(users: Users, shutdown: CancellationToken) {
+    let config = Options::new();
+    let grace = i64::try_from(config.host_down_grace.as_secs()).unwrap_or(i64::MAX);
+    // Clients can't reconnect while the hub is down, so don't count that time against them
+    let started = utils::unix_timestamp();
+    let mut interval = time::interval(Duration::from_secs(CHECK_INTERVAL));
+
+    loop {
+        // Wait for the next interval or until interrupted
+        tokio::select! {
+            _ = interval.tick() => {},
+            () = shutdown.cancelled() => break,
+        }
+        let known = match db::get_known_clients() {
+            Ok(known) => known,
+            Err(e) => {
+                eprintln!("Error reading known clients: {e}");
+                continue;
+            }
+        };
+        let online = online_names(&users).await;
+        let now = utils::unix_timestamp();
+        for client in known {
+            if online.contains(&client.name) {
+                if let Err(e) = db::touch_known_client(&client.name, now) {
+                    eprintln!("Error updating last seen time: {e}");
+                }
+            } else if now - client.last_seen.max(started) > grace {
+                let summary = format!(
+                    "{} has not been seen since {}",
+                    client.name,
+                    utils::to_rfc3339(client.last_seen)
+                );
+                alerts::fire(HOST_DOWN, alert_labels(&client.name), &summary).await;
+            }
+        }
+    }
+
+    println!("Liveness loop exiting");
+}
@@ -0,0 +1,307 @@
+use crate::config::{LogMetricConfig, LogMetricKind};
+use crate::db::Sample;
+use crate::line_protocol::metric_name;
+use regex::Regex;
+use std::collections::{BTreeMap, HashMap};
+
+// Prometheus' default histogram buckets, suited to durations in seconds
+const DEFAULT_BUCKETS: &[f64] = &[
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+
+// The named group holding the number a gauge or histogram records
+const VALUE: &str = "value";
+
+type Labels = BTreeMap<String, String>;
+
+/// A configured metric, ready to match lines against.
+struct Pattern {
+    /// As stored, e.g. with `_total` for counters
+    metric: String,
+    kind: LogMetricKind,
+    regex: Regex,
+    file: Option<String>,
+    scale: f64,
+    buckets: Vec<f64>,
+    /// The named groups that become labels, with the label names they become
+    labels: Vec<(String, String)>,
+}
+
+impl Pattern {
+    fn new(config: &LogMetricConfig) -> Result<Self, String> {
+        let regex = Regex::new(&config.pattern).map_err(|e| e.to_string())?;
+        let has_value = regex.capture_names().any(|name| name == Some(VALUE));
+        if config.kind != LogMetricKind::Counter && !has_value {
+            return Err("gauges and histograms need a (?P<value>...) group".to_string());
+        }
+        let labels = regex
+            .capture_names()
+            .flatten()
+            .filter(|&name| name != VALUE)
+            .map(|name| (name.to_string(), metric_name(name)))
+            .collect();
+        let name = metric_name(&config.name);
+        // Counters are named like Prometheus names them
+        let metric = if config.kind == LogMetricKind::Counter && !name.ends_with("_total") {
+            format!("{name}_total")
+        } else {
+            name
+        };
+        let mut buckets = if config.buckets.is_empty() {
+            DEFAULT_BUCKETS.to_vec()
+        } else {
+            config.buckets.clone()
+        };
+        buckets.sort_by(f64::total_cmp);
+        buckets.dedup();
+        Ok(Pattern {
+            metric,
+            kind: config.kind,
+            regex,
+            file: config.file.clone(),
+            scale: config.scale,
+            buckets,
+            labels,
+        })
+    }
+}
+
+#[derive(Default)]
+struct Histogram {
+    /// How many values fell at or below each bucket's bound, one more for `+Inf`
+    counts: Vec<u64>,
+    sum: f64,
+}
+
+/// Turns matching log lines into metrics, which carry on from when the client
+/// started: counters and histograms add up, and gauges keep their last value.
+pub struct LogMetrics {
+    patterns: Vec<Pattern>,
+    counters: HashMap<(usize, Labels), f64>,
+    gauges: HashMap<(usize, Labels), f64>,
+    histograms: HashMap<(usize, Labels), Histogram>,
+}
+
+impl LogMetrics {
+    pub fn new(configs: &[LogMetricConfig]) -> Self {
+        let patterns = configs
+            .iter()
+            .filter_map(|config| {
+                Pattern::new(config)
+                    .map_err(|e| eprintln!("Ignoring log metric {}: {e}", config.name))
+                    .ok()
+            })
+            .collect();
+        LogMetrics {
+            patterns,
+            counters: HashMap::new(),
+            gauges: HashMap::new(),
+            histograms: HashMap::new(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.patterns.is_empty()
+    }
+
+    /// Updates every metric whose pattern matches a line read from `file`.
+    pub fn observe(&mut self, file: &str, line: &str) {
+        for (index, pattern) in self.patterns.iter().enumerate() {
+            if pattern.file.as_ref().is_some_and(|f| f != file) {
+                continue;
+            }
+            let Some(captures) = pattern.regex.captures(line) else {
+                continue;
+            };
+            let labels: Labels = pattern
+                .labels
+                .iter()
+                .filter_map(|(group, label)| {
+                    Some((label.clone(), captures.name(group)?.as_str().to_string()))
+                })
+                .collect();
+            let value = match captures.name(VALUE) {
+                Some(value) => match value.as_str().parse::<f64>() {
+                    Ok(v) if v.is_finite() => Some(v * pattern.scale),
+                    // A line the pattern matched but that holds no number isn't counted
+                    _ => continue,
+                },
+                None => None,
+            };
+            let key = (index, labels);
+            match pattern.kind {
+                LogMetricKind::Counter => {
+                    *self.counters.entry(key).or_default() += value.unwrap_or(1.0);
+                }
+                // Their `value` group may be optional, and a line without one isn't a reading
+                LogMetricKind::Gauge => {
+                    let Some(value) = value else {
+                        continue;
+                    };
+                    self.gauges.insert(key, value);
+                }
+                LogMetricKind::Histogram => {
+                    let Some(value) = value else {
+                        continue;
+                    };
+                    let histogram = self.histograms.entry(key).or_default();
+                    histogram.counts.resize(pattern.buckets.len() + 1, 0);
+                    let bucket = pattern.buckets.partition_point(|&bound| bound < value);
+                    histogram.counts[bucket] += 1;
+                    histogram.sum += value;
+                }
+            }
+        }
+    }
+
+    /// The current value of every series seen so far.
+    pub fn samples(&self) -> Vec<Sample> {
+        let mut samples = Vec::new();
+        let mut push = |metric: String, labels: &Labels, value: f64| {
+            samples.push(Sample {
+                metric,
+                labels: labels.clone(),
+                value,
+            });
+        };
+        for ((index, labels), value) in self.counters.iter().chain(&self.gauges) {
+            push(self.patterns[*index].metric.clone(), labels, *value);
+        }
+        for ((index, labels), histogram) in &self.histograms {
+            let pattern = &self.patterns[*index];
+            let name = &pattern.metric;
+            // Buckets count every value at or below their bound, as Prometheus' do
+            let mut cumulative = 0;
+            for (i, count) in histogram.counts.iter().enumerate() {
+                cumulative += count;
+                let le = pattern
+                    .buckets
+                    .get(i)
+                    .map_or_else(|| "+Inf".to_string(), f64::to_string);
+                let mut labels = labels.clone();
+                labels.insert("le".to_string(), le);
+                push(format!("{name}_bucket"), &labels, cumulative as f64);
+            }
+            push(format!("{name}_count"), labels, cumulative as f64);
+            push(format!("{name}_sum"), labels, histogram.sum);
+        }
+        samples
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn metrics(configs: serde_json::Value) -> LogMetrics {
+        LogMetrics::new(&serde_json::from_value::<Vec<LogMetricConfig>>(configs).unwrap())
+    }
+
+    /// The samples as `(metric, labels, value)`, sorted so they can be compared.
+    fn samples(metrics: &LogMetrics) -> Vec<(String, String, f64)> {
+        let mut samples: Vec<_> = metrics
+            .samples()
+            .into_iter()
+            .map(|s| {
+                let labels: Vec<String> =
+                    s.labels.iter().map(|(k, v)| format!("{k}={v}")).collect();
+                (s.metric, labels.join(","), s.value)
+            })
+            .collect();
+        samples.sort_by(|a, b| (&a.0, &a.1).cmp(&(&b.0, &b.1)));
+        samples
+    }
+
+    fn sample(metric: &str, labels: &str, value: f64) -> (String, String, f64) {
+        (metric.to_string(), labels.to_string(), value)
+    }
+
+    #[test]
+    fn counters_count_lines_or_add_up_values() {
+        let mut metrics = metrics(serde_json::json!([
+            {"name": "errors", "pattern": "ERROR"},
+            {"name": "bytes_total", "pattern": r"sent (?P<value>\d+) bytes", "file": "app.log"},
+        ]));
+        for line in ["ERROR one", "sent 100 bytes", "INFO", "ERROR sent 20 bytes"] {
+            metrics.observe("app.log", line);
+        }
+        metrics.observe("other.log", "ERROR sent 5 bytes");
+        assert_eq!(
+            samples(&metrics),
+            [
+                sample("bytes_total", "", 120.0),
+                sample("errors_total", "", 3.0)
+            ]
+        );
+    }
+
+    #[test]
+    fn gauges_keep_the_latest_value_per_label() {
+        let mut metrics = metrics(serde_json::json!([{
+            "name": "queue.depth",
+            "kind": "gauge",
+            "pattern": r"queue (?P<queue_name>\w+) depth (?P<value>\S+)(?P<unused>!)?",
+            "scale": 2,
+        }]));
+        for line in [
+            "queue jobs depth 3",
+            "queue mail depth 1",
+            "queue jobs depth 5",
+            // Not a number, so not a reading
+            "queue mail depth many",
+        ] {
+            metrics.observe("app.log", line);
+        }
+        assert_eq!(
+            samples(&metrics),
+            [
+                sample("queue_depth", "queue_name=jobs", 10.0),
+                sample("queue_depth", "queue_name=mail", 2.0),
+            ]
+        );
+    }
+
+    #[test]
+    fn histograms_count_values_at_or_below_each_bound() {
+        let mut metrics = metrics(serde_json::json!([{
+            "name": "request_seconds",
+            "kind": "histogram",
+            "pattern": r"(?P<method>GET|POST) took (?P<value>\d+)ms",
+            "scale": 0.001,
+            "buckets": [1, 0.1, 0.1],
+        }]));
+        for line in [
+            "GET took 50ms",
+            "GET took 100ms",
+            "GET took 101ms",
+            "GET took 2000ms",
+        ] {
+            metrics.observe("app.log", line);
+        }
+        metrics.observe("app.log", "POST took 1000ms");
+        assert_eq!(
+            samples(&metrics),
+            [
+                sample("request_seconds_bucket", "le=+Inf,method=GET", 4.0),
+                sample("request_seconds_bucket", "le=+Inf,method=POST", 1.0),
+                sample("request_seconds_bucket", "le=0.1,method=GET", 2.0),
+                sample("request_seconds_bucket", "le=0.1,method=POST", 0.0),
+                sample("request_seconds_bucket", "le=1,method=GET", 3.0),
+                sample("request_seconds_bucket", "le=1,method=POST", 1.0),
+                sample("request_seconds_count", "method=GET", 4.0),
+                sample("request_seconds_count", "method=POST", 1.0),
+                sample("request_seconds_sum", "method=GET", 2.251),
+                sample("request_seconds_sum", "method=POST", 1.0),
+            ]
+        );
+    }
+
+    #[test]
+    fn invalid_patterns_are_left_out() {
+        let metrics = metrics(serde_json::json!([
+            {"name": "bad", "pattern": "("},
+            {"name": "level", "kind": "gauge", "pattern": "level"},
+        ]));
+        assert!(metrics.is_empty());
+    }
+}
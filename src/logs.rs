@@ -0,0 +1,621 @@
+use crate::config::Options;
+use crate::db::insert_samples;
+use crate::log_metrics::LogMetrics;
+use crate::protocol::{LogLine, LogLines, ProxyParams};
+use crate::proxy;
+use crate::utils;
+use crate::websocket_server::Users;
+use futures_util::stream;
+use once_cell::sync::Lazy;
+use regex::Regex;
+use serde::Deserialize;
+use std::collections::VecDeque;
+use std::convert::Infallible;
+use std::fs::{self, File, Metadata};
+use std::io::{Read, Seek, SeekFrom};
+use std::sync::Mutex;
+use tokio::task;
+use tokio::time::{self, Duration, Instant};
+use tokio_util::sync::CancellationToken;
+use warp::sse::Event;
+
+// How much of each file is read when the client starts, so there is something to look at
+const BACKLOG_BYTES: u64 = 64 * 1024;
+// Longer lines are cut short so one runaway line can't fill the buffer
+const MAX_LINE_BYTES: usize = 8 * 1024;
+// The most read from one file each round, so a flood in one doesn't hold up the others
+const READ_LIMIT: u64 = 4 * 1024 * 1024;
+// How many of the newest matching lines to return when the request doesn't say
+const DEFAULT_LIMIT: usize = 500;
+// The longest a client holds a request while waiting for new lines
+const MAX_WAIT: Duration = Duration::from_secs(60);
+// How often a waiting request looks for new lines
+const WAIT_POLL: Duration = Duration::from_millis(250);
+// How long each request of a followed stream waits for new lines
+const FOLLOW_WAIT_MS: u64 = 10_000;
+// How long to wait before asking a client again after a request failed
+const FOLLOW_RETRY: Duration = Duration::from_secs(2);
+
+/// The latest lines read from every file, up to `LOG_BUFFER_LINES`.
+struct Buffer {
+    lines: VecDeque<LogLine>,
+    capacity: usize,
+    files: Vec<String>,
+    /// The sequence number of the next line
+    next: u64,
+}
+
+static BUFFER: Lazy<Mutex<Buffer>> = Lazy::new(|| {
+    let config = Options::new().logs;
+    Mutex::new(Buffer {
+        lines: VecDeque::new(),
+        capacity: config.buffer_lines,
+        files: config.files,
+        next: 1,
+    })
+});
+
+impl Buffer {
+    fn push(&mut self, file: &str, timestamp: i64, line: String) {
+        if self.lines.len() >= self.capacity {
+            self.lines.pop_front();
+        }
+        self.lines.push_back(LogLine {
+            seq: self.next,
+            timestamp,
+            file: file.to_string(),
+            line,
+        });
+        self.next += 1;
+    }
+
+    fn query(&self, params: &ProxyParams, grep: Option<&Regex>) -> LogLines {
+        let last = self.next - 1;
+        // A client that restarted numbers its lines from 1 again, so start over
+        let after = params.after.filter(|&after| after <= last);
+        let matches = |line: &&LogLine| {
+            after.is_none_or(|after| line.seq > after)
+                && params.file.as_ref().is_none_or(|file| &line.file == file)
+                && grep.is_none_or(|grep| grep.is_match(&line.line) != params.invert)
+        };
+        let limit = params.limit.unwrap_or(DEFAULT_LIMIT);
+        let mut lines: Vec<LogLine> = self
+            .lines
+            .iter()
+            .rev()
+            .filter(matches)
+            .take(limit.saturating_add(1))
+            .cloned()
+            .collect();
+        let mut truncated = lines.len() > limit;
+        lines.truncate(limit);
+        lines.reverse();
+        if let (Some(after), Some(oldest)) = (after, self.lines.front()) {
+            truncated |= oldest.seq > after + 1;
+        }
+        LogLines {
+            lines,
+            last,
+            truncated,
+            files: self.files.clone(),
+            error: None,
+        }
+    }
+}
+
+/// Answers a proxy request for log lines, waiting up to `wait_ms` for a
+/// matching line when there is none yet.
+pub async fn query(params: &ProxyParams) -> LogLines {
+    let grep = params.grep.as_deref().filter(|g| !g.is_empty());
+    let grep = match grep.map(Regex::new).transpose() {
+        Ok(grep) => grep,
+        Err(e) => {
+            return LogLines {
+                error: Some(e.to_string()),
+                ..LogLines::default()
+            }
+        }
+    };
+    let wait = params
+        .wait_ms
+        .map_or(Duration::ZERO, Duration::from_millis)
+        .min(MAX_WAIT);
+    let deadline = Instant::now() + wait;
+    loop {
+        let lines = BUFFER.lock().unwrap().query(params, grep.as_ref());
+        if !lines.lines.is_empty() || Instant::now() >= deadline {
+            return lines;
+        }
+        time::sleep(WAIT_POLL).await;
+    }
+}
+
+/// An entry of `journalctl -o json` output, as written by e.g.
+/// `journalctl -f -o json >> /var/log/journal.json`.
+#[derive(Deserialize)]
+struct JournalEntry {
+    #[serde(rename = "MESSAGE")]
+    message: String,
+    /// Microseconds since the epoch
+    #[serde(rename = "__REALTIME_TIMESTAMP")]
+    realtime: Option<String>,
+    #[serde(rename = "SYSLOG_IDENTIFIER")]
+    identifier: Option<String>,
+    #[serde(rename = "_SYSTEMD_UNIT")]
+    unit: Option<String>,
+}
+
+/// The time and text of a line: a journal entry's own, or now and the line as it is.
+fn parse_line(line: String, now: i64) -> (i64, String) {
+    if !line.starts_with('{') {
+        return (now, line);
+    }
+    let Ok(entry) = serde_json::from_str::<JournalEntry>(&line) else {
+        return (now, line);
+    };
+    let timestamp = entry
+        .realtime
+        .and_then(|t| t.parse::<i64>().ok())
+        .map_or(now, |us| us / 1_000_000);
+    let text = match entry.identifier.or(entry.unit) {
+        Some(source) => format!("{source}: {}", entry.message),
+        None => entry.message,
+    };
+    (timestamp, text)
+}
+
+/// Identifies the file behind a path, to tell when it has been rotated.
+#[cfg(unix)]
+fn file_id(meta: &Metadata) -> Option<(u64, u64)> {
+    use std::os::unix::fs::MetadataExt;
+    Some((meta.dev(), meta.ino()))
+}
+
+#[cfg(not(unix))]
+fn file_id(_meta: &Metadata) -> Option<(u64, u64)> {
+    None
+}
+
+fn line_text(bytes: &[u8]) -> String {
+    let bytes = bytes.strip_suffix(b"\r").unwrap_or(bytes);
+    let mut text = String::from_utf8_lossy(bytes).into_owned();
+    if text.len() > MAX_LINE_BYTES {
+        let mut end = MAX_LINE_BYTES;
+        while !text.is_char_boundary(end) {
+            end -= 1;
+        }
+        text.truncate(end);
+    }
+    text
+}
+
+/// Follows one log file across rotation, whether it is moved away and
+/// recreated or truncated in place.
+struct Tail {
+    path: String,
+    file: Option<File>,
+    id: Option<(u64, u64)>,
+    position: u64,
+    /// The end of the file that isn't a whole line yet
+    partial: Vec<u8>,
+    /// Set when reading starts partway through a line, which is left out
+    skip_first: bool,
+}
+
+impl Tail {
+    fn new(path: &str) -> Self {
+        Tail {
+            path: path.to_string(),
+            file: None,
+            id: None,
+            position: 0,
+            partial: Vec::new(),
+            skip_first: false,
+        }
+    }
+
+    /// Opens the file to read from up to `backlog` bytes before its end.
+    fn open(&mut self, backlog: u64) {
+        let opened = File::open(&self.path).and_then(|mut file| {
+            let meta = file.metadata()?;
+            let start = meta.len().saturating_sub(backlog);
+            file.seek(SeekFrom::Start(start))?;
+            Ok((file, meta, start))
+        });
+        let Ok((file, meta, start)) = opened else {
+            return;
+        };
+        self.file = Some(file);
+        self.id = file_id(&meta);
+        self.position = start;
+        self.partial.clear();
+        self.skip_first = start > 0;
+    }
+
+    /// Reads the whole lines written since the last read.
+    fn read_lines(&mut self, lines: &mut Vec<String>) {
+        let Some(file) = &mut self.file else {
+            return;
+        };
+        let mut read = Vec::new();
+        match file.take(READ_LIMIT).read_to_end(&mut read) {
+            Ok(n) => self.position += n as u64,
+            Err(e) => eprintln!("Error reading {}: {e}", self.path),
+        }
+        self.partial.extend_from_slice(&read);
+        let data = std::mem::take(&mut self.partial);
+        let mut pieces: Vec<&[u8]> = data.split(|&b| b == b'\n').collect();
+        let partial = pieces.pop().unwrap_or_default();
+        for piece in pieces {
+            if self.skip_first {
+                self.skip_first = false;
+            } else {
+                lines.push(line_text(piece));
+            }
+        }
+        // A line that never ends is passed on in pieces rather than held forever
+        if partial.len() > MAX_LINE_BYTES {
+            lines.push(line_text(partial));
+        } else {
+            self.partial = partial.to_vec();
+        }
+    }
+
+    /// The lines written since the last read, finishing the file the path used
+    /// to name before moving on to the one it names now.
+    fn read(&mut self) -> Vec<String> {
+        let mut lines = Vec::new();
+        let meta = fs::metadata(&self.path).ok();
+        if self.file.is_some() {
+            let replaced = meta.as_ref().is_none_or(|m| file_id(m) != self.id);
+            if replaced {
+                self.read_lines(&mut lines);
+                if !self.partial.is_empty() {
+                    lines.push(line_text(&std::mem::take(&mut self.partial)));
+                }
+                self.file = None;
+            } else if meta.as_ref().is_some_and(|m| m.len() < self.position) {
+                if let Some(file) = &mut self.file {
+                    if file.seek(SeekFrom::Start(0)).is_ok() {
+                        self.position = 0;
+                        self.partial.clear();
+                    }
+                }
+            }
+        }
+        // A file that appears later, or replaces a rotated one, is read from the start
+        if self.file.is_none() && meta.is_some() {
+            self.open(u64::MAX);
+        }
+        self.read_lines(&mut lines);
+        lines
+    }
+}
+
+/// Keeps the lines in the buffer, and derives metrics from them unless they
+/// were read before.
+fn record(read: Vec<(String, Vec<String>)>, metrics: Option<&mut LogMetrics>) {
+    let now = utils::unix_timestamp();
+    let mut buffer = BUFFER.lock().unwrap();
+    let mut metrics = metrics.filter(|m| !m.is_empty());
+    for (file, lines) in read {
+        for line in lines {
+            let (timestamp, text) = parse_line(line, now);
+            if let Some(metrics) = &mut metrics {
+                metrics.observe(&file, &text);
+            }
+            buffer.push(&file, timestamp, text);
+        }
+    }
+}
+
+fn store(metrics: &LogMetrics) {
+    let samples = metrics.samples();
+    if samples.is_empty() {
+        return;
+    }
+    if let Err(e) = insert_samples(utils::unix_timestamp(), &samples) {
+        eprintln!("Error inserting log metric samples: {e}");
+    }
+}
+
+/// Reads every file on a blocking thread, handing the tails back with what was read.
+async fn read_all(
+    mut tails: Vec<Tail>,
+    read: fn(&mut Tail) -> Vec<String>,
+) -> Option<(Vec<Tail>, Vec<(String, Vec<String>)>)> {
+    task::spawn_blocking(move || {
+        let lines = tails
+            .iter_mut()
+            .map(|t| (t.path.clone(), read(t)))
+            .collect();
+        (tails, lines)
+    })
+    .await
+    .ok()
+}
+
+/// Tails the configured log files into the buffer every poll interval, and
+/// records the metrics derived from them every metrics interval, until shutdown.
+pub async fn run(shutdown: CancellationToken) {
+    let config = Options::new().logs;
+    let tails = config.files.iter().map(|path| Tail::new(path)).collect();
+    let mut metrics = LogMetrics::new(&config.metrics);
+
+    // The end of each file as it is now gives the buffer something to show from
+    // the start, but was already counted if the client has run before
+    let backlog = |tail: &mut Tail| {
+        tail.open(BACKLOG_BYTES);
+        let mut lines = Vec::new();
+        tail.read_lines(&mut lines);
+        lines
+    };
+    let Some((mut tails, read)) = read_all(tails, backlog).await else {
+        eprintln!("Log tailing stopped");
+        return;
+    };
+    record(read, None);
+    println!("Tailing {} log file(s)", config.files.len());
+
+    let mut interval = time::interval(config.poll_interval);
+    let mut flush = time::interval(config.metrics_interval);
+    flush.tick().await;
+    loop {
+        tokio::select! {
+            _ = interval.tick() => {},
+            _ = flush.tick(), if !metrics.is_empty() => {
+                store(&metrics);
+                continue;
+            }
+            () = shutdown.cancelled() => break,
+        }
+
+        let Some((returned, read)) = read_all(tails, Tail::read).await else {
+            eprintln!("Log tailing stopped");
+            return;
+        };
+        tails = returned;
+        record(read, Some(&mut metrics));
+    }
+
+    // Keep what was counted since the last flush
+    store(&metrics);
+    println!("Log tailing exiting");
+}
+
+fn failure(message: &str) -> Event {
+    Event::default().event("failure").data(message)
+}
+
+/// Asks a client for its log lines over and over, each request waiting for
+/// lines after the ones the last one returned.
+struct Follow {
+    id: String,
+    params: ProxyParams,
+    users: Users,
+    shutdown: CancellationToken,
+    after: Option<u64>,
+    done: bool,
+}
+
+impl Follow {
+    /// The next event to send, or `None` once the hub is shutting down.
+    async fn next(&mut self) -> Option<Event> {
+        loop {
+            let params = ProxyParams {
+                logs: true,
+                after: self.after,
+                // The first request returns what the client already has, straight away
+                wait_ms: self.after.map(|_| FOLLOW_WAIT_MS),
+                timeout_ms: Some(FOLLOW_WAIT_MS * 2),
+                ..self.params.clone()
+            };
+            // Let the request finish even if the stream is dropped, so it is cleaned up
+            let (id, users) = (self.id.clone(), self.users.clone());
+            let request = tokio::spawn(async move { proxy::request(&id, &users, params).await });
+            let response = tokio::select! {
+                response = request => response.ok()?,
+                () = self.shutdown.cancelled() => return None,
+            };
+            match response.map(serde_json::from_value::<LogLines>) {
+                Ok(Ok(lines)) => {
+                    if let Some(error) = &lines.error {
+                        self.done = true;
+                        return Some(failure(error));
+                    }
+                    let first = self.after.is_none();
+                    self.after = Some(lines.last);
+                    if first || !lines.lines.is_empty() {
+                        return Event::default().event("lines").json_data(&lines).ok();
+                    }
+                }
+                Ok(Err(_)) => {
+                    // Older clients answer with their stats instead
+                    self.done = true;
+                    return Some(failure("client does not keep logs"));
+                }
+                Err(rejection) if rejection.find::<proxy::NotConnected>().is_some() => {
+                    self.done = true;
+                    return Some(failure("client is not connected"));
+                }
+                Err(rejection) if rejection.find::<proxy::Draining>().is_some() => {
+                    self.done = true;
+                    return Some(failure("hub is shutting down"));
+                }
+                Err(_) => {
+                    tokio::select! {
+                        () = time::sleep(FOLLOW_RETRY) => {},
+                        () = self.shutdown.cancelled() => return None,
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Streams a client's log lines as server-sent events: a `lines` event holding
+/// `LogLines` for what the client already has and for each batch after, until
+/// a `failure` event says why the stream ended.
+pub async fn follow_handler(
+    id: String,
+    params: ProxyParams,
+    users: Users,
+    shutdown: CancellationToken,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let follow = Follow {
+        id,
+        after: params.after,
+        params,
+        users,
+        shutdown,
+        done: false,
+    };
+    let events = stream::unfold(follow, |mut follow| async move {
+        if follow.done {
+            return None;
+        }
+        let event = follow.next().await?;
+        Some((Ok::<_, Infallible>(event), follow))
+    });
+    Ok(warp::sse::reply(warp::sse::keep_alive().stream(events)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::OpenOptions;
+    use std::io::Write;
+
+    fn append(path: &std::path::Path, text: &str) {
+        let mut file = OpenOptions::new().append(true).open(path).unwrap();
+        file.write_all(text.as_bytes()).unwrap();
+    }
+
+    #[test]
+    fn tail_waits_for_whole_lines() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("app.log");
+        fs::write(&path, "first\npar").unwrap();
+        let mut tail = Tail::new(path.to_str().unwrap());
+        assert_eq!(tail.read(), ["first"]);
+        append(&path, "tial\r\nnext");
+        assert_eq!(tail.read(), ["partial"]);
+        assert!(tail.read().is_empty());
+        append(&path, "\n");
+        assert_eq!(tail.read(), ["next"]);
+    }
+
+    #[test]
+    fn tail_finishes_a_moved_file_before_the_new_one() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("app.log");
+        let rotated = dir.path().join("app.log.1");
+        fs::write(&path, "one\n").unwrap();
+        let mut tail = Tail::new(path.to_str().unwrap());
+        assert_eq!(tail.read(), ["one"]);
+
+        append(&path, "two\nthr");
+        fs::rename(&path, &rotated).unwrap();
+        append(&rotated, "ee");
+        fs::write(&path, "four\n").unwrap();
+        // The unfinished last line of the old file won't be finished now
+        assert_eq!(tail.read(), ["two", "three", "four"]);
+        append(&rotated, "lost\n");
+        append(&path, "five\n");
+        assert_eq!(tail.read(), ["five"]);
+
+        // A file that is gone for a while is read from its start when it returns
+        fs::remove_file(&path).unwrap();
+        assert!(tail.read().is_empty());
+        fs::write(&path, "six\n").unwrap();
+        assert_eq!(tail.read(), ["six"]);
+    }
+
+    #[test]
+    fn tail_starts_over_when_truncated_in_place() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("app.log");
+        fs::write(&path, "one\ntwo\n").unwrap();
+        let mut tail = Tail::new(path.to_str().unwrap());
+        assert_eq!(tail.read(), ["one", "two"]);
+        fs::write(&path, "3\n").unwrap();
+        assert_eq!(tail.read(), ["3"]);
+        append(&path, "4\n");
+        assert_eq!(tail.read(), ["4"]);
+    }
+
+    fn buffer(lines: &[&str]) -> Buffer {
+        let mut buffer = Buffer {
+            lines: VecDeque::new(),
+            capacity: 4,
+            files: vec!["app.log".to_string()],
+            next: 1,
+        };
+        for line in lines {
+            buffer.push("app.log", 0, line.to_string());
+        }
+        buffer
+    }
+
+    fn seqs(lines: &LogLines) -> Vec<u64> {
+        lines.lines.iter().map(|l| l.seq).collect()
+    }
+
+    #[test]
+    fn query_returns_lines_after_a_sequence_number() {
+        // Only the last 4 of the 6 lines are kept
+        let buffer = buffer(&["a", "b", "c", "d", "e", "f"]);
+        let query = |after| {
+            let params = ProxyParams {
+                after,
+                ..ProxyParams::default()
+            };
+            buffer.query(&params, None)
+        };
+        let lines = query(None);
+        assert_eq!(
+            (seqs(&lines), lines.last, lines.truncated),
+            (vec![3, 4, 5, 6], 6, false)
+        );
+        let lines = query(Some(4));
+        assert_eq!((seqs(&lines), lines.truncated), (vec![5, 6], false));
+        let lines = query(Some(6));
+        assert_eq!((seqs(&lines), lines.truncated), (vec![], false));
+        // Lines 2 and earlier were dropped before they could be returned
+        let lines = query(Some(1));
+        assert_eq!((seqs(&lines), lines.truncated), (vec![3, 4, 5, 6], true));
+        // After more lines than the client has, it must have restarted
+        let lines = query(Some(100));
+        assert_eq!((seqs(&lines), lines.truncated), (vec![3, 4, 5, 6], false));
+    }
+
+    #[test]
+    fn query_limits_to_the_newest_lines() {
+        let buffer = buffer(&["a", "b", "c"]);
+        let query = |limit| {
+            let params = ProxyParams {
+                limit: Some(limit),
+                ..ProxyParams::default()
+            };
+            buffer.query(&params, None)
+        };
+        let lines = query(2);
+        assert_eq!((seqs(&lines), lines.truncated), (vec![2, 3], true));
+        let lines = query(3);
+        assert_eq!((seqs(&lines), lines.truncated), (vec![1, 2, 3], false));
+        let lines = query(usize::MAX);
+        assert_eq!((seqs(&lines), lines.truncated), (vec![1, 2, 3], false));
+    }
+
+    #[test]
+    fn query_greps_and_inverts() {
+        let buffer = buffer(&["GET /", "error: disk full", "GET /health", "ERROR again"]);
+        let grep = Regex::new("(?i)error").unwrap();
+        let mut params = ProxyParams::default();
+        assert_eq!(seqs(&buffer.query(&params, Some(&grep))), [2, 4]);
+        params.invert = true;
+        assert_eq!(seqs(&buffer.query(&params, Some(&grep))), [1, 3]);
+        params.file = Some("other.log".to_string());
+        assert!(buffer.query(&params, Some(&grep)).lines.is_empty());
+    }
+}
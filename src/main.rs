@@ -1,56 +1,123 @@
+mod alerts;
+mod backoff;
+mod checks;
 mod cleanup;
 mod cli;
+mod client_server;
 mod clients;
+mod collectors;
 mod config;
-mod cpu_monitor;
 mod db;
+mod events;
+mod fleet;
+mod line_protocol;
+mod liveness;
+mod log_metrics;
+mod logs;
+mod notifier;
+mod otlp;
+mod process_monitor;
+mod prometheus;
+mod protocol;
 mod proxy;
+mod query;
+mod registry;
+mod shutdown;
+mod statsd;
 mod utils;
 mod warp_server;
+mod watch;
 mod websocket_client;
 mod websocket_server;
 
 use clap::Parser;
 use cli::{Args, Commands};
+use config::Options;
+use once_cell::sync::Lazy;
+use shutdown::Shutdown;
 use std::error::Error;
-use std::sync::{
-    atomic::{AtomicBool, Ordering},
-    Arc,
-};
+use tokio_util::sync::CancellationToken;
+use websocket_server::Users;
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn Error>> {
     let args = Args::parse();
     dotenv::dotenv().ok();
-    let running = Arc::new(AtomicBool::new(true));
-    let running_ctrlc = running.clone();
-    let ctrlc_task = tokio::spawn(async move {
-        tokio::signal::ctrl_c()
-            .await
-            .expect("Failed to listen for Ctrl+C");
-        println!("Received Ctrl+C, initiating graceful shutdown...");
-        running_ctrlc.store(false, Ordering::SeqCst);
-    });
+    let config = Options::new();
+    let shutdown = Shutdown::new();
+    tokio::spawn(shutdown.clone().listen_for_signals());
 
     match &args.command {
         Some(Commands::Client {}) => {
             println!("Running the Client program");
             db::init()?;
-            let cpu_task = tokio::spawn(cpu_monitor::cpu_monitoring_loop(running.clone()));
-            let cleanup_task = tokio::spawn(cleanup::run(running.clone()));
-            let websocket_task =
-                tokio::spawn(websocket_client::connect_with_retry(running.clone()));
-            let _ = tokio::join!(cpu_task, cleanup_task, websocket_task, ctrlc_task);
+            for collector in collectors::build() {
+                shutdown.spawn(
+                    collector.name().to_string(),
+                    collectors::run(collector, shutdown.token()),
+                );
+            }
+            shutdown.spawn("process monitor", process_monitor::run(shutdown.token()));
+            shutdown.spawn("cleanup", cleanup::run(shutdown.token()));
+            if let Some(port) = config.client_status_port {
+                shutdown.spawn(
+                    "status server",
+                    client_server::run_server(port, shutdown.token()),
+                );
+            }
+            if !config.logs.files.is_empty() {
+                shutdown.spawn("logs", logs::run(shutdown.token()));
+            }
+            if let Some(port) = config.statsd.port {
+                shutdown.spawn("statsd", statsd::run(port, shutdown.token()));
+            }
+            let coordinator = shutdown.clone();
+            shutdown.spawn("websocket client", async move {
+                if let Err(e) = websocket_client::connect_with_retry(coordinator.token()).await {
+                    eprintln!("WebSocket client stopped: {e}");
+                    // Without a hub connection there's nothing left to do
+                    coordinator.trigger();
+                }
+            });
         }
         Some(Commands::Hub {}) => {
             println!("Running the Hub program");
-            let webserver_task = tokio::spawn(warp_server::run_server(running.clone()));
-            let _ = tokio::join!(webserver_task);
+            db::init()?;
+            // Open the registry up front so a misconfigured one fails at startup
+            Lazy::force(&registry::REGISTRY);
+            let users = Users::default();
+            // Cancelled once the web server has finished draining
+            let stopped = CancellationToken::new();
+            shutdown.spawn("registry", registry::run(stopped.clone()));
+            shutdown.spawn("alerts", alerts::run(shutdown.clone()));
+            // Expires the metrics the hub receives itself over OTLP
+            shutdown.spawn("cleanup", cleanup::run(shutdown.token()));
+            shutdown.spawn("liveness", liveness::run(users.clone(), shutdown.token()));
+            if !config.upstream_uris.is_empty() {
+                let (users, token) = (users.clone(), shutdown.token());
+                shutdown.spawn("upstream", async move {
+                    if let Err(e) = websocket_client::connect_upstream(users, token).await {
+                        eprintln!("Upstream connection stopped: {e}");
+                    }
+                });
+            }
+            let token = shutdown.token();
+            shutdown.spawn("web server", async move {
+                if let Err(e) = warp_server::run_server(users, token).await {
+                    eprintln!("Web server error: {e}");
+                }
+                stopped.cancel();
+            });
         }
         None => {
             println!("Invalid subcommand. See usage.");
+            return Ok(());
         }
     }
 
+    if !shutdown.drain(config.shutdown_drain).await {
+        std::process::exit(1);
+    }
+
     Ok(())
 }
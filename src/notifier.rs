@@ -0,0 +1,373 @@
+use crate::alerts::{Alert, AlertStatus, Notification};
+use crate::backoff::BackoffPolicy;
+use crate::config::{AlertChannel, ChannelKind, Options};
+use crate::db;
+use crate::utils;
+use serde_json::{json, Value};
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use tokio::time::{sleep, Duration};
+use tokio_util::sync::CancellationToken;
+
+const REQUEST_TIMEOUT: u64 = 10;
+const MAX_RETRY_DELAY: u64 = 300;
+// Alertmanager uses the zero time for alerts that have not ended
+const ZERO_TIME: &str = "0001-01-01T00:00:00Z";
+
+fn webhook_payload(notification: &Notification) -> Value {
+    json!({
+        "group_key": notification.group_key,
+        "status": notification.status,
+        "group_labels": notification.group_labels,
+        "alerts": notification.alerts,
+    })
+}
+
+fn slack_payload(notification: &Notification) -> Value {
+    let firing = notification
+        .alerts
+        .iter()
+        .filter(|a| a.status == AlertStatus::Firing)
+        .count();
+    let title = notification
+        .group_labels
+        .values()
+        .cloned()
+        .collect::<Vec<String>>()
+        .join(" ");
+    let header = match notification.status {
+        AlertStatus::Firing => format!("*[FIRING:{firing}] {title}*"),
+        AlertStatus::Resolved => format!("*[RESOLVED] {title}*"),
+    };
+    let lines: Vec<String> = notification
+        .alerts
+        .iter()
+        .map(|a| {
+            format!(
+                "• {} ({}): {}",
+                a.fingerprint(),
+                a.status.as_str(),
+                a.summary
+            )
+        })
+        .collect();
+    let text = format!("{header}\n{}", lines.join("\n"));
+    json!({ "text": text })
+}
+
+/// Labels shared by every alert in the notification.
+fn common_labels(alerts: &[Alert]) -> BTreeMap<String, String> {
+    let mut iter = alerts.iter().map(Alert::all_labels);
+    let mut common = iter.next().unwrap_or_default();
+    for labels in iter {
+        common.retain(|k, v| labels.get(k) == Some(v));
+    }
+    common
+}
+
+// https://prometheus.io/docs/alerting/latest/configuration/#webhook_config
+fn alertmanager_payload(channel: &AlertChannel, notification: &Notification) -> Value {
+    let alerts: Vec<Value> = notification
+        .alerts
+        .iter()
+        .map(|a| {
+            json!({
+                "status": a.status,
+                "labels": a.all_labels(),
+                "annotations": { "summary": a.summary },
+                "startsAt": utils::to_rfc3339(a.starts_at),
+                "endsAt": a.ends_at.map_or_else(|| ZERO_TIME.to_string(), utils::to_rfc3339),
+                "generatorURL": "",
+                "fingerprint": a.fingerprint(),
+            })
+        })
+        .collect();
+    json!({
+        "version": "4",
+        "groupKey": notification.group_key,
+        "truncatedAlerts": 0,
+        "status": notification.status,
+        "receiver": channel.name,
+        "groupLabels": notification.group_labels,
+        "commonLabels": common_labels(&notification.alerts),
+        "commonAnnotations": {},
+        "externalURL": "",
+        "alerts": alerts,
+    })
+}
+
+fn payload(channel: &AlertChannel, notification: &Notification) -> Value {
+    match channel.kind {
+        ChannelKind::Webhook => webhook_payload(notification),
+        ChannelKind::Slack => slack_payload(notification),
+        ChannelKind::Alertmanager => alertmanager_payload(channel, notification),
+    }
+}
+
+async fn post(client: &reqwest::Client, url: &str, body: &Value) -> Result<String, String> {
+    let res = client
+        .post(url)
+        .json(body)
+        .timeout(Duration::from_secs(REQUEST_TIMEOUT))
+        .send()
+        .await
+        .map_err(|e| e.to_string())?;
+    let status = res.status();
+    if status.is_success() {
+        Ok(status.to_string())
+    } else {
+        Err(status.to_string())
+    }
+}
+
+/// Posts a notification to a channel, retrying with exponential backoff.
+/// Every attempt is recorded in the delivery log.
+pub async fn deliver(
+    channel: AlertChannel,
+    notification: Arc<Notification>,
+    shutdown: CancellationToken,
+) {
+    let config = Options::new();
+    let policy = BackoffPolicy {
+        base: config.alerts.retry_delay,
+        max: Duration::from_secs(MAX_RETRY_DELAY),
+        jitter: false,
+        max_retries: Some(config.alerts.max_attempts),
+    };
+    let record = |delivery: db::Delivery| {
+        if let Err(e) = db::insert_delivery(&delivery) {
+            eprintln!("Error recording delivery: {e}");
+        }
+    };
+    if !send(&channel, &notification, &policy, &shutdown, record).await {
+        eprintln!(
+            "Giving up on delivery to {} for group {}",
+            channel.name, notification.group_key
+        );
+    }
+}
+
+/// Posts a notification until the channel accepts it, the policy runs out of
+/// retries or shutdown begins, passing every attempt to `record`. Returns
+/// whether the notification was delivered.
+async fn send(
+    channel: &AlertChannel,
+    notification: &Notification,
+    policy: &BackoffPolicy,
+    shutdown: &CancellationToken,
+    mut record: impl FnMut(db::Delivery),
+) -> bool {
+    let client = reqwest::Client::new();
+    let body = payload(channel, notification);
+    let attempts = policy.max_retries.unwrap_or(u32::MAX);
+
+    for attempt in 1..=attempts {
+        let result = post(&client, &channel.url, &body).await;
+        record(db::Delivery {
+            timestamp: utils::unix_timestamp(),
+            channel: channel.name.clone(),
+            group_key: notification.group_key.clone(),
+            status: notification.status.as_str().to_string(),
+            attempt,
+            success: result.is_ok(),
+            detail: match &result {
+                Ok(detail) | Err(detail) => detail.clone(),
+            },
+        });
+        match result {
+            Ok(_) => return true,
+            Err(e) => eprintln!(
+                "Delivery to {} failed (attempt {attempt}/{attempts}): {e}",
+                channel.name
+            ),
+        }
+        if !policy.exhausted(attempt) {
+            let delay = policy.delay(attempt, &mut rand::thread_rng());
+            tokio::select! {
+                () = sleep(delay) => {}
+                // A retry isn't worth holding up shutdown for
+                () = shutdown.cancelled() => return false,
+            }
+        }
+    }
+    false
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::VecDeque;
+    use std::sync::Mutex;
+    use warp::http::StatusCode;
+    use warp::Filter;
+
+    type Bodies = Arc<Mutex<Vec<Value>>>;
+
+    /// A local stand-in for a channel. It answers with the given statuses in
+    /// turn, then with 200, and keeps every body posted to it.
+    fn stand_in(statuses: &[u16]) -> (String, Bodies) {
+        let bodies = Bodies::default();
+        let received = bodies.clone();
+        let statuses = Arc::new(Mutex::new(VecDeque::from(statuses.to_vec())));
+        let route = warp::post()
+            .and(warp::body::json())
+            .map(move |body: Value| {
+                received.lock().unwrap().push(body);
+                let status = statuses.lock().unwrap().pop_front().unwrap_or(200);
+                warp::reply::with_status("", StatusCode::from_u16(status).unwrap())
+            });
+        let (addr, server) = warp::serve(route).bind_ephemeral(([127, 0, 0, 1], 0));
+        tokio::spawn(server);
+        (format!("http://{addr}/hook"), bodies)
+    }
+
+    fn alert(client: &str, status: AlertStatus, ends_at: Option<i64>) -> Alert {
+        Alert {
+            name: "HighCpu".to_string(),
+            labels: BTreeMap::from([("client".to_string(), client.to_string())]),
+            summary: format!("CPU on {client} above 90%"),
+            status,
+            starts_at: 1_700_000_000,
+            ends_at,
+        }
+    }
+
+    fn notification() -> Notification {
+        Notification {
+            group_key: "{alertname=HighCpu}".to_string(),
+            group_labels: BTreeMap::from([("alertname".to_string(), "HighCpu".to_string())]),
+            status: AlertStatus::Firing,
+            alerts: vec![
+                alert("web-1", AlertStatus::Firing, None),
+                alert("web-2", AlertStatus::Resolved, Some(1_700_000_600)),
+            ],
+        }
+    }
+
+    fn policy(max_retries: u32, base: Duration) -> BackoffPolicy {
+        BackoffPolicy {
+            base,
+            max: base,
+            jitter: false,
+            max_retries: Some(max_retries),
+        }
+    }
+
+    /// Delivers `notification()` to a stand-in answering with `statuses`,
+    /// returning whether it was delivered, the attempts and the bodies posted.
+    async fn deliver_to(
+        kind: ChannelKind,
+        statuses: &[u16],
+        policy: BackoffPolicy,
+        shutdown: &CancellationToken,
+    ) -> (bool, Vec<db::Delivery>, Vec<Value>) {
+        let (url, bodies) = stand_in(statuses);
+        let channel = AlertChannel {
+            name: "oncall".to_string(),
+            kind,
+            url,
+        };
+        let mut deliveries = Vec::new();
+        let delivered = send(&channel, &notification(), &policy, shutdown, |d| {
+            deliveries.push(d);
+        })
+        .await;
+        let bodies = bodies.lock().unwrap().clone();
+        (delivered, deliveries, bodies)
+    }
+
+    async fn body_for(kind: ChannelKind) -> Value {
+        let shutdown = CancellationToken::new();
+        let policy = policy(1, Duration::from_millis(1));
+        let (delivered, _, mut bodies) = deliver_to(kind, &[], policy, &shutdown).await;
+        assert!(delivered);
+        assert_eq!(bodies.len(), 1);
+        bodies.remove(0)
+    }
+
+    #[tokio::test]
+    async fn webhook_posts_the_notification() {
+        let body = body_for(ChannelKind::Webhook).await;
+        assert_eq!(body["group_key"], "{alertname=HighCpu}");
+        assert_eq!(body["status"], "firing");
+        assert_eq!(body["group_labels"], json!({ "alertname": "HighCpu" }));
+        assert_eq!(body["alerts"][0]["labels"], json!({ "client": "web-1" }));
+        assert_eq!(body["alerts"][1]["status"], "resolved");
+        assert_eq!(body["alerts"][1]["ends_at"], 1_700_000_600);
+    }
+
+    #[tokio::test]
+    async fn slack_posts_a_line_per_alert() {
+        let body = body_for(ChannelKind::Slack).await;
+        assert_eq!(
+            body["text"],
+            "*[FIRING:1] HighCpu*\n\
+             • HighCpu{client=web-1} (firing): CPU on web-1 above 90%\n\
+             • HighCpu{client=web-2} (resolved): CPU on web-2 above 90%"
+        );
+    }
+
+    #[tokio::test]
+    async fn alertmanager_posts_a_version_4_payload() {
+        let body = body_for(ChannelKind::Alertmanager).await;
+        assert_eq!(body["version"], "4");
+        assert_eq!(body["receiver"], "oncall");
+        assert_eq!(body["status"], "firing");
+        assert_eq!(body["groupLabels"], json!({ "alertname": "HighCpu" }));
+        assert_eq!(body["commonLabels"], json!({ "alertname": "HighCpu" }));
+        let alerts = body["alerts"].as_array().unwrap();
+        assert_eq!(alerts.len(), 2);
+        assert_eq!(
+            alerts[0]["labels"],
+            json!({ "alertname": "HighCpu", "client": "web-1" })
+        );
+        assert_eq!(
+            alerts[0]["annotations"]["summary"],
+            "CPU on web-1 above 90%"
+        );
+        assert_eq!(alerts[0]["startsAt"], "2023-11-14T22:13:20Z");
+        assert_eq!(alerts[0]["endsAt"], ZERO_TIME);
+        assert_eq!(alerts[1]["endsAt"], "2023-11-14T22:23:20Z");
+        assert_eq!(alerts[1]["fingerprint"], "HighCpu{client=web-2}");
+    }
+
+    #[tokio::test]
+    async fn failed_deliveries_are_retried() {
+        let shutdown = CancellationToken::new();
+        let policy = policy(5, Duration::from_millis(1));
+        let (delivered, deliveries, bodies) =
+            deliver_to(ChannelKind::Webhook, &[500, 503], policy, &shutdown).await;
+        assert!(delivered);
+        assert_eq!(bodies.len(), 3);
+        let attempts: Vec<(u32, bool)> =
+            deliveries.iter().map(|d| (d.attempt, d.success)).collect();
+        assert_eq!(attempts, [(1, false), (2, false), (3, true)]);
+        assert_eq!(deliveries[0].detail, "500 Internal Server Error");
+        assert_eq!(deliveries[1].detail, "503 Service Unavailable");
+        assert_eq!(deliveries[2].channel, "oncall");
+        assert_eq!(deliveries[2].status, "firing");
+    }
+
+    #[tokio::test]
+    async fn delivery_gives_up_after_the_last_attempt() {
+        let shutdown = CancellationToken::new();
+        let policy = policy(3, Duration::from_millis(1));
+        let (delivered, deliveries, bodies) =
+            deliver_to(ChannelKind::Webhook, &[500; 5], policy, &shutdown).await;
+        assert!(!delivered);
+        assert_eq!(bodies.len(), 3);
+        assert!(deliveries.iter().all(|d| !d.success));
+    }
+
+    #[tokio::test]
+    async fn shutdown_stops_retries() {
+        let shutdown = CancellationToken::new();
+        shutdown.cancel();
+        // Without shutdown the retry would wait an hour
+        let policy = policy(3, Duration::from_secs(3600));
+        let (delivered, deliveries, _) =
+            deliver_to(ChannelKind::Webhook, &[500], policy, &shutdown).await;
+        assert!(!delivered);
+        assert_eq!(deliveries.len(), 1);
+    }
+}
@@ -0,0 +1,865 @@
+use crate::db::{insert_samples, DatabaseError, Sample};
+use crate::line_protocol::metric_name;
+use crate::utils;
+use flate2::read::GzDecoder;
+use once_cell::sync::Lazy;
+use prost::Message;
+use serde::{Deserialize, Deserializer, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::io::Read;
+use std::str::FromStr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use warp::http::StatusCode;
+use warp::hyper::body::Bytes;
+
+/// The largest request accepted, in bytes, before and after decompression.
+pub const BODY_LIMIT: u64 = 16 * 1024 * 1024;
+
+// AggregationTemporality: delta points only count what happened since the previous point
+const DELTA: i32 = 1;
+// DataPointFlags: the point marks a gap rather than carrying a value
+const NO_RECORDED_VALUE: u32 = 1;
+
+type Labels = BTreeMap<String, String>;
+
+// The subset of the OTLP metrics messages the receiver reads, from
+// opentelemetry/proto/collector/metrics/v1 and opentelemetry/proto/metrics/v1.
+// Fields of a `oneof` are declared as optional fields with the same tags, which
+// is the same on the wire, and fields the receiver doesn't use are left out, as
+// protobuf skips unknown fields. The same structs read OTLP/JSON, where 64-bit
+// integers may be sent as strings.
+
+#[derive(Clone, PartialEq, Message, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ExportMetricsServiceRequest {
+    #[prost(message, repeated, tag = "1")]
+    #[serde(default)]
+    resource_metrics: Vec<ResourceMetrics>,
+}
+
+#[derive(Clone, PartialEq, Message, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ResourceMetrics {
+    #[prost(message, optional, tag = "1")]
+    #[serde(default)]
+    resource: Option<Resource>,
+    #[prost(message, repeated, tag = "2")]
+    #[serde(default)]
+    scope_metrics: Vec<ScopeMetrics>,
+}
+
+#[derive(Clone, PartialEq, Message, Deserialize)]
+struct Resource {
+    #[prost(message, repeated, tag = "1")]
+    #[serde(default)]
+    attributes: Vec<KeyValue>,
+}
+
+#[derive(Clone, PartialEq, Message, Deserialize)]
+struct ScopeMetrics {
+    #[prost(message, repeated, tag = "2")]
+    #[serde(default)]
+    metrics: Vec<Metric>,
+}
+
+#[derive(Clone, PartialEq, Message, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Metric {
+    #[prost(string, tag = "1")]
+    #[serde(default)]
+    name: String,
+    #[prost(message, optional, tag = "5")]
+    #[serde(default)]
+    gauge: Option<Gauge>,
+    #[prost(message, optional, tag = "7")]
+    #[serde(default)]
+    sum: Option<Sum>,
+    #[prost(message, optional, tag = "9")]
+    #[serde(default)]
+    histogram: Option<Histogram>,
+    #[prost(message, optional, tag = "10")]
+    #[serde(default)]
+    exponential_histogram: Option<Unsupported>,
+    #[prost(message, optional, tag = "11")]
+    #[serde(default)]
+    summary: Option<Unsupported>,
+}
+
+#[derive(Clone, PartialEq, Message, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Gauge {
+    #[prost(message, repeated, tag = "1")]
+    #[serde(default)]
+    data_points: Vec<NumberDataPoint>,
+}
+
+#[derive(Clone, PartialEq, Message, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Sum {
+    #[prost(message, repeated, tag = "1")]
+    #[serde(default)]
+    data_points: Vec<NumberDataPoint>,
+    #[prost(int32, tag = "2")]
+    #[serde(default)]
+    aggregation_temporality: i32,
+    #[prost(bool, tag = "3")]
+    #[serde(default)]
+    is_monotonic: bool,
+}
+
+#[derive(Clone, PartialEq, Message, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Histogram {
+    #[prost(message, repeated, tag = "1")]
+    #[serde(default)]
+    data_points: Vec<HistogramDataPoint>,
+    #[prost(int32, tag = "2")]
+    #[serde(default)]
+    aggregation_temporality: i32,
+}
+
+/// Exponential histograms and summaries, which are only counted so they can be
+/// reported as rejected.
+#[derive(Clone, PartialEq, Message, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Unsupported {
+    #[prost(message, repeated, tag = "1")]
+    #[serde(default)]
+    data_points: Vec<Ignored>,
+}
+
+#[derive(Clone, PartialEq, Message, Deserialize)]
+struct Ignored {}
+
+#[derive(Clone, PartialEq, Message, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct NumberDataPoint {
+    #[prost(message, repeated, tag = "7")]
+    #[serde(default)]
+    attributes: Vec<KeyValue>,
+    #[prost(fixed64, tag = "3")]
+    #[serde(default, deserialize_with = "int")]
+    time_unix_nano: u64,
+    #[prost(double, optional, tag = "4")]
+    #[serde(default)]
+    as_double: Option<f64>,
+    #[prost(sfixed64, optional, tag = "6")]
+    #[serde(default, deserialize_with = "optional_int")]
+    as_int: Option<i64>,
+    #[prost(uint32, tag = "8")]
+    #[serde(default)]
+    flags: u32,
+}
+
+#[derive(Clone, PartialEq, Message, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct HistogramDataPoint {
+    #[prost(message, repeated, tag = "9")]
+    #[serde(default)]
+    attributes: Vec<KeyValue>,
+    #[prost(fixed64, tag = "3")]
+    #[serde(default, deserialize_with = "int")]
+    time_unix_nano: u64,
+    #[prost(fixed64, tag = "4")]
+    #[serde(default, deserialize_with = "int")]
+    count: u64,
+    #[prost(double, optional, tag = "5")]
+    #[serde(default)]
+    sum: Option<f64>,
+    #[prost(fixed64, repeated, tag = "6")]
+    #[serde(default, deserialize_with = "int_list")]
+    bucket_counts: Vec<u64>,
+    #[prost(double, repeated, tag = "7")]
+    #[serde(default)]
+    explicit_bounds: Vec<f64>,
+    #[prost(uint32, tag = "10")]
+    #[serde(default)]
+    flags: u32,
+}
+
+#[derive(Clone, PartialEq, Message, Deserialize)]
+struct KeyValue {
+    #[prost(string, tag = "1")]
+    #[serde(default)]
+    key: String,
+    #[prost(message, optional, tag = "2")]
+    #[serde(default)]
+    value: Option<AnyValue>,
+}
+
+/// Attribute values; arrays, maps and bytes aren't used as labels.
+#[derive(Clone, PartialEq, Message, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct AnyValue {
+    #[prost(string, optional, tag = "1")]
+    #[serde(default)]
+    string_value: Option<String>,
+    #[prost(bool, optional, tag = "2")]
+    #[serde(default)]
+    bool_value: Option<bool>,
+    #[prost(int64, optional, tag = "3")]
+    #[serde(default, deserialize_with = "optional_int")]
+    int_value: Option<i64>,
+    #[prost(double, optional, tag = "4")]
+    #[serde(default)]
+    double_value: Option<f64>,
+}
+
+#[derive(Clone, PartialEq, Message, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct ExportMetricsServiceResponse {
+    #[prost(message, optional, tag = "1")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    partial_success: Option<ExportMetricsPartialSuccess>,
+}
+
+#[derive(Clone, PartialEq, Message, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct ExportMetricsPartialSuccess {
+    #[prost(int64, tag = "1")]
+    rejected_data_points: i64,
+    #[prost(string, tag = "2")]
+    error_message: String,
+}
+
+/// The `google.rpc.Status` OTLP answers failed requests with.
+#[derive(Clone, PartialEq, Message, Serialize)]
+struct Status {
+    #[prost(int32, tag = "1")]
+    code: i32,
+    #[prost(string, tag = "2")]
+    message: String,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum JsonInt<T> {
+    Number(T),
+    String(String),
+}
+
+impl<T: FromStr> JsonInt<T> {
+    fn value<E: serde::de::Error>(self) -> Result<T, E> {
+        match self {
+            JsonInt::Number(n) => Ok(n),
+            JsonInt::String(s) => s
+                .parse()
+                .map_err(|_| E::custom(format!("invalid integer \"{s}\""))),
+        }
+    }
+}
+
+fn int<'de, D: Deserializer<'de>, T: Deserialize<'de> + FromStr>(d: D) -> Result<T, D::Error> {
+    JsonInt::deserialize(d)?.value()
+}
+
+fn optional_int<'de, D: Deserializer<'de>>(d: D) -> Result<Option<i64>, D::Error> {
+    Option::<JsonInt<i64>>::deserialize(d)?
+        .map(JsonInt::value)
+        .transpose()
+}
+
+fn int_list<'de, D: Deserializer<'de>>(d: D) -> Result<Vec<u64>, D::Error> {
+    Vec::<JsonInt<u64>>::deserialize(d)?
+        .into_iter()
+        .map(JsonInt::value)
+        .collect()
+}
+
+// Running totals of delta sums and histograms, which are stored as cumulative
+// counters so they can be read with `rate` like any other. They are kept in
+// memory, so each series' deltas need to reach the same hub instance.
+static TOTALS: Lazy<Mutex<HashMap<(String, Labels), Total>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+// Totals that haven't had a delta for this long are dropped, so a series that
+// is sent again afterwards starts over from zero, which `rate` reads as a reset
+const TOTAL_IDLE: Duration = Duration::from_secs(60 * 60);
+
+struct Total {
+    value: f64,
+    updated: Instant,
+}
+
+fn labels(resource: &Labels, attributes: &[KeyValue]) -> Labels {
+    let mut labels = resource.clone();
+    for attribute in attributes {
+        let Some(value) = &attribute.value else {
+            continue;
+        };
+        let value = if let Some(s) = &value.string_value {
+            s.clone()
+        } else if let Some(b) = value.bool_value {
+            b.to_string()
+        } else if let Some(i) = value.int_value {
+            i.to_string()
+        } else if let Some(d) = value.double_value {
+            d.to_string()
+        } else {
+            continue;
+        };
+        labels.insert(metric_name(&attribute.key), value);
+    }
+    labels
+}
+
+/// Turns the metrics of a request into samples, keyed by the second they were taken.
+#[derive(Default)]
+struct Converter {
+    samples: BTreeMap<i64, Vec<Sample>>,
+    accepted: usize,
+    rejected: i64,
+    unsupported: Vec<String>,
+}
+
+impl Converter {
+    fn push(&mut self, time_unix_nano: u64, metric: String, labels: Labels, value: f64) {
+        let timestamp = match time_unix_nano / 1_000_000_000 {
+            0 => utils::unix_timestamp(),
+            seconds => i64::try_from(seconds).unwrap_or(i64::MAX),
+        };
+        self.samples.entry(timestamp).or_default().push(Sample {
+            metric,
+            labels,
+            value,
+        });
+    }
+
+    fn number(&mut self, point: &NumberDataPoint, name: &str, labels: Labels, delta: bool) {
+        let value = point.as_double.or(point.as_int.map(|i| i as f64));
+        let recorded = |v: &f64| v.is_finite() && point.flags & NO_RECORDED_VALUE == 0;
+        let Some(mut value) = value.filter(recorded) else {
+            self.rejected += 1;
+            return;
+        };
+        if delta {
+            value = accumulate(name, &labels, value);
+        }
+        self.accepted += 1;
+        self.push(point.time_unix_nano, name.to_string(), labels, value);
+    }
+
+    /// Records a histogram the way Prometheus does, as `_bucket` counters
+    /// labelled with each bucket's upper bound, `le`, and `_count` and `_sum`.
+    fn histogram(&mut self, point: &HistogramDataPoint, name: &str, labels: Labels, delta: bool) {
+        if point.flags & NO_RECORDED_VALUE != 0
+            || point.bucket_counts.len() > point.explicit_bounds.len() + 1
+        {
+            self.rejected += 1;
+            return;
+        }
+        let mut values = Vec::new();
+        let mut cumulative = 0;
+        for (i, count) in point.bucket_counts.iter().enumerate() {
+            cumulative += count;
+            let le = point
+                .explicit_bounds
+                .get(i)
+                .map_or_else(|| "+Inf".to_string(), f64::to_string);
+            let mut labels = labels.clone();
+            labels.insert("le".to_string(), le);
+            values.push((format!("{name}_bucket"), labels, cumulative as f64));
+        }
+        values.push((format!("{name}_count"), labels.clone(), point.count as f64));
+        if let Some(sum) = point.sum.filter(|s| s.is_finite()) {
+            values.push((format!("{name}_sum"), labels, sum));
+        }
+        for (metric, labels, mut value) in values {
+            if delta {
+                value = accumulate(&metric, &labels, value);
+            }
+            self.push(point.time_unix_nano, metric, labels, value);
+        }
+        self.accepted += 1;
+    }
+
+    fn metric(&mut self, metric: &Metric, resource: &Labels) {
+        let name = metric_name(&metric.name);
+        if let Some(gauge) = &metric.gauge {
+            for point in &gauge.data_points {
+                self.number(point, &name, labels(resource, &point.attributes), false);
+            }
+        } else if let Some(sum) = &metric.sum {
+            // Monotonic sums are counters, which Prometheus names with `_total`
+            let name = if sum.is_monotonic && !name.ends_with("_total") {
+                format!("{name}_total")
+            } else {
+                name
+            };
+            let delta = sum.aggregation_temporality == DELTA;
+            for point in &sum.data_points {
+                self.number(point, &name, labels(resource, &point.attributes), delta);
+            }
+        } else if let Some(histogram) = &metric.histogram {
+            let delta = histogram.aggregation_temporality == DELTA;
+            for point in &histogram.data_points {
+                self.histogram(point, &name, labels(resource, &point.attributes), delta);
+            }
+        } else {
+            let points = metric
+                .exponential_histogram
+                .as_ref()
+                .or(metric.summary.as_ref())
+                .map_or(0, |m| m.data_points.len());
+            self.rejected += i64::try_from(points).unwrap_or(i64::MAX);
+            self.unsupported.push(metric.name.clone());
+        }
+    }
+
+    fn request(&mut self, request: &ExportMetricsServiceRequest) {
+        for resource_metrics in &request.resource_metrics {
+            let resource = resource_metrics
+                .resource
+                .as_ref()
+                .map(|r| labels(&Labels::new(), &r.attributes))
+                .unwrap_or_default();
+            for scope in &resource_metrics.scope_metrics {
+                for metric in &scope.metrics {
+                    self.metric(metric, &resource);
+                }
+            }
+        }
+    }
+
+    /// What the response reports about the points that weren't stored, if any.
+    fn partial_success(&self) -> Option<ExportMetricsPartialSuccess> {
+        (self.rejected > 0).then(|| {
+            let mut error_message = format!("{} data point(s) rejected", self.rejected);
+            if !self.unsupported.is_empty() {
+                error_message.push_str(&format!(
+                    "; exponential histograms and summaries aren't supported: {}",
+                    self.unsupported.join(", ")
+                ));
+            }
+            ExportMetricsPartialSuccess {
+                rejected_data_points: self.rejected,
+                error_message,
+            }
+        })
+    }
+}
+
+fn accumulate(metric: &str, labels: &Labels, delta: f64) -> f64 {
+    let mut totals = TOTALS.lock().unwrap();
+    let total = totals
+        .entry((metric.to_string(), labels.clone()))
+        .or_insert(Total {
+            value: 0.0,
+            updated: Instant::now(),
+        });
+    total.value += delta;
+    total.updated = Instant::now();
+    total.value
+}
+
+fn expire_totals() {
+    TOTALS
+        .lock()
+        .unwrap()
+        .retain(|_, total| total.updated.elapsed() < TOTAL_IDLE);
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Format {
+    Protobuf,
+    Json,
+}
+
+fn reply<T: Message + Serialize>(
+    format: Format,
+    code: StatusCode,
+    message: &T,
+) -> Box<dyn warp::Reply> {
+    let (body, content_type) = match format {
+        Format::Protobuf => (message.encode_to_vec(), "application/x-protobuf"),
+        Format::Json => (
+            serde_json::to_vec(message).unwrap_or_default(),
+            "application/json",
+        ),
+    };
+    Box::new(warp::reply::with_status(
+        warp::reply::with_header(body, "content-type", content_type),
+        code,
+    ))
+}
+
+fn failure(format: Format, code: StatusCode, message: String) -> Box<dyn warp::Reply> {
+    // gRPC's INVALID_ARGUMENT, as the OTLP specification asks for bad requests
+    reply(format, code, &Status { code: 3, message })
+}
+
+fn decompress(body: &[u8], encoding: Option<&str>) -> Result<Vec<u8>, String> {
+    match encoding {
+        None | Some("identity") => Ok(body.to_vec()),
+        Some("gzip") => {
+            let mut out = Vec::new();
+            GzDecoder::new(body)
+                .take(BODY_LIMIT + 1)
+                .read_to_end(&mut out)
+                .map_err(|e| format!("invalid gzip body: {e}"))?;
+            if out.len() as u64 > BODY_LIMIT {
+                return Err("decompressed body is too large".to_string());
+            }
+            Ok(out)
+        }
+        Some(other) => Err(format!("unsupported content encoding \"{other}\"")),
+    }
+}
+
+fn decode(
+    format: Format,
+    encoding: Option<&str>,
+    body: &[u8],
+) -> Result<ExportMetricsServiceRequest, String> {
+    let body = decompress(body, encoding)?;
+    match format {
+        Format::Protobuf => ExportMetricsServiceRequest::decode(body.as_slice())
+            .map_err(|e| format!("invalid request: {e}")),
+        Format::Json => serde_json::from_slice(&body).map_err(|e| format!("invalid request: {e}")),
+    }
+}
+
+/// Receives metrics exported over OTLP/HTTP, in protobuf or JSON, and stores
+/// them on the hub.
+pub async fn handler(
+    content_type: Option<String>,
+    content_encoding: Option<String>,
+    body: Bytes,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let content_type = content_type.unwrap_or_default();
+    let format = match content_type.split(';').next().unwrap_or_default().trim() {
+        "application/x-protobuf" | "application/protobuf" => Format::Protobuf,
+        "application/json" => Format::Json,
+        other => {
+            let message = format!("unsupported content type \"{other}\"");
+            return Ok(failure(
+                Format::Json,
+                StatusCode::UNSUPPORTED_MEDIA_TYPE,
+                message,
+            ));
+        }
+    };
+    let request = match decode(format, content_encoding.as_deref(), &body) {
+        Ok(request) => request,
+        Err(e) => return Ok(failure(format, StatusCode::BAD_REQUEST, e)),
+    };
+
+    expire_totals();
+    let mut converter = Converter::default();
+    converter.request(&request);
+    for (timestamp, samples) in &converter.samples {
+        insert_samples(*timestamp, samples).map_err(|e| {
+            eprintln!("Error storing OTLP metrics: {e}");
+            warp::reject::custom(DatabaseError)
+        })?;
+    }
+
+    let response = ExportMetricsServiceResponse {
+        partial_success: converter.partial_success(),
+    };
+    Ok(reply(format, StatusCode::OK, &response))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::write::GzEncoder;
+    use flate2::Compression;
+    use std::io::Write;
+
+    const TIME: u64 = 1_700_000_000_000_000_000;
+
+    fn attribute(key: &str, value: AnyValue) -> KeyValue {
+        KeyValue {
+            key: key.to_string(),
+            value: Some(value),
+        }
+    }
+
+    fn string(value: &str) -> AnyValue {
+        AnyValue {
+            string_value: Some(value.to_string()),
+            ..Default::default()
+        }
+    }
+
+    fn request(metrics: Vec<Metric>) -> ExportMetricsServiceRequest {
+        ExportMetricsServiceRequest {
+            resource_metrics: vec![ResourceMetrics {
+                resource: Some(Resource {
+                    attributes: vec![attribute("service.name", string("api"))],
+                }),
+                scope_metrics: vec![ScopeMetrics { metrics }],
+            }],
+        }
+    }
+
+    fn gauge(name: &str, data_points: Vec<NumberDataPoint>) -> Metric {
+        Metric {
+            name: name.to_string(),
+            gauge: Some(Gauge { data_points }),
+            ..Default::default()
+        }
+    }
+
+    fn double(value: f64) -> NumberDataPoint {
+        NumberDataPoint {
+            time_unix_nano: TIME,
+            as_double: Some(value),
+            ..Default::default()
+        }
+    }
+
+    type Flat = (i64, String, Vec<(String, String)>, f64);
+
+    /// The converted samples as `(timestamp, metric, labels, value)`.
+    fn convert(request: &ExportMetricsServiceRequest) -> (Converter, Vec<Flat>) {
+        let mut converter = Converter::default();
+        converter.request(request);
+        let samples = converter
+            .samples
+            .iter()
+            .flat_map(|(timestamp, samples)| {
+                samples.iter().map(|s| {
+                    let labels = s.labels.clone().into_iter().collect();
+                    (*timestamp, s.metric.clone(), labels, s.value)
+                })
+            })
+            .collect();
+        (converter, samples)
+    }
+
+    fn flat(timestamp: i64, metric: &str, labels: &[(&str, &str)], value: f64) -> Flat {
+        let labels = labels
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect();
+        (timestamp, metric.to_string(), labels, value)
+    }
+
+    #[test]
+    fn protobuf_requests_become_samples() {
+        let code = AnyValue {
+            int_value: Some(200),
+            ..Default::default()
+        };
+        let requests = Metric {
+            name: "http.requests".to_string(),
+            sum: Some(Sum {
+                data_points: vec![NumberDataPoint {
+                    attributes: vec![attribute("code", code)],
+                    time_unix_nano: TIME + 1_500_000_000,
+                    as_int: Some(7),
+                    ..Default::default()
+                }],
+                aggregation_temporality: 2,
+                is_monotonic: true,
+            }),
+            ..Default::default()
+        };
+        let mut usage = double(0.5);
+        usage.attributes = vec![attribute("host", string("a"))];
+        let body = request(vec![gauge("cpu.usage", vec![usage]), requests]).encode_to_vec();
+
+        let (converter, samples) = convert(&decode(Format::Protobuf, None, &body).unwrap());
+        assert_eq!(
+            samples,
+            [
+                flat(
+                    1_700_000_000,
+                    "cpu_usage",
+                    &[("host", "a"), ("service_name", "api")],
+                    0.5
+                ),
+                flat(
+                    1_700_000_001,
+                    "http_requests_total",
+                    &[("code", "200"), ("service_name", "api")],
+                    7.0
+                ),
+            ]
+        );
+        assert_eq!(converter.partial_success(), None);
+        assert!(decode(Format::Protobuf, None, b"\xff\xff").is_err());
+    }
+
+    #[test]
+    fn oneof_values_decode_from_their_own_tags() {
+        // as_double is field 4 and as_int field 6 of the NumberDataPoint oneof,
+        // both 64-bit fixed width on the wire
+        let mut bytes = vec![0x21];
+        bytes.extend(2.5f64.to_le_bytes());
+        let point = NumberDataPoint::decode(bytes.as_slice()).unwrap();
+        assert_eq!((point.as_double, point.as_int), (Some(2.5), None));
+
+        let mut bytes = vec![0x31];
+        bytes.extend((-5i64).to_le_bytes());
+        let point = NumberDataPoint::decode(bytes.as_slice()).unwrap();
+        assert_eq!((point.as_double, point.as_int), (None, Some(-5)));
+    }
+
+    #[test]
+    fn json_requests_take_integers_as_strings_or_numbers() {
+        let body = r#"{"resourceMetrics": [{"scopeMetrics": [{"metrics": [
+            {"name": "queue", "gauge": {"dataPoints": [
+                {"timeUnixNano": "1700000000000000000", "asInt": "3",
+                 "attributes": [{"key": "on", "value": {"boolValue": true}}]},
+                {"timeUnixNano": 1700000001000000000, "asInt": 4}
+            ]}},
+            {"name": "latency", "histogram": {"aggregationTemporality": 2, "dataPoints": [
+                {"timeUnixNano": "1700000000000000000", "count": "6", "sum": 2.5,
+                 "bucketCounts": ["1", 2, "3"], "explicitBounds": [0.1, 1]}
+            ]}}
+        ]}]}]}"#;
+        let (_, samples) = convert(&decode(Format::Json, None, body.as_bytes()).unwrap());
+        let t = 1_700_000_000;
+        assert_eq!(
+            samples,
+            [
+                flat(t, "queue", &[("on", "true")], 3.0),
+                flat(t, "latency_bucket", &[("le", "0.1")], 1.0),
+                flat(t, "latency_bucket", &[("le", "1")], 3.0),
+                flat(t, "latency_bucket", &[("le", "+Inf")], 6.0),
+                flat(t, "latency_count", &[], 6.0),
+                flat(t, "latency_sum", &[], 2.5),
+                flat(t + 1, "queue", &[], 4.0),
+            ]
+        );
+
+        let bad = r#"{"resourceMetrics": [{"scopeMetrics": [{"metrics": [
+            {"name": "queue", "gauge": {"dataPoints": [{"asInt": "three"}]}}
+        ]}]}]}"#;
+        let e = decode(Format::Json, None, bad.as_bytes()).unwrap_err();
+        assert!(e.contains("invalid integer \"three\""), "{e}");
+    }
+
+    #[test]
+    fn delta_points_are_accumulated() {
+        let delta = |value: i64, bucket_counts: Vec<u64>| {
+            request(vec![
+                Metric {
+                    name: "delta.test.jobs".to_string(),
+                    sum: Some(Sum {
+                        data_points: vec![NumberDataPoint {
+                            time_unix_nano: TIME,
+                            as_int: Some(value),
+                            ..Default::default()
+                        }],
+                        aggregation_temporality: DELTA,
+                        is_monotonic: true,
+                    }),
+                    ..Default::default()
+                },
+                Metric {
+                    name: "delta.test.size".to_string(),
+                    histogram: Some(Histogram {
+                        data_points: vec![HistogramDataPoint {
+                            time_unix_nano: TIME,
+                            count: bucket_counts.iter().sum(),
+                            bucket_counts,
+                            explicit_bounds: vec![10.0],
+                            ..Default::default()
+                        }],
+                        aggregation_temporality: DELTA,
+                    }),
+                    ..Default::default()
+                },
+            ])
+        };
+        let values = |request| {
+            let (_, samples) = convert(&request);
+            samples
+                .into_iter()
+                .map(|(.., value)| value)
+                .collect::<Vec<_>>()
+        };
+        // jobs, then the size buckets le=10 and +Inf, then its count
+        assert_eq!(values(delta(2, vec![1, 1])), [2.0, 1.0, 2.0, 2.0]);
+        assert_eq!(values(delta(3, vec![0, 4])), [5.0, 1.0, 6.0, 6.0]);
+    }
+
+    #[test]
+    fn points_without_values_are_rejected() {
+        let unrecorded = NumberDataPoint {
+            flags: NO_RECORDED_VALUE,
+            ..double(1.0)
+        };
+        let empty = NumberDataPoint {
+            time_unix_nano: TIME,
+            ..Default::default()
+        };
+        let points = vec![
+            unrecorded,
+            double(f64::NAN),
+            double(f64::INFINITY),
+            empty,
+            double(1.0),
+        ];
+        let histogram = Metric {
+            name: "latency".to_string(),
+            histogram: Some(Histogram {
+                data_points: vec![HistogramDataPoint {
+                    time_unix_nano: TIME,
+                    flags: NO_RECORDED_VALUE,
+                    ..Default::default()
+                }],
+                aggregation_temporality: 2,
+            }),
+            ..Default::default()
+        };
+        let unsupported = |points: usize| Unsupported {
+            data_points: vec![Ignored {}; points],
+        };
+        let summary = Metric {
+            name: "rpc.summary".to_string(),
+            summary: Some(unsupported(2)),
+            ..Default::default()
+        };
+        let exponential = Metric {
+            name: "rpc.exponential".to_string(),
+            exponential_histogram: Some(unsupported(1)),
+            ..Default::default()
+        };
+        let (converter, samples) = convert(&request(vec![
+            gauge("queue", points),
+            histogram,
+            summary,
+            exponential,
+        ]));
+        assert_eq!(
+            samples,
+            [flat(
+                1_700_000_000,
+                "queue",
+                &[("service_name", "api")],
+                1.0
+            )]
+        );
+        assert_eq!(converter.accepted, 1);
+        assert_eq!(
+            converter.partial_success(),
+            Some(ExportMetricsPartialSuccess {
+                rejected_data_points: 8,
+                error_message: "8 data point(s) rejected; exponential histograms and \
+                                summaries aren't supported: rpc.summary, rpc.exponential"
+                    .to_string(),
+            })
+        );
+    }
+
+    #[test]
+    fn gzip_bodies_are_decompressed() {
+        let body = request(vec![gauge("queue", vec![double(2.0)])]).encode_to_vec();
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(&body).unwrap();
+        let gzipped = encoder.finish().unwrap();
+
+        let (_, samples) = convert(&decode(Format::Protobuf, Some("gzip"), &gzipped).unwrap());
+        assert_eq!(samples.len(), 1);
+        let e = decode(Format::Protobuf, Some("gzip"), &body).unwrap_err();
+        assert!(e.starts_with("invalid gzip body"), "{e}");
+        let e = decode(Format::Protobuf, Some("br"), &body).unwrap_err();
+        assert_eq!(e, "unsupported content encoding \"br\"");
+    }
+}
@@ -0,0 +1,117 @@
+use crate::config::Options;
+use crate::db::{expire_process_snapshots, insert_process_snapshot};
+use crate::protocol::ProcessInfo;
+use crate::utils;
+use std::cmp::Reverse;
+use std::collections::BTreeMap;
+use sysinfo::{Process, ProcessRefreshKind, ProcessesToUpdate, System, UpdateKind, Users};
+use tokio::task;
+use tokio::time::{self, Duration};
+use tokio_util::sync::CancellationToken;
+
+fn refresh(sys: &mut System) {
+    let kind = ProcessRefreshKind::new()
+        .with_cpu()
+        .with_memory()
+        .with_cmd(UpdateKind::OnlyIfNotSet)
+        .with_user(UpdateKind::OnlyIfNotSet);
+    sys.refresh_processes_specifics(ProcessesToUpdate::All, true, kind);
+}
+
+/// The `top_n` processes using the most CPU, and the `top_n` using the most
+/// memory, busiest first.
+fn top_processes(sys: &System, users: &Users, top_n: usize) -> Vec<ProcessInfo> {
+    // Threads are listed alongside processes on Linux
+    let mut by_cpu: Vec<&Process> = sys
+        .processes()
+        .values()
+        .filter(|p| p.thread_kind().is_none())
+        .collect();
+    by_cpu.sort_by(|a, b| b.cpu_usage().total_cmp(&a.cpu_usage()));
+    let mut by_memory = by_cpu.clone();
+    by_memory.sort_by_key(|p| Reverse(p.memory()));
+
+    let chosen: BTreeMap<_, &Process> = by_cpu
+        .iter()
+        .take(top_n)
+        .chain(by_memory.iter().take(top_n))
+        .map(|p| (p.pid(), *p))
+        .collect();
+    let mut processes: Vec<ProcessInfo> = chosen
+        .into_values()
+        .map(|p| ProcessInfo {
+            pid: p.pid().as_u32(),
+            name: p.name().to_string_lossy().into_owned(),
+            command: p
+                .cmd()
+                .iter()
+                .map(|arg| arg.to_string_lossy())
+                .collect::<Vec<_>>()
+                .join(" "),
+            user: p
+                .user_id()
+                .and_then(|uid| users.get_user_by_id(uid))
+                .map(|user| user.name().to_string()),
+            cpu_usage: p.cpu_usage(),
+            memory: p.memory(),
+        })
+        .collect();
+    processes.sort_by(|a, b| b.cpu_usage.total_cmp(&a.cpu_usage));
+    processes
+}
+
+/// Records a snapshot of the busiest processes and expires the ones older than `retention`.
+fn snapshot(sys: &mut System, users: &mut Users, top_n: usize, retention: Duration) {
+    refresh(sys);
+    users.refresh_list();
+    let processes = top_processes(sys, users, top_n);
+    let timestamp = utils::unix_timestamp();
+    if let Err(e) = insert_process_snapshot(timestamp, &processes) {
+        eprintln!("Error inserting process snapshot: {e}");
+    }
+
+    let retention = i64::try_from(retention.as_secs()).unwrap_or(i64::MAX);
+    if let Err(e) = expire_process_snapshots(timestamp.saturating_sub(retention)) {
+        eprintln!("Error expiring process snapshots: {e}");
+    }
+}
+
+pub async fn run(shutdown: CancellationToken) {
+    let config = Options::new().processes;
+    let (top_n, retention) = (config.top_n, config.retention);
+    let mut interval = time::interval(config.interval);
+
+    // Process CPU usage is measured between refreshes, so the first one only sets a baseline.
+    // Refreshing reads every process's stats, so it runs off the async workers.
+    let baseline = task::spawn_blocking(|| {
+        let mut sys = System::new();
+        refresh(&mut sys);
+        (sys, Users::new_with_refreshed_list())
+    });
+    let Ok((mut sys, mut users)) = baseline.await else {
+        eprintln!("Process monitor stopped");
+        return;
+    };
+    interval.tick().await;
+
+    loop {
+        // Wait for the next interval or until interrupted
+        tokio::select! {
+            _ = interval.tick() => {},
+            () = shutdown.cancelled() => break,
+        }
+
+        let round = task::spawn_blocking(move || {
+            snapshot(&mut sys, &mut users, top_n, retention);
+            (sys, users)
+        })
+        .await;
+        let Ok(returned) = round else {
+            eprintln!("Process monitor stopped");
+            return;
+        };
+        (sys, users) = returned;
+    }
+
+    println!("Process monitoring loop exiting");
+}
@@ -0,0 +1,222 @@
+use crate::query::{self, QueryError, QueryResult};
+use crate::websocket_server::Users;
+use serde::Serialize;
+use std::collections::{BTreeMap, BTreeSet};
+use warp::http::StatusCode;
+use warp::reply::{Json, WithStatus};
+
+/// Query parameters, from the query string of a GET or the form body of a POST.
+/// Kept as pairs since the series API repeats `match[]`.
+pub type Params = Vec<(String, String)>;
+
+/// The largest form body accepted, in bytes.
+pub const PARAMS_LIMIT: u64 = 1024 * 1024;
+
+pub fn parse_params(raw: &[u8]) -> Params {
+    url::form_urlencoded::parse(raw).into_owned().collect()
+}
+
+fn param<'a>(params: &'a Params, name: &str) -> Option<&'a str> {
+    params
+        .iter()
+        .find(|(k, _)| k == name)
+        .map(|(_, v)| v.as_str())
+}
+
+fn matchers(params: &Params) -> Vec<&str> {
+    params
+        .iter()
+        .filter(|(k, _)| k == "match[]")
+        .map(|(_, v)| v.as_str())
+        .collect()
+}
+
+#[derive(Serialize)]
+struct Success<T> {
+    status: &'static str,
+    data: T,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    warnings: Vec<String>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct Failure {
+    status: &'static str,
+    error_type: &'static str,
+    error: String,
+}
+
+#[derive(Serialize)]
+struct Sample {
+    metric: query::Labels,
+    value: (f64, String),
+}
+
+#[derive(Serialize)]
+struct Series {
+    metric: query::Labels,
+    values: Vec<(f64, String)>,
+}
+
+#[derive(Serialize)]
+#[serde(tag = "resultType", content = "result", rename_all = "lowercase")]
+enum Data {
+    Scalar((f64, String)),
+    Vector(Vec<Sample>),
+    Matrix(Vec<Series>),
+}
+
+/// Prometheus sends sample values as strings, so that NaN and infinities survive JSON.
+fn format_value(value: f64) -> String {
+    if value.is_nan() {
+        "NaN".to_string()
+    } else if value.is_infinite() {
+        if value > 0.0 { "+Inf" } else { "-Inf" }.to_string()
+    } else {
+        value.to_string()
+    }
+}
+
+fn point((time, value): (f64, f64)) -> (f64, String) {
+    (time, format_value(value))
+}
+
+impl From<QueryResult> for Data {
+    fn from(result: QueryResult) -> Self {
+        match result {
+            QueryResult::Scalar(p) => Data::Scalar(point(p)),
+            QueryResult::Vector(samples) => Data::Vector(
+                samples
+                    .into_iter()
+                    .map(|s| Sample {
+                        metric: s.metric,
+                        value: point(s.value),
+                    })
+                    .collect(),
+            ),
+            QueryResult::Matrix(series) => Data::Matrix(
+                series
+                    .into_iter()
+                    .map(|s| Series {
+                        metric: s.metric,
+                        values: s.values.into_iter().map(point).collect(),
+                    })
+                    .collect(),
+            ),
+        }
+    }
+}
+
+fn success<T: Serialize>(data: T, warnings: Vec<String>) -> WithStatus<Json> {
+    let body = Success {
+        status: "success",
+        data,
+        warnings,
+    };
+    warp::reply::with_status(warp::reply::json(&body), StatusCode::OK)
+}
+
+/// Reports a failure the way Prometheus does, rather than through `handle_rejection`,
+/// since clients such as Grafana show the `error` field to the user.
+fn failure(rejection: &warp::Rejection) -> WithStatus<Json> {
+    let (code, error_type, error) = if let Some(QueryError(e)) = rejection.find() {
+        (StatusCode::BAD_REQUEST, "bad_data", e.clone())
+    } else {
+        eprintln!("Error evaluating query: {rejection:?}");
+        (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "internal",
+            "the query could not be evaluated".to_string(),
+        )
+    };
+    let body = Failure {
+        status: "error",
+        error_type,
+        error,
+    };
+    warp::reply::with_status(warp::reply::json(&body), code)
+}
+
+fn reply<T: Serialize>(result: Result<(T, Vec<String>), warp::Rejection>) -> WithStatus<Json> {
+    match result {
+        Ok((data, warnings)) => success(data, warnings),
+        Err(rejection) => failure(&rejection),
+    }
+}
+
+async fn instant(params: &Params, users: &Users) -> Result<(Data, Vec<String>), warp::Rejection> {
+    let time = query::parse_time("time", param(params, "time"))?;
+    let (result, warnings) = query::instant(param(params, "query"), time, users).await?;
+    Ok((result.into(), warnings))
+}
+
+async fn range(params: &Params, users: &Users) -> Result<(Data, Vec<String>), warp::Rejection> {
+    let start = query::parse_time("start", param(params, "start"))?;
+    let end = query::parse_time("end", param(params, "end"))?;
+    let step = query::parse_step(param(params, "step"))?;
+    let (result, warnings) = query::range(param(params, "query"), start, end, step, users).await?;
+    Ok((result.into(), warnings))
+}
+
+pub async fn query_handler(
+    params: Params,
+    users: Users,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    Ok(reply(instant(&params, &users).await))
+}
+
+pub async fn query_range_handler(
+    params: Params,
+    users: Users,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    Ok(reply(range(&params, &users).await))
+}
+
+pub async fn series_handler(
+    params: Params,
+    users: Users,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let selectors = matchers(&params);
+    if selectors.is_empty() {
+        return Ok(failure(&warp::reject::custom(QueryError(
+            "no match[] parameter provided".to_string(),
+        ))));
+    }
+    Ok(reply(
+        query::series(&selectors, &users)
+            .await
+            .map(|series| (series, Vec::new())),
+    ))
+}
+
+pub async fn labels_handler(
+    params: Params,
+    users: Users,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let result = query::series(&matchers(&params), &users)
+        .await
+        .map(|series| {
+            let names: BTreeSet<String> =
+                series.into_iter().flat_map(BTreeMap::into_keys).collect();
+            (names, Vec::new())
+        });
+    Ok(reply(result))
+}
+
+pub async fn label_values_handler(
+    name: String,
+    params: Params,
+    users: Users,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let result = query::series(&matchers(&params), &users)
+        .await
+        .map(|series| {
+            let values: BTreeSet<String> = series
+                .into_iter()
+                .filter_map(|mut labels| labels.remove(&name))
+                .collect();
+            (values, Vec::new())
+        });
+    Ok(reply(result))
+}
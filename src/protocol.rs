@@ -0,0 +1,273 @@
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+/// Messages a client sends to the hub over the websocket.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ClientEvent {
+    /// Sent once after connecting so the hub can recognise the client across reconnects.
+    Hello {
+        name: String,
+        #[serde(default)]
+        token: Option<String>,
+        /// Set when the client is a downstream hub rather than an agent
+        #[serde(default)]
+        hub: bool,
+        /// Used to select groups of clients, e.g. `role=web`
+        #[serde(default)]
+        labels: BTreeMap<String, String>,
+    },
+    /// Sent periodically by a downstream hub to advertise every client below it.
+    Clients { clients: Vec<ClientInfo> },
+    /// Sent by an agent whenever the state of the processes it watches changes.
+    Watched { processes: Vec<WatchedProcess> },
+    /// Sent by an agent whenever one of its checks has run again.
+    Checks { checks: Vec<CheckState> },
+}
+
+/// A client as listed by `/api/clients`, and as advertised to an upstream hub.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ClientInfo {
+    pub address: String,
+    /// Route for proxy requests; clients behind a downstream hub get the
+    /// hub's id prepended, e.g. `3.7`
+    pub id: Option<String>,
+    pub latency_ms: Option<f64>,
+    pub name: Option<String>,
+    pub online: bool,
+    pub last_seen: i64,
+    pub site: Option<String>,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub labels: BTreeMap<String, String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub watched: Vec<WatchedProcess>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub checks: Vec<CheckState>,
+}
+
+/// The state of a process a client has been configured to watch.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct WatchedProcess {
+    /// The process name, or the path of its pid file
+    pub name: String,
+    pub up: bool,
+    pub pid: Option<u32>,
+    /// How many times the process has come back with a different pid
+    pub restarts: u64,
+    /// Summed over every matching process, in percent of one core
+    pub cpu_usage: f32,
+    /// Resident set size in bytes, summed over every matching process
+    pub memory: u64,
+}
+
+/// The outcome of a check, following the exit codes of Nagios plugins.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CheckStatus {
+    Ok,
+    Warning,
+    Critical,
+    Unknown,
+}
+
+impl CheckStatus {
+    pub fn from_code(code: Option<i32>) -> Self {
+        match code {
+            Some(0) => CheckStatus::Ok,
+            Some(1) => CheckStatus::Warning,
+            Some(2) => CheckStatus::Critical,
+            _ => CheckStatus::Unknown,
+        }
+    }
+
+    pub fn code(self) -> u8 {
+        match self {
+            CheckStatus::Ok => 0,
+            CheckStatus::Warning => 1,
+            CheckStatus::Critical => 2,
+            CheckStatus::Unknown => 3,
+        }
+    }
+}
+
+/// The latest run of a check a client has been configured with.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct CheckState {
+    pub name: String,
+    pub status: CheckStatus,
+    /// The first line the check printed, without its performance data
+    pub output: String,
+    pub last_run: i64,
+    pub duration_ms: f64,
+}
+
+/// Options for a proxy request, passed along with it to the client and to any
+/// hub relaying it.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct ProxyParams {
+    /// Only return stats recorded at or after this time
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub since: Option<i64>,
+    /// Return the series of this metric rather than the overall CPU usage
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub metric: Option<String>,
+    /// Return the process snapshot taken at or just before this time instead
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub processes_at: Option<i64>,
+    /// Return the name and labels of every series recorded since `since` instead
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub series: bool,
+    /// Store these lines of InfluxDB line protocol instead, answering with a `WriteResult`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub write: Option<String>,
+    /// The precision of the timestamps in `write`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub precision: Option<Precision>,
+    /// Return the client's latest log lines instead, answering with `LogLines`
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub logs: bool,
+    /// Only return log lines after this sequence number
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub after: Option<u64>,
+    /// Only return log lines matching this regular expression
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub grep: Option<String>,
+    /// Return the log lines that don't match `grep` instead, like `grep -v`
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub invert: bool,
+    /// Only return log lines read from this file
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub file: Option<String>,
+    /// Return at most this many of the newest matching log lines
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub limit: Option<usize>,
+    /// How long the client may wait for a matching log line when there is none yet
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub wait_ms: Option<u64>,
+    /// How long to wait for the client to answer
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub timeout_ms: Option<u64>,
+}
+
+/// One series of a metric as returned for a proxy request with `metric` set,
+/// e.g. `cpu_core_usage` with `{"core": "3"}`.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct MetricSeries {
+    pub labels: BTreeMap<String, String>,
+    /// `(timestamp, value)` pairs, newest first
+    pub samples: Vec<(i64, f64)>,
+}
+
+/// The unit of line protocol timestamps, named as InfluxDB's `precision` parameter names it.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Precision {
+    #[default]
+    #[serde(rename = "ns", alias = "n")]
+    Nanoseconds,
+    #[serde(rename = "us", alias = "u")]
+    Microseconds,
+    #[serde(rename = "ms")]
+    Milliseconds,
+    #[serde(rename = "s")]
+    Seconds,
+    #[serde(rename = "m")]
+    Minutes,
+    #[serde(rename = "h")]
+    Hours,
+}
+
+/// A line of a write that couldn't be parsed, numbered from 1.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct LineError {
+    pub line: usize,
+    pub error: String,
+}
+
+impl std::fmt::Display for LineError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "line {}: {}", self.line, self.error)
+    }
+}
+
+/// The outcome of writing line protocol to a client. Lines that could be
+/// parsed are stored even when others couldn't.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct WriteResult {
+    /// How many points were stored
+    pub written: usize,
+    pub errors: Vec<LineError>,
+}
+
+/// What a client posts back in place of a response to a proxy request it
+/// couldn't answer, such as a downstream hub whose client isn't connected,
+/// with the reason as `proxy::failure_reason` gives it.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ProxyFailure {
+    pub error: String,
+}
+
+/// A series as listed for a proxy request with `series` set.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct SeriesInfo {
+    pub metric: String,
+    pub labels: BTreeMap<String, String>,
+}
+
+/// A line read from one of a client's log files.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct LogLine {
+    /// Numbers every line the client has read, in order, from 1
+    pub seq: u64,
+    pub timestamp: i64,
+    pub file: String,
+    pub line: String,
+}
+
+/// The answer to a proxy request with `logs` set.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct LogLines {
+    /// The matching lines, oldest first
+    pub lines: Vec<LogLine>,
+    /// The newest line the client has read, to pass as `after` for the lines after these
+    pub last: u64,
+    /// Set when there were more matching lines than returned, or some after
+    /// `after` had already been dropped from the client's buffer
+    pub truncated: bool,
+    /// The log files the client tails
+    pub files: Vec<String>,
+    /// Why the lines couldn't be returned, e.g. an invalid `grep`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+/// A process as recorded in a snapshot of the busiest ones.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ProcessInfo {
+    pub pid: u32,
+    pub name: String,
+    pub command: String,
+    pub user: Option<String>,
+    /// Percent of one core, so a busy multi-threaded process may exceed 100
+    pub cpu_usage: f32,
+    /// Resident set size in bytes
+    pub memory: u64,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ProcessSnapshot {
+    pub timestamp: i64,
+    pub processes: Vec<ProcessInfo>,
+}
+
+/// Messages the hub sends to a client over the websocket, besides proxy requests.
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum HubEvent {
+    /// Sent before the hub shuts down. The client should wait `reconnect_delay_ms`
+    /// and then reconnect, to `redirect_uri` if one is given.
+    GoingAway {
+        redirect_uri: Option<String>,
+        reconnect_delay_ms: u64,
+    },
+}
@@ -1,21 +1,24 @@
+use crate::protocol::{ProxyFailure, ProxyParams};
+use crate::registry;
 use crate::websocket_server::Users;
 use once_cell::sync::Lazy;
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
 use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, Ordering};
 use tokio::sync::RwLock;
-use tokio::time::{sleep, Duration};
+use tokio::time::{sleep, Duration, Instant};
 use uuid::Uuid;
 use warp::ws::Message;
 
 #[derive(Serialize, Deserialize)]
-struct ProxyResponse {
-    id: usize,
-}
-
-#[derive(Serialize, Deserialize)]
-struct ClientMessage {
-    request_id: String,
+pub struct ClientMessage {
+    pub request_id: String,
+    /// For a downstream hub, the route of the client below it to relay to
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub target: Option<String>,
+    #[serde(flatten)]
+    pub params: ProxyParams,
 }
 
 #[derive(Debug)]
@@ -24,16 +27,40 @@ pub struct ParseError;
 #[derive(Debug)]
 pub struct RequestIdNotFound;
 
+#[derive(Debug)]
+pub struct ClientTimeout;
+
+#[derive(Debug)]
+pub struct NotConnected;
+
+#[derive(Debug)]
+pub struct Draining;
+
+#[derive(Debug)]
+pub struct PeerError;
+
 impl warp::reject::Reject for ParseError {}
 impl warp::reject::Reject for RequestIdNotFound {}
+impl warp::reject::Reject for ClientTimeout {}
+impl warp::reject::Reject for NotConnected {}
+impl warp::reject::Reject for Draining {}
+impl warp::reject::Reject for PeerError {}
 
 static PENDING_REQUESTS: Lazy<RwLock<HashMap<String, Option<String>>>> =
     Lazy::new(|| RwLock::new(HashMap::new()));
 
-static MAX_POLL_ITERS: u32 = 5000;
+// How long to wait for a client when the request doesn't say
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(500);
+
+// Extra time given to another instance, so that it gives up on its client first
+const PEER_GRACE: Duration = Duration::from_secs(5);
 
-async fn poll_for_response(uuid: &str) -> Option<String> {
-    for _ in 1..MAX_POLL_ITERS {
+// Set once the hub starts shutting down, after which no new requests are proxied
+static DRAINING: AtomicBool = AtomicBool::new(false);
+
+async fn poll_for_response(uuid: &str, timeout: Duration) -> Option<String> {
+    let started = Instant::now();
+    while started.elapsed() < timeout {
         let req_map = PENDING_REQUESTS.read().await;
         if let Some(response) = req_map.get(uuid).cloned() {
             if response.is_some() {
@@ -46,32 +73,189 @@ async fn poll_for_response(uuid: &str) -> Option<String> {
     None
 }
 
-pub async fn handler(source: String, users: Users) -> Result<impl warp::Reply, warp::Rejection> {
-    let id: usize = source
-        .parse()
-        .map_err(|_e| warp::reject::custom(ParseError))?;
-    let user_map = users.read().await;
-    let user = user_map.get(&id);
-    if let Some(user) = user {
-        let body = ClientMessage {
-            request_id: Uuid::new_v4().to_string(),
-        };
-        let mut req_map = PENDING_REQUESTS.write().await;
-        req_map.insert(body.request_id.clone(), None);
-        drop(req_map);
-        let msg = Message::text(serde_json::to_string(&body).unwrap());
-        if let Err(_disconnected) = user.sender.send(msg) {
-            eprintln!("Could not reach client through websocket.");
-        };
-        let response = poll_for_response(&body.request_id).await;
-        let json_res: Value =
-            serde_json::from_str(&response.unwrap()).expect("Expected response to be valid JSON");
-        Ok(warp::reply::json(&json_res))
+/// Whether this instance is waiting on a client's response to the request.
+pub async fn is_pending(request_id: &str) -> bool {
+    PENDING_REQUESTS.read().await.contains_key(request_id)
+}
+
+/// Stops accepting proxy requests and waits up to `deadline` for the ones in flight to finish.
+pub async fn drain(deadline: Duration) {
+    DRAINING.store(true, Ordering::SeqCst);
+    let pending = PENDING_REQUESTS.read().await.len();
+    if pending > 0 {
+        println!("Waiting for {pending} proxy request(s) to finish...");
+    }
+    let started = Instant::now();
+    while started.elapsed() < deadline {
+        if PENDING_REQUESTS.read().await.is_empty() {
+            return;
+        }
+        sleep(Duration::from_millis(100)).await;
+    }
+    eprintln!("Gave up waiting for proxy requests to finish");
+}
+
+/// Passes a proxy request on to the hub instance holding the client.
+async fn forward_request(
+    instance: &str,
+    source: &str,
+    params: ProxyParams,
+) -> Result<Value, warp::Rejection> {
+    let url = format!("{instance}/api/proxy/{source}");
+    let client = reqwest::Client::new();
+    // Writes carry their lines, which are too much for a query string
+    let request = if params.write.is_some() {
+        client.post(&url).json(&params)
     } else {
-        Err(warp::reject::not_found())
+        client.get(&url).query(&params)
+    };
+    let res = request
+        .timeout(timeout(&params) + PEER_GRACE)
+        .send()
+        .await
+        .map_err(|e| {
+            eprintln!("Error forwarding proxy request to {instance}: {e}");
+            warp::reject::custom(PeerError)
+        })?;
+    match res.status() {
+        reqwest::StatusCode::NOT_FOUND => Err(warp::reject::custom(NotConnected)),
+        reqwest::StatusCode::GATEWAY_TIMEOUT => Err(warp::reject::custom(ClientTimeout)),
+        status if status.is_success() => res.json().await.map_err(|e| {
+            eprintln!("Invalid response from {instance}: {e}");
+            warp::reject::custom(PeerError)
+        }),
+        status => {
+            eprintln!("{instance} answered a proxy request with {status}");
+            Err(warp::reject::custom(PeerError))
+        }
     }
 }
 
+/// Passes a client's response on to the hub instance waiting for it.
+async fn forward_response(
+    instance: &str,
+    request_id: &str,
+    body: warp::hyper::body::Bytes,
+) -> bool {
+    let url = format!("{instance}/api/proxy/response/{request_id}");
+    match reqwest::Client::new().post(&url).body(body).send().await {
+        Ok(res) => res.status().is_success(),
+        Err(e) => {
+            eprintln!("Error forwarding proxy response to {instance}: {e}");
+            false
+        }
+    }
+}
+
+fn timeout(params: &ProxyParams) -> Duration {
+    params
+        .timeout_ms
+        .map_or(DEFAULT_TIMEOUT, Duration::from_millis)
+}
+
+/// Asks the client routed to by `source` for its stats. A route of `3.7`
+/// relays the request through the downstream hub with id 3 to its client 7.
+/// Clients connected to another hub instance are reached through that instance.
+/// Once the hub starts draining, no new requests are sent.
+pub async fn request(
+    source: &str,
+    users: &Users,
+    params: ProxyParams,
+) -> Result<Value, warp::Rejection> {
+    if DRAINING.load(Ordering::SeqCst) {
+        return Err(warp::reject::custom(Draining));
+    }
+    let (id, target) = match source.split_once('.') {
+        Some((id, rest)) => (id, Some(rest.to_string())),
+        None => (source, None),
+    };
+    let id: usize = id.parse().map_err(|_e| warp::reject::custom(ParseError))?;
+    // Don't hold the lock while waiting on the client
+    let local = users
+        .read()
+        .await
+        .get(&id)
+        .map(|user| (user.hub, user.sender.clone()));
+    let sender = match local {
+        Some((hub, sender)) if hub == target.is_some() => sender,
+        Some(_) => return Err(warp::reject::custom(NotConnected)),
+        None => {
+            return match registry::call(move |registry| registry.locate_client(id)).await {
+                Ok(Some(instance)) => forward_request(&instance, source, params).await,
+                Ok(None) => Err(warp::reject::custom(NotConnected)),
+                Err(e) => {
+                    eprintln!("Error reading hub registry: {e}");
+                    Err(warp::reject::custom(PeerError))
+                }
+            };
+        }
+    };
+    let wait = timeout(&params);
+    let body = ClientMessage {
+        request_id: Uuid::new_v4().to_string(),
+        target,
+        params,
+    };
+    let mut req_map = PENDING_REQUESTS.write().await;
+    req_map.insert(body.request_id.clone(), None);
+    drop(req_map);
+    // The client may post its response to any hub instance
+    let request_id = body.request_id.clone();
+    if let Err(e) = registry::call(move |registry| registry.register_request(&request_id)).await {
+        eprintln!("Error updating hub registry: {e}");
+    }
+    let msg = Message::text(serde_json::to_string(&body).unwrap());
+    if let Err(_disconnected) = sender.send(msg) {
+        eprintln!("Could not reach client through websocket.");
+    };
+    let response = poll_for_response(&body.request_id, wait).await;
+    PENDING_REQUESTS.write().await.remove(&body.request_id);
+    let request_id = body.request_id.clone();
+    if let Err(e) = registry::call(move |registry| registry.unregister_request(&request_id)).await {
+        eprintln!("Error updating hub registry: {e}");
+    }
+    let response = response.ok_or_else(|| warp::reject::custom(ClientTimeout))?;
+    let json_res: Value =
+        serde_json::from_str(&response).expect("Expected response to be valid JSON");
+    // A downstream hub that couldn't relay the request says why
+    if let Ok(failure) = ProxyFailure::deserialize(&json_res) {
+        return Err(rejection(&failure.error));
+    }
+    Ok(json_res)
+}
+
+/// The rejection a failure reason, as given by `failure_reason`, stands for.
+fn rejection(reason: &str) -> warp::Rejection {
+    match reason {
+        "timeout" => warp::reject::custom(ClientTimeout),
+        "not connected" => warp::reject::custom(NotConnected),
+        "draining" => warp::reject::custom(Draining),
+        _ => warp::reject::custom(PeerError),
+    }
+}
+
+/// A short description of why a proxy request failed.
+pub fn failure_reason(rejection: &warp::Rejection) -> &'static str {
+    if rejection.find::<ClientTimeout>().is_some() {
+        "timeout"
+    } else if rejection.find::<NotConnected>().is_some() {
+        "not connected"
+    } else if rejection.find::<Draining>().is_some() {
+        "draining"
+    } else {
+        "error"
+    }
+}
+
+pub async fn handler(
+    source: String,
+    params: ProxyParams,
+    users: Users,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let json_res = request(&source, &users, params).await?;
+    Ok(warp::reply::json(&json_res))
+}
+
 pub async fn client_response_handler(
     request_id: String,
     body: warp::hyper::body::Bytes,
@@ -79,8 +263,17 @@ pub async fn client_response_handler(
     let data = std::str::from_utf8(&body).unwrap();
     let mut req_map = PENDING_REQUESTS.write().await;
     if req_map.get(&request_id).is_none() {
-        eprintln!("here");
         drop(req_map);
+        // Another hub instance may be the one waiting for it
+        let id = request_id.clone();
+        if let Ok(Some(instance)) =
+            registry::call(move |registry| registry.locate_request(&id)).await
+        {
+            if forward_response(&instance, &request_id, body).await {
+                return Ok(warp::reply::html("Success"));
+            }
+        }
+        eprintln!("No pending request with id {request_id}");
         return Err(warp::reject::custom(RequestIdNotFound));
     }
     req_map.insert(request_id, Some(data.to_string()));
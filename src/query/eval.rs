@@ -0,0 +1,446 @@
+use super::parser::{AggregateOp, BinaryOp, Expr, Function, Grouping, Selector};
+use std::collections::{BTreeMap, HashMap, HashSet};
+
+pub type Labels = BTreeMap<String, String>;
+
+const MANY_TO_MANY: &str =
+    "many-to-many matching isn't supported, narrow the match with on(...) or ignoring(...)";
+
+/// A series as fetched for a selector, with samples ordered by time.
+#[derive(Clone, Debug)]
+pub struct Series {
+    pub labels: Labels,
+    pub samples: Vec<(f64, f64)>,
+}
+
+#[derive(Clone, Debug)]
+pub struct Sample {
+    pub labels: Labels,
+    pub value: f64,
+}
+
+#[derive(Clone, Debug)]
+pub enum Value {
+    Scalar(f64),
+    Vector(Vec<Sample>),
+}
+
+/// The series fetched for every selector in a query, keyed by the selector.
+pub struct Data {
+    pub series: HashMap<String, Vec<Series>>,
+    /// How far back an instant selector looks for the latest sample
+    pub lookback: f64,
+}
+
+fn drop_name(mut labels: Labels) -> Labels {
+    labels.remove("__name__");
+    labels
+}
+
+/// The labels that identify a sample for grouping or matching.
+fn signature(labels: &Labels, grouping: Option<&Grouping>) -> Labels {
+    match grouping {
+        Some(Grouping::By(keep)) => labels
+            .iter()
+            .filter(|(k, _)| keep.contains(k))
+            .map(|(k, v)| (k.clone(), v.clone()))
+            .collect(),
+        Some(Grouping::Without(drop)) => labels
+            .iter()
+            .filter(|(k, _)| *k != "__name__" && !drop.contains(k))
+            .map(|(k, v)| (k.clone(), v.clone()))
+            .collect(),
+        None => drop_name(labels.clone()),
+    }
+}
+
+impl Data {
+    fn series(&self, selector: &Selector) -> &[Series] {
+        self.series
+            .get(&selector.to_string())
+            .map_or(&[], Vec::as_slice)
+    }
+
+    fn instant(&self, selector: &Selector, time: f64) -> Vec<Sample> {
+        self.series(selector)
+            .iter()
+            .filter_map(|s| {
+                s.samples
+                    .iter()
+                    .rev()
+                    .find(|&&(t, _)| t <= time && t > time - self.lookback)
+                    .map(|&(_, value)| Sample {
+                        labels: s.labels.clone(),
+                        value,
+                    })
+            })
+            .collect()
+    }
+
+    fn call(&self, function: Function, arg: &Expr, time: f64) -> Vec<Sample> {
+        let Expr::Range(selector, range) = arg else {
+            // The parser only accepts range vectors as function arguments
+            return Vec::new();
+        };
+        self.series(selector)
+            .iter()
+            .filter_map(|s| {
+                let window: Vec<(f64, f64)> = s
+                    .samples
+                    .iter()
+                    .copied()
+                    .filter(|&(t, _)| t <= time && t > time - range)
+                    .collect();
+                let value = over_time(function, &window)?;
+                Some(Sample {
+                    labels: drop_name(s.labels.clone()),
+                    value,
+                })
+            })
+            .collect()
+    }
+
+    /// Evaluates the expression at the given unix time.
+    pub fn eval(&self, expr: &Expr, time: f64) -> Result<Value, String> {
+        Ok(match expr {
+            Expr::Number(n) => Value::Scalar(*n),
+            Expr::Selector(selector) => Value::Vector(self.instant(selector, time)),
+            Expr::Range(..) => return Err("range vectors can't be evaluated directly".to_string()),
+            Expr::Call(function, arg) => Value::Vector(self.call(*function, arg, time)),
+            Expr::Aggregate(op, grouping, arg) => {
+                let Value::Vector(samples) = self.eval(arg, time)? else {
+                    return Err("aggregations expect an instant vector".to_string());
+                };
+                Value::Vector(aggregate(*op, grouping.as_ref(), samples))
+            }
+            Expr::Negate(arg) => match self.eval(arg, time)? {
+                Value::Scalar(n) => Value::Scalar(-n),
+                Value::Vector(samples) => Value::Vector(
+                    samples
+                        .into_iter()
+                        .map(|s| Sample {
+                            labels: drop_name(s.labels),
+                            value: -s.value,
+                        })
+                        .collect(),
+                ),
+            },
+            Expr::Binary(op, lhs, rhs, matching) => binary(
+                *op,
+                self.eval(lhs, time)?,
+                self.eval(rhs, time)?,
+                matching.as_ref(),
+            )?,
+        })
+    }
+}
+
+fn over_time(function: Function, window: &[(f64, f64)]) -> Option<f64> {
+    let (&(first_t, first), &(last_t, _)) = (window.first()?, window.last()?);
+    let values = window.iter().map(|&(_, v)| v);
+    Some(match function {
+        Function::Rate => {
+            if window.len() < 2 || last_t <= first_t {
+                return None;
+            }
+            // Counters that go down have been reset, so count from zero again
+            let mut increase = 0.0;
+            let mut previous = first;
+            for &(_, v) in &window[1..] {
+                increase += if v < previous { v } else { v - previous };
+                previous = v;
+            }
+            increase / (last_t - first_t)
+        }
+        Function::AvgOverTime => values.sum::<f64>() / window.len() as f64,
+        Function::MaxOverTime => values.fold(f64::NEG_INFINITY, f64::max),
+        Function::MinOverTime => values.fold(f64::INFINITY, f64::min),
+        Function::SumOverTime => values.sum(),
+        Function::CountOverTime => window.len() as f64,
+    })
+}
+
+fn aggregate(op: AggregateOp, grouping: Option<&Grouping>, samples: Vec<Sample>) -> Vec<Sample> {
+    let mut groups: BTreeMap<Labels, Vec<f64>> = BTreeMap::new();
+    for sample in samples {
+        // Without a grouping everything is aggregated into one sample
+        let key = match grouping {
+            Some(grouping) => signature(&sample.labels, Some(grouping)),
+            None => Labels::new(),
+        };
+        groups.entry(key).or_default().push(sample.value);
+    }
+    groups
+        .into_iter()
+        .map(|(labels, values)| {
+            let value = match op {
+                AggregateOp::Sum => values.iter().sum(),
+                AggregateOp::Avg => values.iter().sum::<f64>() / values.len() as f64,
+                AggregateOp::Min => values.iter().copied().fold(f64::INFINITY, f64::min),
+                AggregateOp::Max => values.iter().copied().fold(f64::NEG_INFINITY, f64::max),
+                AggregateOp::Count => values.len() as f64,
+            };
+            Sample { labels, value }
+        })
+        .collect()
+}
+
+fn binary(
+    op: BinaryOp,
+    lhs: Value,
+    rhs: Value,
+    matching: Option<&Grouping>,
+) -> Result<Value, String> {
+    let scale = |samples: Vec<Sample>, f: &dyn Fn(f64) -> f64| {
+        samples
+            .into_iter()
+            .map(|s| Sample {
+                labels: drop_name(s.labels),
+                value: f(s.value),
+            })
+            .collect()
+    };
+    Ok(match (lhs, rhs) {
+        (Value::Scalar(a), Value::Scalar(b)) => Value::Scalar(op.apply(a, b)),
+        (Value::Vector(v), Value::Scalar(b)) => Value::Vector(scale(v, &|a| op.apply(a, b))),
+        (Value::Scalar(a), Value::Vector(v)) => Value::Vector(scale(v, &|b| op.apply(a, b))),
+        (Value::Vector(lhs), Value::Vector(rhs)) => {
+            // One-to-one matching on the labels both sides share
+            let mut right: HashMap<Labels, f64> = HashMap::new();
+            for sample in rhs {
+                let key = signature(&sample.labels, matching);
+                if right.insert(key, sample.value).is_some() {
+                    return Err(MANY_TO_MANY.to_string());
+                }
+            }
+            let mut seen = HashSet::new();
+            let mut result = Vec::new();
+            for sample in lhs {
+                let key = signature(&sample.labels, matching);
+                let Some(&value) = right.get(&key) else {
+                    continue;
+                };
+                if !seen.insert(key) {
+                    return Err(MANY_TO_MANY.to_string());
+                }
+                result.push(Sample {
+                    labels: drop_name(sample.labels),
+                    value: op.apply(sample.value, value),
+                });
+            }
+            Value::Vector(result)
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::query::parser::parse;
+
+    fn labels(pairs: &[(&str, &str)]) -> Labels {
+        pairs
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect()
+    }
+
+    fn series(pairs: &[(&str, &str)], samples: &[(f64, f64)]) -> Series {
+        Series {
+            labels: labels(pairs),
+            samples: samples.to_vec(),
+        }
+    }
+
+    /// Evaluates the query at `time`, fetching each selector from `all` as the
+    /// database would.
+    fn eval(query: &str, all: &[Series], time: f64) -> Result<Value, String> {
+        let expr = parse(query).unwrap();
+        let mut series = HashMap::new();
+        for (selector, _) in expr.selectors(300.0) {
+            let selected = all
+                .iter()
+                .filter(|s| {
+                    selector
+                        .matchers
+                        .iter()
+                        .all(|m| m.matches(s.labels.get(&m.label).map(String::as_str)))
+                })
+                .cloned()
+                .collect();
+            series.insert(selector.to_string(), selected);
+        }
+        Data {
+            series,
+            lookback: 300.0,
+        }
+        .eval(&expr, time)
+    }
+
+    fn scalar(query: &str) -> f64 {
+        match eval(query, &[], 0.0) {
+            Ok(Value::Scalar(n)) => n,
+            other => panic!("{query}: {other:?}"),
+        }
+    }
+
+    /// The samples of an instant vector, sorted by their labels.
+    fn vector(query: &str, all: &[Series], time: f64) -> Vec<(Labels, f64)> {
+        match eval(query, all, time) {
+            Ok(Value::Vector(samples)) => {
+                let mut samples: Vec<_> =
+                    samples.into_iter().map(|s| (s.labels, s.value)).collect();
+                samples.sort_by(|a, b| a.0.cmp(&b.0));
+                samples
+            }
+            other => panic!("{query}: {other:?}"),
+        }
+    }
+
+    fn cpu() -> Vec<Series> {
+        vec![
+            series(
+                &[("__name__", "cpu"), ("host", "a"), ("role", "web")],
+                &[(10.0, 1.0), (20.0, 3.0)],
+            ),
+            series(
+                &[("__name__", "cpu"), ("host", "b"), ("role", "web")],
+                &[(10.0, 5.0)],
+            ),
+            series(
+                &[("__name__", "cpu"), ("host", "c"), ("role", "db")],
+                &[(10.0, 2.0)],
+            ),
+            series(
+                &[("__name__", "cores"), ("host", "a"), ("role", "web")],
+                &[(10.0, 2.0)],
+            ),
+            series(
+                &[("__name__", "cores"), ("host", "b"), ("role", "web")],
+                &[(10.0, 4.0)],
+            ),
+        ]
+    }
+
+    #[test]
+    fn operators_follow_precedence() {
+        assert_eq!(scalar("1 + 2 * 3"), 7.0);
+        assert_eq!(scalar("(1 + 2) * 3"), 9.0);
+        assert_eq!(scalar("10 - 4 - 3"), 3.0);
+        assert_eq!(scalar("2 * 3 % 4"), 2.0);
+        assert_eq!(scalar("2 ^ 3 ^ 2"), 512.0);
+        assert_eq!(scalar("-2 ^ 2"), -4.0);
+        assert_eq!(scalar("2 ^ -1"), 0.5);
+    }
+
+    #[test]
+    fn selectors_pick_the_latest_sample_within_the_lookback() {
+        let all = cpu();
+        assert_eq!(
+            vector(r#"cpu{role="web"}"#, &all, 20.0),
+            [
+                (
+                    labels(&[("__name__", "cpu"), ("host", "a"), ("role", "web")]),
+                    3.0
+                ),
+                (
+                    labels(&[("__name__", "cpu"), ("host", "b"), ("role", "web")]),
+                    5.0
+                ),
+            ]
+        );
+        // Samples after the evaluation time aren't seen, nor ones past the lookback
+        assert_eq!(vector(r#"cpu{host="a"}"#, &all, 15.0)[0].1, 1.0);
+        assert!(vector("cpu", &all, 400.0).is_empty());
+    }
+
+    #[test]
+    fn range_functions_use_the_samples_in_the_window() {
+        let all = [series(
+            &[("__name__", "requests"), ("host", "a")],
+            &[
+                (0.0, 10.0),
+                (10.0, 20.0),
+                (20.0, 5.0),
+                (30.0, 15.0),
+                (40.0, 100.0),
+            ],
+        )];
+        let host = labels(&[("host", "a")]);
+        // 10 until the reset at 20s, 5 counted from zero, then 10 more, over 30s
+        assert_eq!(
+            vector("rate(requests[35s])", &all, 30.0),
+            [(host.clone(), 25.0 / 30.0)]
+        );
+        assert_eq!(
+            vector("avg_over_time(requests[35s])", &all, 30.0),
+            [(host.clone(), 12.5)]
+        );
+        assert_eq!(
+            vector("max_over_time(requests[15s])", &all, 30.0),
+            [(host.clone(), 15.0)]
+        );
+        assert_eq!(
+            vector("max_over_time(requests[1m])", &all, 40.0),
+            [(host, 100.0)]
+        );
+        // A single sample has no rate
+        assert!(vector("rate(requests[5s])", &all, 30.0).is_empty());
+    }
+
+    #[test]
+    fn aggregations_group_by_labels() {
+        let all = cpu();
+        assert_eq!(vector("sum(cpu)", &all, 20.0), [(Labels::new(), 10.0)]);
+        assert_eq!(
+            vector("sum by (role) (cpu)", &all, 20.0),
+            [
+                (labels(&[("role", "db")]), 2.0),
+                (labels(&[("role", "web")]), 8.0),
+            ]
+        );
+        assert_eq!(
+            vector("max without (host) (cpu)", &all, 20.0),
+            [
+                (labels(&[("role", "db")]), 2.0),
+                (labels(&[("role", "web")]), 5.0),
+            ]
+        );
+        assert_eq!(
+            vector("count by (role) (cpu)", &all, 20.0),
+            [
+                (labels(&[("role", "db")]), 1.0),
+                (labels(&[("role", "web")]), 2.0),
+            ]
+        );
+    }
+
+    #[test]
+    fn vectors_match_one_to_one() {
+        let all = cpu();
+        let per_core = [
+            (labels(&[("host", "a"), ("role", "web")]), 1.5),
+            (labels(&[("host", "b"), ("role", "web")]), 1.25),
+        ];
+        // Samples without a match on the other side are dropped
+        assert_eq!(vector("cpu / cores", &all, 20.0), per_core);
+        assert_eq!(vector("cpu / on(host) cores", &all, 20.0), per_core);
+        assert_eq!(vector("cpu / ignoring(role) cores", &all, 20.0), per_core);
+        assert_eq!(
+            vector("cpu * 2", &all, 20.0)[0],
+            (labels(&[("host", "a"), ("role", "web")]), 6.0)
+        );
+        let e = eval("cpu / on(role) cores", &all, 20.0).unwrap_err();
+        assert_eq!(e, MANY_TO_MANY);
+        let e = eval("cpu / on(role) sum by (role) (cores)", &all, 20.0).unwrap_err();
+        assert_eq!(e, MANY_TO_MANY);
+        assert_eq!(
+            vector(
+                "sum by (role) (cpu) / on(role) sum by (role) (cores)",
+                &all,
+                20.0
+            ),
+            [(labels(&[("role", "web")]), 8.0 / 6.0)]
+        );
+    }
+}
@@ -0,0 +1,547 @@
+mod eval;
+mod parser;
+
+use crate::clients;
+use crate::collectors;
+use crate::db;
+use crate::protocol::{ClientInfo, MetricSeries, ProxyParams, SeriesInfo};
+use crate::proxy;
+use crate::utils;
+use crate::websocket_server::Users;
+use eval::{Data, Series, Value};
+use futures_util::future::join_all;
+use parser::{parse_duration, Expr, MatchOp, Selector};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use tokio::task;
+
+pub use eval::Labels;
+
+// How far back an instant selector looks for the latest sample, like PromQL's default
+const LOOKBACK: f64 = 300.0;
+// How long to wait for each client's stats
+const FETCH_TIMEOUT_MS: u64 = 10_000;
+// Range queries may not evaluate at more points than this
+const MAX_POINTS: f64 = 11_000.0;
+// The metric every client has recorded from the start, which is fetched
+// without naming it so older clients can still answer
+const CPU_USAGE: &str = "cpu_usage";
+
+/// A query that couldn't be parsed or evaluated.
+#[derive(Debug)]
+pub struct QueryError(pub String);
+
+impl warp::reject::Reject for QueryError {}
+
+fn invalid(message: impl Into<String>) -> warp::Rejection {
+    warp::reject::custom(QueryError(message.into()))
+}
+
+#[derive(Deserialize)]
+pub struct InstantQuery {
+    query: Option<String>,
+    /// Unix time to evaluate at, defaulting to now
+    time: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct RangeQuery {
+    query: Option<String>,
+    start: Option<String>,
+    end: Option<String>,
+    /// Seconds, or a duration such as `1m`
+    step: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct InstantSample {
+    pub metric: Labels,
+    pub value: (f64, f64),
+}
+
+#[derive(Serialize)]
+pub struct RangeSeries {
+    pub metric: Labels,
+    pub values: Vec<(f64, f64)>,
+}
+
+#[derive(Serialize)]
+#[serde(tag = "result_type", content = "result", rename_all = "snake_case")]
+pub enum QueryResult {
+    Scalar((f64, f64)),
+    Vector(Vec<InstantSample>),
+    Matrix(Vec<RangeSeries>),
+}
+
+#[derive(Serialize)]
+struct Response {
+    #[serde(flatten)]
+    result: QueryResult,
+    /// Clients that couldn't be queried, whose series are missing from the result
+    warnings: Vec<String>,
+}
+
+pub fn parse_time(name: &str, value: Option<&str>) -> Result<f64, warp::Rejection> {
+    value.map_or_else(
+        || Ok(utils::unix_timestamp() as f64),
+        |v| {
+            v.parse()
+                .ok()
+                .filter(|t: &f64| t.is_finite())
+                .ok_or_else(|| {
+                    invalid(format!("invalid {name} \"{v}\", expected a unix timestamp"))
+                })
+        },
+    )
+}
+
+fn parse(query: Option<&str>) -> Result<Expr, warp::Rejection> {
+    let query = query.ok_or_else(|| invalid("missing query parameter"))?;
+    parser::parse(query).map_err(|e| invalid(e.to_string()))
+}
+
+/// The labels a client's series are identified by, besides the metric name.
+fn client_labels(client: &ClientInfo, id: &str) -> Labels {
+    let mut labels = client.labels.clone();
+    labels.insert("id".to_string(), id.to_string());
+    labels.insert(
+        "client".to_string(),
+        client.name.clone().unwrap_or_else(|| id.to_string()),
+    );
+    if let Some(site) = &client.site {
+        labels.insert("site".to_string(), site.clone());
+    }
+    labels
+}
+
+/// A metric of a connected client, labelled with what is known before asking
+/// the client for its series.
+struct ClientMetric {
+    id: String,
+    metric: String,
+    /// The labels of the metric's own, which only its series have, or `None`
+    /// for a metric the hub doesn't know of
+    series_labels: Option<&'static [&'static str]>,
+    labels: Labels,
+}
+
+impl ClientMetric {
+    /// Whether any of the metric's series might match the selector. Matchers on
+    /// the metric's own labels are left until its series are fetched.
+    fn may_match(&self, selector: &Selector) -> bool {
+        selector
+            .matchers
+            .iter()
+            .filter(|m| match self.series_labels {
+                Some(own) => !own.contains(&m.label.as_str()),
+                // Any label the client itself doesn't have may be one of the metric's own
+                None => self.labels.contains_key(&m.label),
+            })
+            .all(|m| m.matches(self.labels.get(&m.label).map(String::as_str)))
+    }
+}
+
+/// The metric a selector names outright, e.g. `check_status` in `check_status{check="disk"}`.
+fn exact_name(selector: &Selector) -> Option<&str> {
+    selector
+        .matchers
+        .iter()
+        .find(|m| m.label == "__name__" && m.op == MatchOp::Equal)
+        .map(|m| m.value.as_str())
+}
+
+/// The names of the metrics a client has recorded since `since`. Clients that
+/// can't list their series, such as older ones, are taken to have none.
+async fn listed_metrics(id: &str, since: i64, users: &Users) -> BTreeSet<String> {
+    let params = ProxyParams {
+        since: Some(since),
+        timeout_ms: Some(FETCH_TIMEOUT_MS),
+        series: true,
+        ..ProxyParams::default()
+    };
+    let Ok(response) = proxy::request(id, users, params).await else {
+        return BTreeSet::new();
+    };
+    serde_json::from_value::<Vec<SeriesInfo>>(response)
+        .map(|series| series.into_iter().map(|s| s.metric).collect())
+        .unwrap_or_default()
+}
+
+/// The metrics of connected clients that the selectors may want: every metric the
+/// hub knows of, those the selectors name, and for selectors that don't name
+/// one, whatever else the clients have recorded since `since`.
+async fn client_metrics(
+    selectors: &HashMap<String, (&Selector, f64)>,
+    since: i64,
+    users: &Users,
+) -> Result<Vec<ClientMetric>, warp::Rejection> {
+    let clients = clients::list(users).await.map_err(|e| {
+        eprintln!("Error reading known clients: {e}");
+        warp::reject::custom(db::DatabaseError)
+    })?;
+    let clients: Vec<(&String, &ClientInfo)> = clients
+        .iter()
+        .filter(|c| c.online)
+        .filter_map(|c| Some((c.id.as_ref()?, c)))
+        .collect();
+
+    let known: Vec<(&str, &'static [&'static str])> = std::iter::once((CPU_USAGE, &[][..]))
+        .chain(collectors::metrics())
+        .collect();
+    let is_known = |name: &str| known.iter().any(|&(metric, _)| metric == name);
+    let named: BTreeSet<String> = selectors
+        .values()
+        .filter_map(|(s, _)| exact_name(s))
+        .filter(|name| !is_known(name))
+        .map(str::to_string)
+        .collect();
+    let listed = if selectors.values().any(|(s, _)| exact_name(s).is_none()) {
+        join_all(
+            clients
+                .iter()
+                .map(|(id, _)| listed_metrics(id, since, users)),
+        )
+        .await
+    } else {
+        vec![BTreeSet::new(); clients.len()]
+    };
+
+    let mut metrics = Vec::new();
+    for ((id, client), listed) in clients.into_iter().zip(listed) {
+        let unknown: BTreeSet<String> = named
+            .iter()
+            .cloned()
+            .chain(listed.into_iter().filter(|name| !is_known(name)))
+            .collect();
+        let all = known
+            .iter()
+            .map(|&(metric, series_labels)| (metric.to_string(), Some(series_labels)))
+            .chain(unknown.into_iter().map(|metric| (metric, None)));
+        for (metric, series_labels) in all {
+            let mut labels = client_labels(client, id);
+            labels.insert("__name__".to_string(), metric.clone());
+            metrics.push(ClientMetric {
+                id: id.clone(),
+                metric,
+                series_labels,
+                labels,
+            });
+        }
+    }
+    Ok(metrics)
+}
+
+/// The labels of every series matching any of the selectors, or of every
+/// series if there are none, that has had a sample recently.
+pub async fn series(selectors: &[&str], users: &Users) -> Result<Vec<Labels>, warp::Rejection> {
+    let mut selectors = selectors
+        .iter()
+        .map(|s| parser::parse_selector(s).map_err(|e| invalid(e.to_string())))
+        .collect::<Result<Vec<_>, _>>()?;
+    if selectors.is_empty() {
+        selectors.push(
+            parser::parse_selector("{__name__=~\".+\"}").map_err(|e| invalid(e.to_string()))?,
+        );
+    }
+    let selectors = selectors
+        .iter()
+        .map(|s| (s.to_string(), (s, LOOKBACK)))
+        .collect();
+    let now = utils::unix_timestamp() as f64;
+    let (series, _warnings) = fetch_selectors(&selectors, now, now, users).await?;
+    let labels: BTreeSet<Labels> = series
+        .into_values()
+        .flatten()
+        .filter(|s| !s.samples.is_empty())
+        .map(|s| s.labels)
+        .collect();
+    Ok(labels.into_iter().collect())
+}
+
+fn selects(selector: &Selector, labels: &Labels) -> bool {
+    selector
+        .matchers
+        .iter()
+        .all(|m| m.matches(labels.get(&m.label).map(String::as_str)))
+}
+
+/// The series in a client's answer, labelled only with the metric's own labels,
+/// with samples up to `end` in time order.
+fn client_series(
+    metric: &str,
+    response: Result<serde_json::Value, warp::Rejection>,
+    end: f64,
+) -> Result<Vec<Series>, String> {
+    let response = response.map_err(|e| proxy::failure_reason(&e).to_string())?;
+    let series: Vec<MetricSeries> = if metric == CPU_USAGE {
+        let stats: Vec<(i64, f32)> = serde_json::from_value(response).map_err(|e| e.to_string())?;
+        vec![MetricSeries {
+            labels: Labels::new(),
+            samples: stats.into_iter().map(|(t, v)| (t, f64::from(v))).collect(),
+        }]
+    } else {
+        serde_json::from_value(response).map_err(|e| e.to_string())?
+    };
+    Ok(series.into_iter().map(|s| to_series(s, end)).collect())
+}
+
+/// A stored series with its samples up to `end` in time order.
+fn to_series(series: MetricSeries, end: f64) -> Series {
+    let mut points: Vec<(f64, f64)> = series
+        .samples
+        .into_iter()
+        .map(|(t, v)| (t as f64, v))
+        .filter(|&(t, _)| t <= end)
+        .collect();
+    points.sort_by(|a, b| a.0.total_cmp(&b.0));
+    Series {
+        labels: series.labels,
+        samples: points,
+    }
+}
+
+/// The series the hub has stored itself, such as metrics received over OTLP,
+/// matching each selector, with samples from the selector's range before
+/// `start` up to `end`. Selectors that name their metric only read its samples.
+fn local_series(
+    selectors: &[(String, Selector, f64)],
+    start: f64,
+    end: f64,
+) -> Result<HashMap<String, Vec<Series>>, crate::db::Error> {
+    let mut series: HashMap<String, Vec<Series>> = HashMap::new();
+    for (key, selector, range) in selectors {
+        let since = (start - range).floor() as i64;
+        let metrics = match exact_name(selector) {
+            Some(name) => vec![name.to_string()],
+            None => db::get_metrics_since(since)?
+                .into_iter()
+                .filter(|metric| {
+                    selector
+                        .matchers
+                        .iter()
+                        .filter(|m| m.label == "__name__")
+                        .all(|m| m.matches(Some(metric)))
+                })
+                .collect(),
+        };
+        for metric in metrics {
+            for stored in db::get_samples_since(&metric, since)? {
+                let mut stored = to_series(stored, end);
+                stored.labels.insert("__name__".to_string(), metric.clone());
+                if selects(selector, &stored.labels) {
+                    series.entry(key.clone()).or_default().push(stored);
+                }
+            }
+        }
+    }
+    Ok(series)
+}
+
+/// Fetches from the clients, and from what the hub stores itself, the series
+/// matching each selector, keyed like the selectors, with samples from the
+/// selector's range before `start` up to `end`.
+async fn fetch_selectors(
+    selectors: &HashMap<String, (&Selector, f64)>,
+    start: f64,
+    end: f64,
+    users: &Users,
+) -> Result<(HashMap<String, Vec<Series>>, Vec<String>), warp::Rejection> {
+    // Work out which metrics of which clients each selector wants, so each is only asked for once
+    let longest = selectors
+        .values()
+        .map(|&(_, range)| range)
+        .fold(0.0, f64::max);
+    let metrics = client_metrics(selectors, (start - longest).floor() as i64, users).await?;
+    let mut wanted: BTreeMap<(&str, &str), f64> = BTreeMap::new();
+    let mut candidates: Vec<(&String, &Selector, &ClientMetric)> = Vec::new();
+    for metric in &metrics {
+        for (key, (selector, range)) in selectors {
+            if metric.may_match(selector) {
+                let wanted = wanted
+                    .entry((metric.id.as_str(), metric.metric.as_str()))
+                    .or_insert(*range);
+                *wanted = wanted.max(*range);
+                candidates.push((key, selector, metric));
+            }
+        }
+    }
+
+    let responses = join_all(wanted.iter().map(|(&(id, metric), range)| {
+        let params = ProxyParams {
+            since: Some((start - range).floor() as i64),
+            timeout_ms: Some(FETCH_TIMEOUT_MS),
+            metric: (metric != CPU_USAGE).then(|| metric.to_string()),
+            ..ProxyParams::default()
+        };
+        async move { ((id, metric), proxy::request(id, users, params).await) }
+    }))
+    .await;
+
+    let mut warnings = Vec::new();
+    let mut fetched = HashMap::new();
+    for ((id, metric), response) in responses {
+        match client_series(metric, response, end) {
+            Ok(series) => {
+                fetched.insert((id, metric), series);
+            }
+            Err(e) => warnings.push(format!(
+                "client {id} could not be queried for {metric}: {e}"
+            )),
+        }
+    }
+
+    // Reading them scans the hub's own samples, so it runs off the async workers
+    let owned: Vec<(String, Selector, f64)> = selectors
+        .iter()
+        .map(|(key, &(selector, range))| (key.clone(), selector.clone(), range))
+        .collect();
+    let stored =
+        task::spawn_blocking(move || local_series(&owned, start, end).map_err(|e| e.to_string()))
+            .await
+            .unwrap_or_else(|e| Err(e.to_string()));
+    let mut series = stored.map_err(|e| {
+        eprintln!("Error reading stored series: {e}");
+        warp::reject::custom(db::DatabaseError)
+    })?;
+    for (key, selector, metric) in candidates {
+        let Some(fetched) = fetched.get(&(metric.id.as_str(), metric.metric.as_str())) else {
+            continue;
+        };
+        for own in fetched {
+            let mut labels = metric.labels.clone();
+            labels.extend(own.labels.clone());
+            if selects(selector, &labels) {
+                series.entry(key.clone()).or_default().push(Series {
+                    labels,
+                    samples: own.samples.clone(),
+                });
+            }
+        }
+    }
+    Ok((series, warnings))
+}
+
+/// Fetches from the clients every series the expression needs to be
+/// evaluated between `start` and `end`.
+async fn fetch(
+    expr: &Expr,
+    start: f64,
+    end: f64,
+    users: &Users,
+) -> Result<(Data, Vec<String>), warp::Rejection> {
+    // The same selector may appear more than once; fetch it for the longest range
+    let mut selectors: HashMap<String, (&Selector, f64)> = HashMap::new();
+    for (selector, range) in expr.selectors(LOOKBACK) {
+        let entry = selectors
+            .entry(selector.to_string())
+            .or_insert((selector, range));
+        entry.1 = entry.1.max(range);
+    }
+    let (series, warnings) = fetch_selectors(&selectors, start, end, users).await?;
+    let data = Data {
+        series,
+        lookback: LOOKBACK,
+    };
+    Ok((data, warnings))
+}
+
+/// Evaluates a query at a single point in time.
+pub async fn instant(
+    query: Option<&str>,
+    time: f64,
+    users: &Users,
+) -> Result<(QueryResult, Vec<String>), warp::Rejection> {
+    let expr = parse(query)?;
+    let (data, warnings) = fetch(&expr, time, time, users).await?;
+    let result = match data.eval(&expr, time).map_err(invalid)? {
+        Value::Scalar(n) => QueryResult::Scalar((time, n)),
+        Value::Vector(samples) => QueryResult::Vector(
+            samples
+                .into_iter()
+                .map(|s| InstantSample {
+                    metric: s.labels,
+                    value: (time, s.value),
+                })
+                .collect(),
+        ),
+    };
+    Ok((result, warnings))
+}
+
+/// Evaluates a query at every step between `start` and `end`.
+pub async fn range(
+    query: Option<&str>,
+    start: f64,
+    end: f64,
+    step: f64,
+    users: &Users,
+) -> Result<(QueryResult, Vec<String>), warp::Rejection> {
+    let expr = parse(query)?;
+    if !(start.is_finite() && end.is_finite() && step.is_finite()) {
+        return Err(invalid("start, end and step must be finite"));
+    }
+    if end < start {
+        return Err(invalid("end must not be before start"));
+    }
+    if step <= 0.0 {
+        return Err(invalid("step must be positive"));
+    }
+    if (end - start) / step > MAX_POINTS {
+        return Err(invalid(format!(
+            "too many points, use a step of at least {}s",
+            ((end - start) / MAX_POINTS).ceil()
+        )));
+    }
+    let (data, warnings) = fetch(&expr, start, end, users).await?;
+    let mut series: BTreeMap<Labels, Vec<(f64, f64)>> = BTreeMap::new();
+    // Counting points rather than adding up steps keeps rounding from adding or dropping one
+    let points = ((end - start) / step).floor() as u64;
+    for i in 0..=points {
+        let time = start + i as f64 * step;
+        match data.eval(&expr, time).map_err(invalid)? {
+            Value::Scalar(n) => series.entry(Labels::new()).or_default().push((time, n)),
+            Value::Vector(samples) => {
+                for s in samples {
+                    series.entry(s.labels).or_default().push((time, s.value));
+                }
+            }
+        }
+    }
+    let result = QueryResult::Matrix(
+        series
+            .into_iter()
+            .map(|(metric, values)| RangeSeries { metric, values })
+            .collect(),
+    );
+    Ok((result, warnings))
+}
+
+pub fn parse_step(value: Option<&str>) -> Result<f64, warp::Rejection> {
+    let value = value.ok_or_else(|| invalid("missing step parameter"))?;
+    value
+        .parse()
+        .ok()
+        .or_else(|| parse_duration(value))
+        .filter(|step: &f64| step.is_finite())
+        .ok_or_else(|| invalid(format!("invalid step \"{value}\"")))
+}
+
+pub async fn handler(
+    query: InstantQuery,
+    users: Users,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let time = parse_time("time", query.time.as_deref())?;
+    let (result, warnings) = instant(query.query.as_deref(), time, &users).await?;
+    Ok(warp::reply::json(&Response { result, warnings }))
+}
+
+pub async fn range_handler(
+    query: RangeQuery,
+    users: Users,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let start = parse_time("start", query.start.as_deref())?;
+    let end = parse_time("end", query.end.as_deref())?;
+    let step = parse_step(query.step.as_deref())?;
+    let (result, warnings) = range(query.query.as_deref(), start, end, step, &users).await?;
+    Ok(warp::reply::json(&Response { result, warnings }))
+}
@@ -0,0 +1,856 @@
+use regex::Regex;
+use std::fmt;
+
+/// A query that failed to parse, with the character offset the problem was found at.
+#[derive(Debug)]
+pub struct ParseError {
+    pub position: usize,
+    pub message: String,
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "parse error at char {}: {}",
+            self.position + 1,
+            self.message
+        )
+    }
+}
+
+type Result<T> = std::result::Result<T, ParseError>;
+
+// How deeply expressions may nest, which keeps parsing and evaluating them off the end of the stack
+const MAX_DEPTH: usize = 256;
+
+fn error<T>(position: usize, message: impl Into<String>) -> Result<T> {
+    Err(ParseError {
+        position,
+        message: message.into(),
+    })
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MatchOp {
+    Equal,
+    NotEqual,
+    Regex,
+    NotRegex,
+}
+
+#[derive(Clone, Debug)]
+pub struct Matcher {
+    pub label: String,
+    pub op: MatchOp,
+    pub value: String,
+    regex: Option<Regex>,
+}
+
+impl Matcher {
+    pub fn new(label: &str, op: MatchOp, value: &str) -> std::result::Result<Self, regex::Error> {
+        // Like PromQL, regular expressions have to match the whole value
+        let regex = match op {
+            MatchOp::Regex | MatchOp::NotRegex => Some(Regex::new(&format!("^(?:{value})$"))?),
+            MatchOp::Equal | MatchOp::NotEqual => None,
+        };
+        Ok(Matcher {
+            label: label.to_string(),
+            op,
+            value: value.to_string(),
+            regex,
+        })
+    }
+
+    /// Whether the label value matches; a missing label counts as empty.
+    pub fn matches(&self, value: Option<&str>) -> bool {
+        let value = value.unwrap_or_default();
+        match (&self.op, &self.regex) {
+            (MatchOp::Equal, _) => value == self.value,
+            (MatchOp::NotEqual, _) => value != self.value,
+            (MatchOp::Regex, Some(re)) => re.is_match(value),
+            (MatchOp::NotRegex, Some(re)) => !re.is_match(value),
+            _ => false,
+        }
+    }
+}
+
+/// Selects series by metric name and labels, e.g. `cpu_usage{role="web"}`.
+#[derive(Clone, Debug)]
+pub struct Selector {
+    pub matchers: Vec<Matcher>,
+}
+
+impl fmt::Display for Selector {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let matchers: Vec<String> = self
+            .matchers
+            .iter()
+            .map(|m| {
+                let op = match m.op {
+                    MatchOp::Equal => "=",
+                    MatchOp::NotEqual => "!=",
+                    MatchOp::Regex => "=~",
+                    MatchOp::NotRegex => "!~",
+                };
+                format!("{}{op}{:?}", m.label, m.value)
+            })
+            .collect();
+        write!(f, "{{{}}}", matchers.join(","))
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Function {
+    Rate,
+    AvgOverTime,
+    MaxOverTime,
+    MinOverTime,
+    SumOverTime,
+    CountOverTime,
+}
+
+impl Function {
+    fn from_name(name: &str) -> Option<Self> {
+        Some(match name {
+            "rate" => Function::Rate,
+            "avg_over_time" => Function::AvgOverTime,
+            "max_over_time" => Function::MaxOverTime,
+            "min_over_time" => Function::MinOverTime,
+            "sum_over_time" => Function::SumOverTime,
+            "count_over_time" => Function::CountOverTime,
+            _ => return None,
+        })
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AggregateOp {
+    Sum,
+    Avg,
+    Min,
+    Max,
+    Count,
+}
+
+impl AggregateOp {
+    fn from_name(name: &str) -> Option<Self> {
+        Some(match name {
+            "sum" => AggregateOp::Sum,
+            "avg" => AggregateOp::Avg,
+            "min" => AggregateOp::Min,
+            "max" => AggregateOp::Max,
+            "count" => AggregateOp::Count,
+            _ => return None,
+        })
+    }
+}
+
+/// Which labels an aggregation keeps, or which labels vectors are matched on.
+#[derive(Clone, Debug)]
+pub enum Grouping {
+    By(Vec<String>),
+    Without(Vec<String>),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BinaryOp {
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Mod,
+    Pow,
+}
+
+impl BinaryOp {
+    pub fn apply(self, lhs: f64, rhs: f64) -> f64 {
+        match self {
+            BinaryOp::Add => lhs + rhs,
+            BinaryOp::Sub => lhs - rhs,
+            BinaryOp::Mul => lhs * rhs,
+            BinaryOp::Div => lhs / rhs,
+            BinaryOp::Mod => lhs % rhs,
+            BinaryOp::Pow => lhs.powf(rhs),
+        }
+    }
+
+    fn precedence(self) -> u8 {
+        match self {
+            BinaryOp::Add | BinaryOp::Sub => 1,
+            BinaryOp::Mul | BinaryOp::Div | BinaryOp::Mod => 2,
+            BinaryOp::Pow => 3,
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub enum Expr {
+    Number(f64),
+    /// The latest sample of each selected series
+    Selector(Selector),
+    /// Every sample of each selected series within the range, in seconds
+    Range(Selector, f64),
+    Call(Function, Box<Expr>),
+    Aggregate(AggregateOp, Option<Grouping>, Box<Expr>),
+    /// `matching` is set by `on(...)` or `ignoring(...)`
+    Binary(BinaryOp, Box<Expr>, Box<Expr>, Option<Grouping>),
+    Negate(Box<Expr>),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Kind {
+    Scalar,
+    Vector,
+    Range,
+}
+
+impl Expr {
+    fn kind(&self) -> Kind {
+        match self {
+            Expr::Number(_) => Kind::Scalar,
+            Expr::Range(..) => Kind::Range,
+            Expr::Negate(e) => e.kind(),
+            Expr::Binary(_, lhs, rhs, _) => {
+                if lhs.kind() == Kind::Scalar && rhs.kind() == Kind::Scalar {
+                    Kind::Scalar
+                } else {
+                    Kind::Vector
+                }
+            }
+            Expr::Selector(_) | Expr::Call(..) | Expr::Aggregate(..) => Kind::Vector,
+        }
+    }
+
+    /// Every selector in the expression, with how far back before the
+    /// evaluation time it needs samples for.
+    pub fn selectors(&self, lookback: f64) -> Vec<(&Selector, f64)> {
+        match self {
+            Expr::Number(_) => Vec::new(),
+            Expr::Selector(s) => vec![(s, lookback)],
+            Expr::Range(s, range) => vec![(s, *range)],
+            Expr::Call(_, e) | Expr::Aggregate(_, _, e) | Expr::Negate(e) => e.selectors(lookback),
+            Expr::Binary(_, lhs, rhs, _) => {
+                let mut selectors = lhs.selectors(lookback);
+                selectors.extend(rhs.selectors(lookback));
+                selectors
+            }
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Token {
+    Ident(String),
+    Number(f64),
+    Duration(f64),
+    Str(String),
+    LBrace,
+    RBrace,
+    LParen,
+    RParen,
+    LBracket,
+    RBracket,
+    Comma,
+    Eq,
+    NotEq,
+    RegexEq,
+    NotRegexEq,
+    Op(BinaryOp),
+    Eof,
+}
+
+fn describe(token: &Token) -> String {
+    match token {
+        Token::Ident(name) => format!("identifier \"{name}\""),
+        Token::Number(n) => format!("number {n}"),
+        Token::Duration(_) => "duration".to_string(),
+        Token::Str(s) => format!("string {s:?}"),
+        Token::Eof => "end of input".to_string(),
+        other => format!("{other:?}"),
+    }
+}
+
+/// Parses a duration such as `5m` or `1h30m` into seconds.
+pub fn parse_duration(text: &str) -> Option<f64> {
+    let mut total = 0.0;
+    let mut rest = text;
+    if rest.is_empty() {
+        return None;
+    }
+    while !rest.is_empty() {
+        let digits = rest
+            .find(|c: char| !c.is_ascii_digit())
+            .unwrap_or(rest.len());
+        if digits == 0 {
+            return None;
+        }
+        let value: f64 = rest[..digits].parse().ok()?;
+        rest = &rest[digits..];
+        let unit = rest
+            .find(|c: char| c.is_ascii_digit())
+            .unwrap_or(rest.len());
+        let seconds = match &rest[..unit] {
+            "ms" => 0.001,
+            "s" => 1.0,
+            "m" => 60.0,
+            "h" => 3600.0,
+            "d" => 86400.0,
+            "w" => 604_800.0,
+            _ => return None,
+        };
+        total += value * seconds;
+        rest = &rest[unit..];
+    }
+    Some(total)
+}
+
+fn tokenize(input: &str) -> Result<Vec<(usize, Token)>> {
+    let bytes = input.as_bytes();
+    let mut tokens = Vec::new();
+    let mut i = 0;
+    while i < bytes.len() {
+        let c = bytes[i];
+        let start = i;
+        if c.is_ascii_whitespace() {
+            i += 1;
+            continue;
+        }
+        let token = match c {
+            b'{' => Token::LBrace,
+            b'}' => Token::RBrace,
+            b'(' => Token::LParen,
+            b')' => Token::RParen,
+            b'[' => Token::LBracket,
+            b']' => Token::RBracket,
+            b',' => Token::Comma,
+            b'+' => Token::Op(BinaryOp::Add),
+            b'-' => Token::Op(BinaryOp::Sub),
+            b'*' => Token::Op(BinaryOp::Mul),
+            b'/' => Token::Op(BinaryOp::Div),
+            b'%' => Token::Op(BinaryOp::Mod),
+            b'^' => Token::Op(BinaryOp::Pow),
+            b'=' if bytes.get(i + 1) == Some(&b'~') => {
+                i += 1;
+                Token::RegexEq
+            }
+            b'=' => Token::Eq,
+            b'!' if bytes.get(i + 1) == Some(&b'=') => {
+                i += 1;
+                Token::NotEq
+            }
+            b'!' if bytes.get(i + 1) == Some(&b'~') => {
+                i += 1;
+                Token::NotRegexEq
+            }
+            b'"' | b'\'' => {
+                let mut value = String::new();
+                let mut chars = input[i + 1..].char_indices();
+                loop {
+                    match chars.next() {
+                        Some((offset, ch)) if ch as u32 == u32::from(c) => {
+                            i += offset + 1;
+                            break;
+                        }
+                        Some((_, '\\')) => match chars.next() {
+                            Some((_, 'n')) => value.push('\n'),
+                            Some((_, 't')) => value.push('\t'),
+                            Some((_, ch)) => value.push(ch),
+                            None => return error(start, "unterminated string"),
+                        },
+                        Some((_, ch)) => value.push(ch),
+                        None => return error(start, "unterminated string"),
+                    }
+                }
+                Token::Str(value)
+            }
+            b'0'..=b'9' | b'.' => {
+                let end = input[i..]
+                    .find(|ch: char| !(ch.is_ascii_alphanumeric() || ch == '.'))
+                    .map_or(input.len(), |n| i + n);
+                let text = &input[i..end];
+                i = end - 1;
+                if let Ok(n) = text.parse() {
+                    Token::Number(n)
+                } else if let Some(d) = parse_duration(text) {
+                    Token::Duration(d)
+                } else {
+                    return error(start, format!("invalid number or duration \"{text}\""));
+                }
+            }
+            c if c.is_ascii_alphabetic() || c == b'_' || c == b':' => {
+                let end = input[i..]
+                    .find(|ch: char| !(ch.is_ascii_alphanumeric() || ch == '_' || ch == ':'))
+                    .map_or(input.len(), |n| i + n);
+                let name = input[i..end].to_string();
+                i = end - 1;
+                Token::Ident(name)
+            }
+            _ => {
+                let ch = input[i..].chars().next().unwrap_or_default();
+                return error(start, format!("unexpected character '{ch}'"));
+            }
+        };
+        tokens.push((start, token));
+        i += 1;
+    }
+    tokens.push((input.len(), Token::Eof));
+    Ok(tokens)
+}
+
+struct Parser {
+    tokens: Vec<(usize, Token)>,
+    next: usize,
+    /// How deeply the expression being parsed is nested
+    depth: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> &Token {
+        &self.tokens[self.next].1
+    }
+
+    fn position(&self) -> usize {
+        self.tokens[self.next].0
+    }
+
+    fn advance(&mut self) -> Token {
+        let token = self.tokens[self.next].1.clone();
+        if token != Token::Eof {
+            self.next += 1;
+        }
+        token
+    }
+
+    fn expect(&mut self, expected: &Token, what: &str) -> Result<()> {
+        if self.peek() == expected {
+            self.advance();
+            Ok(())
+        } else {
+            error(
+                self.position(),
+                format!("expected {what}, found {}", describe(self.peek())),
+            )
+        }
+    }
+
+    /// Goes one level deeper into the expression.
+    fn nest(&mut self) -> Result<()> {
+        self.depth += 1;
+        if self.depth > MAX_DEPTH {
+            return error(
+                self.position(),
+                format!("expression nested more than {MAX_DEPTH} levels deep"),
+            );
+        }
+        Ok(())
+    }
+
+    fn expr(&mut self, min_precedence: u8) -> Result<Expr> {
+        let depth = self.depth;
+        let expr = self.binary(min_precedence);
+        self.depth = depth;
+        expr
+    }
+
+    fn binary(&mut self, min_precedence: u8) -> Result<Expr> {
+        self.nest()?;
+        let mut lhs = self.unary()?;
+        loop {
+            let Token::Op(op) = *self.peek() else {
+                return Ok(lhs);
+            };
+            if op.precedence() < min_precedence {
+                return Ok(lhs);
+            }
+            let position = self.position();
+            self.advance();
+            let matching = self.matching()?;
+            // `^` is right associative, everything else left associative
+            let next = if op == BinaryOp::Pow {
+                op.precedence()
+            } else {
+                op.precedence() + 1
+            };
+            let rhs = self.expr(next)?;
+            for side in [&lhs, &rhs] {
+                if side.kind() == Kind::Range {
+                    return error(position, "range vectors can't be used in arithmetic");
+                }
+            }
+            if matching.is_some() && (lhs.kind() == Kind::Scalar || rhs.kind() == Kind::Scalar) {
+                return error(position, "vector matching only applies between two vectors");
+            }
+            lhs = Expr::Binary(op, Box::new(lhs), Box::new(rhs), matching);
+            // Each operator in a chain like `a + b + c` nests the ones before it
+            self.nest()?;
+        }
+    }
+
+    fn matching(&mut self) -> Result<Option<Grouping>> {
+        match self.peek() {
+            Token::Ident(name) if name == "on" => {
+                self.advance();
+                Ok(Some(Grouping::By(self.labels()?)))
+            }
+            Token::Ident(name) if name == "ignoring" => {
+                self.advance();
+                Ok(Some(Grouping::Without(self.labels()?)))
+            }
+            _ => Ok(None),
+        }
+    }
+
+    fn unary(&mut self) -> Result<Expr> {
+        match self.peek() {
+            Token::Op(BinaryOp::Sub) => {
+                let position = self.position();
+                self.advance();
+                // Binds tighter than everything but `^`, so -2^2 is -4
+                let expr = self.expr(BinaryOp::Pow.precedence())?;
+                if expr.kind() == Kind::Range {
+                    return error(position, "range vectors can't be negated");
+                }
+                Ok(Expr::Negate(Box::new(expr)))
+            }
+            Token::Op(BinaryOp::Add) => {
+                self.advance();
+                self.expr(BinaryOp::Pow.precedence())
+            }
+            _ => self.primary(),
+        }
+    }
+
+    fn primary(&mut self) -> Result<Expr> {
+        let position = self.position();
+        match self.advance() {
+            Token::Number(n) => Ok(Expr::Number(n)),
+            Token::LParen => {
+                let expr = self.expr(0)?;
+                self.expect(&Token::RParen, "\")\"")?;
+                Ok(expr)
+            }
+            Token::LBrace => {
+                let selector = self.matchers(Vec::new(), position)?;
+                self.range(selector)
+            }
+            Token::Ident(name) => {
+                if let Some(op) = AggregateOp::from_name(&name) {
+                    if matches!(self.peek(), Token::LParen | Token::Ident(_)) {
+                        return self.aggregate(op);
+                    }
+                }
+                if let Some(function) = Function::from_name(&name) {
+                    if *self.peek() == Token::LParen {
+                        return self.call(function, &name);
+                    }
+                }
+                if *self.peek() == Token::LParen {
+                    return error(position, format!("unknown function \"{name}\""));
+                }
+                let name_matcher = Matcher::new("__name__", MatchOp::Equal, &name)
+                    .expect("Equality matchers don't compile a regex");
+                let selector = if *self.peek() == Token::LBrace {
+                    let position = self.position();
+                    self.advance();
+                    self.matchers(vec![name_matcher], position)?
+                } else {
+                    Selector {
+                        matchers: vec![name_matcher],
+                    }
+                };
+                self.range(selector)
+            }
+            token => error(
+                position,
+                format!("expected an expression, found {}", describe(&token)),
+            ),
+        }
+    }
+
+    /// Parses label matchers up to the closing brace.
+    fn matchers(&mut self, mut matchers: Vec<Matcher>, open: usize) -> Result<Selector> {
+        loop {
+            let position = self.position();
+            let label = match self.advance() {
+                Token::RBrace => break,
+                Token::Ident(label) => label,
+                token => {
+                    return error(
+                        position,
+                        format!("expected a label name, found {}", describe(&token)),
+                    )
+                }
+            };
+            let position = self.position();
+            let op = match self.advance() {
+                Token::Eq => MatchOp::Equal,
+                Token::NotEq => MatchOp::NotEqual,
+                Token::RegexEq => MatchOp::Regex,
+                Token::NotRegexEq => MatchOp::NotRegex,
+                token => {
+                    return error(
+                        position,
+                        format!("expected a label matcher, found {}", describe(&token)),
+                    )
+                }
+            };
+            let position = self.position();
+            let Token::Str(value) = self.advance() else {
+                return error(position, "expected a quoted label value");
+            };
+            let matcher = Matcher::new(&label, op, &value)
+                .or_else(|e| error(position, format!("invalid regular expression: {e}")))?;
+            matchers.push(matcher);
+            match self.advance() {
+                Token::Comma => {}
+                Token::RBrace => break,
+                token => {
+                    return error(
+                        self.tokens[self.next - 1].0,
+                        format!("expected \",\" or \"}}\", found {}", describe(&token)),
+                    )
+                }
+            }
+        }
+        // Like PromQL, refuse selectors that could match every series
+        if !matchers.iter().any(|m| !m.matches(None)) {
+            return error(
+                open,
+                "a selector needs at least one matcher that doesn't match empty labels",
+            );
+        }
+        Ok(Selector { matchers })
+    }
+
+    fn range(&mut self, selector: Selector) -> Result<Expr> {
+        if *self.peek() != Token::LBracket {
+            return Ok(Expr::Selector(selector));
+        }
+        self.advance();
+        let position = self.position();
+        let seconds = match self.advance() {
+            Token::Duration(d) => d,
+            token => {
+                return error(
+                    position,
+                    format!("expected a duration such as 5m, found {}", describe(&token)),
+                )
+            }
+        };
+        self.expect(&Token::RBracket, "\"]\"")?;
+        Ok(Expr::Range(selector, seconds))
+    }
+
+    fn call(&mut self, function: Function, name: &str) -> Result<Expr> {
+        self.expect(&Token::LParen, "\"(\"")?;
+        let position = self.position();
+        let arg = self.expr(0)?;
+        if arg.kind() != Kind::Range {
+            return error(
+                position,
+                format!("{name}() expects a range vector such as cpu_usage[5m]"),
+            );
+        }
+        self.expect(&Token::RParen, "\")\"")?;
+        Ok(Expr::Call(function, Box::new(arg)))
+    }
+
+    fn labels(&mut self) -> Result<Vec<String>> {
+        self.expect(&Token::LParen, "\"(\"")?;
+        let mut labels = Vec::new();
+        loop {
+            let position = self.position();
+            match self.advance() {
+                Token::RParen => break,
+                Token::Ident(label) => labels.push(label),
+                token => {
+                    return error(
+                        position,
+                        format!("expected a label name, found {}", describe(&token)),
+                    )
+                }
+            }
+            match self.advance() {
+                Token::Comma => {}
+                Token::RParen => break,
+                token => {
+                    return error(
+                        self.tokens[self.next - 1].0,
+                        format!("expected \",\" or \")\", found {}", describe(&token)),
+                    )
+                }
+            }
+        }
+        Ok(labels)
+    }
+
+    fn grouping(&mut self) -> Result<Option<Grouping>> {
+        match self.peek() {
+            Token::Ident(name) if name == "by" => {
+                self.advance();
+                Ok(Some(Grouping::By(self.labels()?)))
+            }
+            Token::Ident(name) if name == "without" => {
+                self.advance();
+                Ok(Some(Grouping::Without(self.labels()?)))
+            }
+            _ => Ok(None),
+        }
+    }
+
+    /// Parses `sum by (label) (expr)` or `sum (expr) by (label)`.
+    fn aggregate(&mut self, op: AggregateOp) -> Result<Expr> {
+        let mut grouping = self.grouping()?;
+        self.expect(&Token::LParen, "\"(\"")?;
+        let position = self.position();
+        let arg = self.expr(0)?;
+        if arg.kind() != Kind::Vector {
+            return error(position, "aggregations expect an instant vector");
+        }
+        self.expect(&Token::RParen, "\")\"")?;
+        if grouping.is_none() {
+            grouping = self.grouping()?;
+        }
+        Ok(Expr::Aggregate(op, grouping, Box::new(arg)))
+    }
+}
+
+/// Parses a lone selector, such as the `match[]` parameters of the series API.
+pub fn parse_selector(input: &str) -> Result<Selector> {
+    match parse(input)? {
+        Expr::Selector(selector) => Ok(selector),
+        _ => error(
+            0,
+            "expected a series selector such as cpu_usage{role=\"web\"}",
+        ),
+    }
+}
+
+/// Parses a query into an expression that evaluates to a scalar or an instant vector.
+pub fn parse(input: &str) -> Result<Expr> {
+    // Tokens are found by byte offset, but errors point at characters
+    parse_expr(input).map_err(|e| ParseError {
+        position: input
+            .get(..e.position)
+            .map_or(e.position, |s| s.chars().count()),
+        message: e.message,
+    })
+}
+
+fn parse_expr(input: &str) -> Result<Expr> {
+    let mut parser = Parser {
+        tokens: tokenize(input)?,
+        next: 0,
+        depth: 0,
+    };
+    let expr = parser.expr(0)?;
+    if *parser.peek() != Token::Eof {
+        return error(
+            parser.position(),
+            format!("unexpected {}", describe(parser.peek())),
+        );
+    }
+    if expr.kind() == Kind::Range {
+        return error(
+            0,
+            "a query can't return a range vector, wrap it in a function such as avg_over_time()",
+        );
+    }
+    Ok(expr)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn selector(query: &str) -> Selector {
+        parse_selector(query).unwrap()
+    }
+
+    #[test]
+    fn matchers_apply_their_operator() {
+        let s = selector(r#"cpu_usage{role="web",zone!="b",host=~"web-.*",env!~"dev|test"}"#);
+        let ops: Vec<_> = s
+            .matchers
+            .iter()
+            .map(|m| (m.label.as_str(), m.op))
+            .collect();
+        assert_eq!(
+            ops,
+            [
+                ("__name__", MatchOp::Equal),
+                ("role", MatchOp::Equal),
+                ("zone", MatchOp::NotEqual),
+                ("host", MatchOp::Regex),
+                ("env", MatchOp::NotRegex),
+            ]
+        );
+        let [name, role, zone, host, env] = &s.matchers[..] else {
+            unreachable!()
+        };
+        assert!(name.matches(Some("cpu_usage")) && !name.matches(Some("cpu")));
+        assert!(role.matches(Some("web")) && !role.matches(None));
+        assert!(zone.matches(Some("a")) && zone.matches(None) && !zone.matches(Some("b")));
+        // Regular expressions are anchored at both ends
+        assert!(host.matches(Some("web-1")) && !host.matches(Some("old-web-1")));
+        assert!(env.matches(Some("prod")) && env.matches(None) && !env.matches(Some("dev")));
+    }
+
+    #[test]
+    fn errors_point_at_the_problem() {
+        for (query, position, message) in [
+            ("rate(cpu[5m]", 12, "expected \")\", found end of input"),
+            ("cpu +", 5, "expected an expression, found end of input"),
+            ("cpu 1", 4, "unexpected number 1"),
+            ("nope(cpu[5m])", 0, "unknown function \"nope\""),
+            ("cpu[5]", 4, "expected a duration such as 5m"),
+            ("{role=~\".*\"}", 0, "needs at least one matcher"),
+            ("cpu[5m]", 0, "can't return a range vector"),
+            (
+                "cpu[5m] + 1",
+                8,
+                "range vectors can't be used in arithmetic",
+            ),
+            (
+                "cpu + on(host) 1",
+                4,
+                "vector matching only applies between two vectors",
+            ),
+            ("rate(cpu)", 5, "rate() expects a range vector"),
+            ("sum(cpu[5m])", 4, "aggregations expect an instant vector"),
+            ("cpu{role=\"web}", 9, "unterminated string"),
+            ("cpu # 1", 4, "unexpected character '#'"),
+            // Columns count characters, not bytes
+            ("cpu{role=\"wéb\"} $", 16, "unexpected character '$'"),
+        ] {
+            let e = parse(query).unwrap_err();
+            assert_eq!(e.position, position, "{query}: {e}");
+            assert!(e.message.contains(message), "{query}: {e}");
+            assert!(e
+                .to_string()
+                .starts_with(&format!("parse error at char {}: ", position + 1)));
+        }
+    }
+
+    #[test]
+    fn deep_nesting_is_an_error() {
+        for query in [
+            format!("{}1{}", "(".repeat(300_000), ")".repeat(300_000)),
+            format!("{}1", "-".repeat(300_000)),
+            format!("1{}", "+1".repeat(300_000)),
+            format!("1{}", "^1".repeat(300_000)),
+            format!("{}up{}", "sum(".repeat(300_000), ")".repeat(300_000)),
+        ] {
+            let e = parse(&query).unwrap_err();
+            assert!(e.message.contains("nested"), "{e}");
+        }
+    }
+
+    #[test]
+    fn nesting_up_to_the_limit_parses() {
+        let depth = MAX_DEPTH / 2;
+        assert!(parse(&format!("{}1{}", "(".repeat(depth), ")".repeat(depth))).is_ok());
+        assert!(parse(&format!("1{}", "+1".repeat(depth))).is_ok());
+    }
+}
@@ -0,0 +1,558 @@
+use crate::config::{Options, RegistryBackend};
+use crate::db::Error;
+use crate::proxy;
+use crate::utils;
+use crate::websocket_server::Users;
+use futures_util::future::join_all;
+use once_cell::sync::Lazy;
+use r2d2::Pool;
+use r2d2_sqlite::SqliteConnectionManager;
+use rusqlite::{params, OptionalExtension};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use tokio::runtime::Handle;
+use tokio::task;
+use tokio::time::{self, Duration};
+use tokio_util::sync::CancellationToken;
+
+const HEARTBEAT_INTERVAL: u64 = 10;
+// Instances that haven't sent a heartbeat for this long are treated as gone
+const INSTANCE_TTL: i64 = 30;
+// How long to wait for a peer to say what it holds
+const PEER_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// A client connected to another hub instance.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct RemoteClient {
+    pub id: usize,
+    pub address: String,
+    pub name: Option<String>,
+    pub labels: BTreeMap<String, String>,
+}
+
+/// Keeps track of which hub instance holds each client connection and each
+/// pending proxy request, so that any instance can route to them.
+pub trait Registry: Send + Sync {
+    /// Allocates an id, unique across instances, for a new connection to this instance.
+    fn register_client(&self, address: &str) -> Result<usize, Error>;
+    /// Records the name and labels a client introduced itself with.
+    fn identify_client(
+        &self,
+        id: usize,
+        name: &str,
+        labels: &BTreeMap<String, String>,
+    ) -> Result<(), Error>;
+    fn unregister_client(&self, id: usize) -> Result<(), Error>;
+    /// The URL of the other live instance holding the client, if any.
+    fn locate_client(&self, id: usize) -> Result<Option<String>, Error>;
+    /// Clients connected to the other live instances.
+    fn remote_clients(&self) -> Result<Vec<RemoteClient>, Error>;
+    fn register_request(&self, request_id: &str) -> Result<(), Error>;
+    fn unregister_request(&self, request_id: &str) -> Result<(), Error>;
+    /// The URL of the other live instance waiting on the request, if any.
+    fn locate_request(&self, request_id: &str) -> Result<Option<String>, Error>;
+    /// Marks this instance as alive and forgets instances that aren't.
+    fn heartbeat(&self) -> Result<(), Error>;
+    /// Forgets this instance and everything registered by it.
+    fn leave(&self) -> Result<(), Error>;
+}
+
+/// A single hub instance, which only routes to its own clients.
+#[derive(Default)]
+pub struct LocalRegistry {
+    next_id: AtomicUsize,
+}
+
+impl Registry for LocalRegistry {
+    fn register_client(&self, _address: &str) -> Result<usize, Error> {
+        Ok(self.next_id.fetch_add(1, Ordering::Relaxed) + 1)
+    }
+
+    fn identify_client(
+        &self,
+        _id: usize,
+        _name: &str,
+        _labels: &BTreeMap<String, String>,
+    ) -> Result<(), Error> {
+        Ok(())
+    }
+
+    fn unregister_client(&self, _id: usize) -> Result<(), Error> {
+        Ok(())
+    }
+
+    fn locate_client(&self, _id: usize) -> Result<Option<String>, Error> {
+        Ok(None)
+    }
+
+    fn remote_clients(&self) -> Result<Vec<RemoteClient>, Error> {
+        Ok(Vec::new())
+    }
+
+    fn register_request(&self, _request_id: &str) -> Result<(), Error> {
+        Ok(())
+    }
+
+    fn unregister_request(&self, _request_id: &str) -> Result<(), Error> {
+        Ok(())
+    }
+
+    fn locate_request(&self, _request_id: &str) -> Result<Option<String>, Error> {
+        Ok(None)
+    }
+
+    fn heartbeat(&self) -> Result<(), Error> {
+        Ok(())
+    }
+
+    fn leave(&self) -> Result<(), Error> {
+        Ok(())
+    }
+}
+
+/// A SQLite database shared by every hub instance on the same disk.
+pub struct SqliteRegistry {
+    pool: Pool<SqliteConnectionManager>,
+    instance_url: String,
+    /// What this instance has registered, which it writes again with every
+    /// heartbeat in case others took it for gone and removed it
+    clients: Mutex<BTreeMap<usize, RemoteClient>>,
+    requests: Mutex<BTreeSet<String>>,
+}
+
+impl SqliteRegistry {
+    pub fn new(path: &str, instance_url: String) -> Result<Self, Error> {
+        // Other instances may be holding the lock, so wait for it rather than failing
+        let manager = SqliteConnectionManager::file(path)
+            .with_init(|c| c.busy_timeout(std::time::Duration::from_secs(5)));
+        let pool = Pool::new(manager)?;
+        let conn = pool.get()?;
+        conn.execute(
+            "CREATE TABLE IF NOT EXISTS registry_instances (
+                url         TEXT PRIMARY KEY,
+                last_seen   INTEGER NOT NULL
+            )",
+            [],
+        )?;
+        conn.execute(
+            "CREATE TABLE IF NOT EXISTS registry_clients (
+                id          INTEGER PRIMARY KEY AUTOINCREMENT,
+                instance    TEXT NOT NULL,
+                address     TEXT NOT NULL,
+                name        TEXT,
+                labels      TEXT
+            )",
+            [],
+        )?;
+        conn.execute(
+            "CREATE TABLE IF NOT EXISTS registry_requests (
+                request_id  TEXT PRIMARY KEY,
+                instance    TEXT NOT NULL
+            )",
+            [],
+        )?;
+        // Anything left over from a previous run of this instance is stale
+        conn.execute(
+            "DELETE FROM registry_clients WHERE instance = ?1",
+            params![instance_url],
+        )?;
+        conn.execute(
+            "DELETE FROM registry_requests WHERE instance = ?1",
+            params![instance_url],
+        )?;
+        drop(conn);
+        let registry = SqliteRegistry {
+            pool,
+            instance_url,
+            clients: Mutex::default(),
+            requests: Mutex::default(),
+        };
+        registry.heartbeat()?;
+        Ok(registry)
+    }
+
+    fn live_since() -> i64 {
+        utils::unix_timestamp() - INSTANCE_TTL
+    }
+}
+
+impl Registry for SqliteRegistry {
+    fn register_client(&self, address: &str) -> Result<usize, Error> {
+        let conn = self.pool.get()?;
+        conn.execute(
+            "INSERT INTO registry_clients (instance, address) VALUES (?1, ?2)",
+            params![self.instance_url, address],
+        )?;
+        let id = usize::try_from(conn.last_insert_rowid()).unwrap_or_default();
+        self.clients.lock().unwrap().insert(
+            id,
+            RemoteClient {
+                id,
+                address: address.to_string(),
+                name: None,
+                labels: BTreeMap::new(),
+            },
+        );
+        Ok(id)
+    }
+
+    fn identify_client(
+        &self,
+        id: usize,
+        name: &str,
+        labels: &BTreeMap<String, String>,
+    ) -> Result<(), Error> {
+        let conn = self.pool.get()?;
+        let labels_json = serde_json::to_string(labels).unwrap_or_default();
+        conn.execute(
+            "UPDATE registry_clients SET name = ?1, labels = ?2 WHERE id = ?3",
+            params![name, labels_json, id],
+        )?;
+        if let Some(client) = self.clients.lock().unwrap().get_mut(&id) {
+            client.name = Some(name.to_string());
+            client.labels.clone_from(labels);
+        }
+        Ok(())
+    }
+
+    fn unregister_client(&self, id: usize) -> Result<(), Error> {
+        let conn = self.pool.get()?;
+        self.clients.lock().unwrap().remove(&id);
+        conn.execute("DELETE FROM registry_clients WHERE id = ?1", params![id])?;
+        Ok(())
+    }
+
+    fn locate_client(&self, id: usize) -> Result<Option<String>, Error> {
+        let conn = self.pool.get()?;
+        let url = conn
+            .query_row(
+                "SELECT c.instance FROM registry_clients c
+                 JOIN registry_instances i ON i.url = c.instance
+                 WHERE c.id = ?1 AND c.instance != ?2 AND i.last_seen >= ?3",
+                params![id, self.instance_url, Self::live_since()],
+                |row| row.get(0),
+            )
+            .optional()?;
+        Ok(url)
+    }
+
+    fn remote_clients(&self) -> Result<Vec<RemoteClient>, Error> {
+        let conn = self.pool.get()?;
+        let mut stmt = conn.prepare(
+            "SELECT c.id, c.address, c.name, c.labels FROM registry_clients c
+             JOIN registry_instances i ON i.url = c.instance
+             WHERE c.instance != ?1 AND i.last_seen >= ?2
+             ORDER BY c.id",
+        )?;
+        let clients = stmt
+            .query_map(params![self.instance_url, Self::live_since()], |row| {
+                Ok(RemoteClient {
+                    id: row.get(0)?,
+                    address: row.get(1)?,
+                    name: row.get(2)?,
+                    labels: row
+                        .get::<_, Option<String>>(3)?
+                        .and_then(|l| serde_json::from_str(&l).ok())
+                        .unwrap_or_default(),
+                })
+            })?
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(clients)
+    }
+
+    fn register_request(&self, request_id: &str) -> Result<(), Error> {
+        let conn = self.pool.get()?;
+        self.requests.lock().unwrap().insert(request_id.to_string());
+        conn.execute(
+            "INSERT INTO registry_requests (request_id, instance) VALUES (?1, ?2)",
+            params![request_id, self.instance_url],
+        )?;
+        Ok(())
+    }
+
+    fn unregister_request(&self, request_id: &str) -> Result<(), Error> {
+        let conn = self.pool.get()?;
+        self.requests.lock().unwrap().remove(request_id);
+        conn.execute(
+            "DELETE FROM registry_requests WHERE request_id = ?1",
+            params![request_id],
+        )?;
+        Ok(())
+    }
+
+    fn locate_request(&self, request_id: &str) -> Result<Option<String>, Error> {
+        let conn = self.pool.get()?;
+        let url = conn
+            .query_row(
+                "SELECT instance FROM registry_requests
+                 WHERE request_id = ?1 AND instance != ?2",
+                params![request_id, self.instance_url],
+                |row| row.get(0),
+            )
+            .optional()?;
+        Ok(url)
+    }
+
+    fn heartbeat(&self) -> Result<(), Error> {
+        let conn = self.pool.get()?;
+        conn.execute(
+            "INSERT INTO registry_instances (url, last_seen) VALUES (?1, ?2)
+             ON CONFLICT(url) DO UPDATE SET last_seen = excluded.last_seen",
+            params![self.instance_url, utils::unix_timestamp()],
+        )?;
+        // Ids are never handed out twice, so this instance's can safely be put back
+        let clients: Vec<RemoteClient> = self.clients.lock().unwrap().values().cloned().collect();
+        for client in clients {
+            conn.execute(
+                "INSERT OR REPLACE INTO registry_clients (id, instance, address, name, labels)
+                 VALUES (?1, ?2, ?3, ?4, ?5)",
+                params![
+                    client.id,
+                    self.instance_url,
+                    client.address,
+                    client.name,
+                    serde_json::to_string(&client.labels).unwrap_or_default(),
+                ],
+            )?;
+        }
+        let requests: Vec<String> = self.requests.lock().unwrap().iter().cloned().collect();
+        for request_id in requests {
+            conn.execute(
+                "INSERT OR REPLACE INTO registry_requests (request_id, instance) VALUES (?1, ?2)",
+                params![request_id, self.instance_url],
+            )?;
+        }
+        let since = Self::live_since();
+        for table in ["registry_clients", "registry_requests"] {
+            conn.execute(
+                &format!(
+                    "DELETE FROM {table} WHERE instance NOT IN
+                     (SELECT url FROM registry_instances WHERE last_seen >= ?1)"
+                ),
+                params![since],
+            )?;
+        }
+        conn.execute(
+            "DELETE FROM registry_instances WHERE last_seen < ?1",
+            params![since],
+        )?;
+        Ok(())
+    }
+
+    fn leave(&self) -> Result<(), Error> {
+        let conn = self.pool.get()?;
+        for table in ["registry_clients", "registry_requests"] {
+            conn.execute(
+                &format!("DELETE FROM {table} WHERE instance = ?1"),
+                params![self.instance_url],
+            )?;
+        }
+        conn.execute(
+            "DELETE FROM registry_instances WHERE url = ?1",
+            params![self.instance_url],
+        )?;
+        Ok(())
+    }
+}
+
+/// Hub instances that know each other's URLs and ask each other what they
+/// hold. Every instance lists the same peers, so the position of its own URL
+/// lets it hand out ids no other will: the `n`th of `N` instances numbers its
+/// clients `n`, `n + N`, `n + 2N`... counting from 1, and any instance can
+/// tell from an id alone which one holds the client.
+pub struct PeerRegistry {
+    peers: Vec<String>,
+    position: usize,
+    next: AtomicUsize,
+    client: reqwest::Client,
+}
+
+impl PeerRegistry {
+    pub fn new(peers: Vec<String>, instance_url: &str) -> Result<Self, String> {
+        let position = peers
+            .iter()
+            .position(|p| p == instance_url)
+            .ok_or_else(|| format!("HUB_INSTANCE_URL {instance_url} isn't one of HUB_PEERS"))?;
+        Ok(PeerRegistry {
+            peers,
+            position,
+            next: AtomicUsize::new(0),
+            client: reqwest::Client::new(),
+        })
+    }
+
+    /// Asks every other instance for `path`, each answer being `None` if it
+    /// couldn't be reached or doesn't have what was asked for. Registry calls
+    /// run on a blocking thread of the runtime, which can wait on it.
+    fn ask_peers<T: serde::de::DeserializeOwned>(&self, path: &str) -> Vec<Option<T>> {
+        let others = self
+            .peers
+            .iter()
+            .enumerate()
+            .filter(|&(i, _)| i != self.position);
+        let requests = others.map(|(_, peer)| async move {
+            let response = self
+                .client
+                .get(format!("{peer}{path}"))
+                .timeout(PEER_TIMEOUT)
+                .send()
+                .await;
+            match response {
+                Ok(response) if response.status() == reqwest::StatusCode::NOT_FOUND => None,
+                Ok(response) => match response.error_for_status() {
+                    Ok(response) => response.json().await.ok(),
+                    Err(e) => {
+                        eprintln!("Error asking hub instance {peer}: {e}");
+                        None
+                    }
+                },
+                Err(e) => {
+                    eprintln!("Error asking hub instance {peer}: {e}");
+                    None
+                }
+            }
+        });
+        Handle::current().block_on(join_all(requests))
+    }
+}
+
+impl Registry for PeerRegistry {
+    fn register_client(&self, _address: &str) -> Result<usize, Error> {
+        let n = self.next.fetch_add(1, Ordering::Relaxed);
+        Ok(n * self.peers.len() + self.position + 1)
+    }
+
+    fn identify_client(
+        &self,
+        _id: usize,
+        _name: &str,
+        _labels: &BTreeMap<String, String>,
+    ) -> Result<(), Error> {
+        Ok(())
+    }
+
+    fn unregister_client(&self, _id: usize) -> Result<(), Error> {
+        Ok(())
+    }
+
+    fn locate_client(&self, id: usize) -> Result<Option<String>, Error> {
+        let holder = id.checked_sub(1).map(|i| i % self.peers.len());
+        Ok(holder
+            .filter(|&i| i != self.position)
+            .map(|i| self.peers[i].clone()))
+    }
+
+    fn remote_clients(&self) -> Result<Vec<RemoteClient>, Error> {
+        let clients: Vec<Option<Vec<RemoteClient>>> = self.ask_peers("/api/registry/clients");
+        Ok(clients.into_iter().flatten().flatten().collect())
+    }
+
+    // Pending requests are only kept by the instance waiting on them, which
+    // its peers ask
+    fn register_request(&self, _request_id: &str) -> Result<(), Error> {
+        Ok(())
+    }
+
+    fn unregister_request(&self, _request_id: &str) -> Result<(), Error> {
+        Ok(())
+    }
+
+    fn locate_request(&self, request_id: &str) -> Result<Option<String>, Error> {
+        let path = format!("/api/registry/requests/{request_id}");
+        let others = self
+            .peers
+            .iter()
+            .enumerate()
+            .filter(|&(i, _)| i != self.position);
+        let waiting: Vec<Option<bool>> = self.ask_peers(&path);
+        Ok(others
+            .zip(waiting)
+            .find(|(_, waiting)| waiting.is_some())
+            .map(|((_, peer), _)| peer.clone()))
+    }
+
+    // Peers are asked as they're needed, so there's nothing to keep alive
+    fn heartbeat(&self) -> Result<(), Error> {
+        Ok(())
+    }
+
+    fn leave(&self) -> Result<(), Error> {
+        Ok(())
+    }
+}
+
+pub static REGISTRY: Lazy<Box<dyn Registry>> = Lazy::new(|| {
+    let config = Options::new();
+    match config.registry.backend {
+        RegistryBackend::Local => Box::<LocalRegistry>::default(),
+        RegistryBackend::Sqlite => Box::new(
+            SqliteRegistry::new(&config.registry.path, config.registry.instance_url)
+                .expect("Failed to open hub registry"),
+        ),
+        RegistryBackend::Peers => Box::new(
+            PeerRegistry::new(config.registry.peers, &config.registry.instance_url)
+                .expect("Invalid hub peers"),
+        ),
+    }
+});
+
+/// Makes a registry call on the blocking pool, as a shared registry can keep
+/// us waiting on its lock or on other instances.
+pub async fn call<T: Send + 'static>(
+    call: impl FnOnce(&dyn Registry) -> Result<T, Error> + Send + 'static,
+) -> Result<T, String> {
+    match task::spawn_blocking(move || call(&**REGISTRY)).await {
+        Ok(result) => result.map_err(|e| e.to_string()),
+        Err(e) => Err(e.to_string()),
+    }
+}
+
+/// Lists this instance's clients for its peers.
+pub async fn clients_handler(users: Users) -> Result<impl warp::Reply, warp::Rejection> {
+    let clients: Vec<RemoteClient> = users
+        .read()
+        .await
+        .iter()
+        .filter(|(_, client)| !client.hub)
+        .map(|(&id, client)| RemoteClient {
+            id,
+            address: client.address(),
+            name: client.name.clone(),
+            labels: client.labels.clone(),
+        })
+        .collect();
+    Ok(warp::reply::json(&clients))
+}
+
+/// Tells a peer whether this instance is waiting on a proxy request.
+pub async fn request_handler(request_id: String) -> Result<impl warp::Reply, warp::Rejection> {
+    if proxy::is_pending(&request_id).await {
+        Ok(warp::reply::json(&true))
+    } else {
+        Err(warp::reject::not_found())
+    }
+}
+
+/// Keeps this instance's registration alive until `stopped` is cancelled, once
+/// the web server has stopped, then removes it. Until then peers keep routing
+/// client responses here, which proxy requests still draining are waiting on.
+pub async fn run(stopped: CancellationToken) {
+    let mut interval = time::interval(Duration::from_secs(HEARTBEAT_INTERVAL));
+
+    loop {
+        tokio::select! {
+            _ = interval.tick() => {},
+            () = stopped.cancelled() => break,
+        }
+        if let Err(e) = call(|registry| registry.heartbeat()).await {
+            eprintln!("Error updating hub registry: {e}");
+        }
+    }
+
+    if let Err(e) = call(|registry| registry.leave()).await {
+        eprintln!("Error leaving hub registry: {e}");
+    }
+    println!("Registry loop exiting");
+}
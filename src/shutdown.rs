@@ -0,0 +1,110 @@
+use std::collections::BTreeMap;
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+use tokio::time::{self, Duration};
+use tokio_util::sync::CancellationToken;
+use tokio_util::task::TaskTracker;
+
+/// Coordinates shutdown of the program's long running tasks. Tasks are spawned
+/// through the coordinator and watch a shared cancellation token; once it is
+/// cancelled the coordinator waits up to a drain deadline for them to finish.
+#[derive(Clone, Default)]
+pub struct Shutdown {
+    token: CancellationToken,
+    tracker: TaskTracker,
+    /// How many tasks of each name are running
+    running: Arc<Mutex<BTreeMap<String, usize>>>,
+}
+
+impl Shutdown {
+    pub fn new() -> Self {
+        Shutdown::default()
+    }
+
+    /// A token that is cancelled when shutdown begins.
+    pub fn token(&self) -> CancellationToken {
+        self.token.clone()
+    }
+
+    /// Begins shutdown.
+    pub fn trigger(&self) {
+        self.token.cancel();
+    }
+
+    /// Spawns a task that is waited on during shutdown.
+    pub fn spawn<F>(&self, name: impl Into<String>, task: F)
+    where
+        F: Future<Output = ()> + Send + 'static,
+    {
+        let name = name.into();
+        *self
+            .running
+            .lock()
+            .unwrap()
+            .entry(name.clone())
+            .or_default() += 1;
+        let running = self.running.clone();
+        self.tracker.spawn(async move {
+            task.await;
+            let mut running = running.lock().unwrap();
+            if let Some(count) = running.get_mut(&name) {
+                *count -= 1;
+                if *count == 0 {
+                    running.remove(&name);
+                }
+            }
+        });
+    }
+
+    /// Begins shutdown on Ctrl+C or SIGTERM. Kubernetes sends SIGTERM when stopping a pod.
+    pub async fn listen_for_signals(self) {
+        tokio::select! {
+            result = tokio::signal::ctrl_c() => {
+                result.expect("Failed to listen for Ctrl+C");
+                println!("Received Ctrl+C, initiating graceful shutdown...");
+            }
+            () = terminate() => {
+                println!("Received SIGTERM, initiating graceful shutdown...");
+            }
+            () = self.token.cancelled() => return,
+        }
+        self.trigger();
+    }
+
+    /// Waits for shutdown to begin, then for every task to finish. Returns
+    /// false if any task was still running when the deadline passed.
+    pub async fn drain(&self, deadline: Duration) -> bool {
+        self.token.cancelled().await;
+        self.tracker.close();
+        if time::timeout(deadline, self.tracker.wait()).await.is_ok() {
+            return true;
+        }
+        let running = self.running.lock().unwrap();
+        let names: Vec<&str> = running.keys().map(String::as_str).collect();
+        eprintln!(
+            "Tasks failed to stop within {} seconds: {}",
+            deadline.as_secs(),
+            names.join(", ")
+        );
+        false
+    }
+}
+
+#[cfg(unix)]
+async fn terminate() {
+    use tokio::signal::unix::{signal, SignalKind};
+    match signal(SignalKind::terminate()) {
+        Ok(mut sigterm) => {
+            sigterm.recv().await;
+        }
+        Err(e) => {
+            eprintln!("Failed to listen for SIGTERM: {e}");
+            std::future::pending::<()>().await;
+        }
+    }
+}
+
+#[cfg(not(unix))]
+async fn terminate() {
+    std::future::pending::<()>().await;
+}
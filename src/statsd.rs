@@ -0,0 +1,322 @@
+use crate::collectors::sample;
+use crate::config::{Options, StatsdProps};
+use crate::db::{insert_samples, Sample};
+use crate::line_protocol::metric_name;
+use crate::utils;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::net::IpAddr;
+use tokio::net::UdpSocket;
+use tokio::time;
+use tokio_util::sync::CancellationToken;
+
+type Labels = BTreeMap<String, String>;
+type Key = (String, Labels);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Kind {
+    Counter,
+    Gauge,
+    /// Durations in milliseconds
+    Timer,
+    /// Histograms and distributions, in whatever unit they were sent in
+    Histogram,
+    Set,
+}
+
+/// One line of a StatsD packet, e.g. `api.requests:1|c|@0.5|#route:/users,method:get`.
+struct Line<'a> {
+    name: String,
+    value: &'a str,
+    kind: Kind,
+    /// The share of events that were sent, so each counts for `1 / rate`
+    rate: f64,
+    tags: Labels,
+}
+
+fn parse_line(line: &str) -> Result<Line<'_>, String> {
+    let mut sections = line.split('|');
+    let (name, value) = sections
+        .next()
+        .and_then(|s| s.split_once(':'))
+        .ok_or("expected name:value")?;
+    if name.is_empty() {
+        return Err("missing name".to_string());
+    }
+    let kind = match sections.next() {
+        Some("c") => Kind::Counter,
+        Some("g") => Kind::Gauge,
+        Some("ms") => Kind::Timer,
+        Some("h" | "d") => Kind::Histogram,
+        Some("s") => Kind::Set,
+        Some(other) => return Err(format!("unknown type \"{other}\"")),
+        None => return Err("missing type".to_string()),
+    };
+    let mut rate = 1.0;
+    let mut tags = Labels::new();
+    // Other sections, such as DogStatsD's container ids, don't matter here
+    for section in sections {
+        if let Some(r) = section.strip_prefix('@') {
+            rate = r
+                .parse()
+                .ok()
+                .filter(|r| *r > 0.0 && *r <= 1.0)
+                .ok_or_else(|| format!("invalid sample rate \"{r}\""))?;
+        } else if let Some(list) = section.strip_prefix('#') {
+            // Tags without a value can't be told apart by label, so are left out
+            tags.extend(
+                list.split(',')
+                    .filter_map(|tag| tag.split_once(':'))
+                    .map(|(k, v)| (metric_name(k), v.to_string())),
+            );
+        }
+    }
+    Ok(Line {
+        name: metric_name(name),
+        value,
+        kind,
+        rate,
+        tags,
+    })
+}
+
+fn parse_value(value: &str) -> Result<f64, String> {
+    value
+        .parse()
+        .ok()
+        .filter(|v: &f64| v.is_finite())
+        .ok_or_else(|| format!("invalid value \"{value}\""))
+}
+
+/// The value at the given percentile of sorted values, by nearest rank.
+fn percentile(sorted: &[f64], p: f64) -> f64 {
+    let rank = (p / 100.0 * sorted.len() as f64).ceil() as usize;
+    sorted[rank.clamp(1, sorted.len()) - 1]
+}
+
+#[derive(Default)]
+struct Timing {
+    values: Vec<f64>,
+    /// Weighted by sample rate
+    count: f64,
+    sum: f64,
+}
+
+// Counters and gauges that haven't been sent for this many flushes are dropped,
+// so that tags with ever-changing values don't keep their series around for good
+const IDLE_FLUSHES: u32 = 30;
+
+/// A counter's or gauge's value, and how many flushes ago it was last sent.
+#[derive(Default)]
+struct Series {
+    value: f64,
+    idle: u32,
+}
+
+/// Aggregates what was received between flushes. Where StatsD resets counters
+/// every flush, these keep adding up, to be recorded as `_total`s that are read
+/// with `rate`, and gauges keep their last value, until either goes unsent for
+/// `IDLE_FLUSHES` flushes. Timers and sets start over every flush.
+#[derive(Default)]
+struct Aggregator {
+    counters: HashMap<Key, Series>,
+    gauges: HashMap<Key, Series>,
+    timings: HashMap<Key, Timing>,
+    sets: HashMap<Key, HashSet<String>>,
+    lines: u64,
+    invalid: u64,
+}
+
+impl Aggregator {
+    fn add(&mut self, line: &str) -> Result<(), String> {
+        let line = parse_line(line)?;
+        let key = (line.name, line.tags);
+        match line.kind {
+            Kind::Counter => {
+                let value = parse_value(line.value)?;
+                let counter = self.counters.entry(key).or_default();
+                counter.value += value / line.rate;
+                counter.idle = 0;
+            }
+            Kind::Gauge => {
+                let value = parse_value(line.value)?;
+                let gauge = self.gauges.entry(key).or_default();
+                // A sign makes the value a change to the gauge rather than its new value
+                if line.value.starts_with(['+', '-']) {
+                    gauge.value += value;
+                } else {
+                    gauge.value = value;
+                }
+                gauge.idle = 0;
+            }
+            Kind::Timer | Kind::Histogram => {
+                let mut value = parse_value(line.value)?;
+                let (mut name, labels) = key;
+                if line.kind == Kind::Timer {
+                    name.push_str("_seconds");
+                    value /= 1000.0;
+                }
+                let timing = self.timings.entry((name, labels)).or_default();
+                timing.values.push(value);
+                timing.count += 1.0 / line.rate;
+                timing.sum += value / line.rate;
+            }
+            Kind::Set => {
+                self.sets
+                    .entry(key)
+                    .or_default()
+                    .insert(line.value.to_string());
+            }
+        }
+        Ok(())
+    }
+
+    fn add_packet(&mut self, packet: &str) {
+        for line in packet.lines().map(str::trim).filter(|l| !l.is_empty()) {
+            self.lines += 1;
+            if let Err(e) = self.add(line) {
+                self.invalid += 1;
+                eprintln!("Ignoring StatsD line \"{line}\": {e}");
+            }
+        }
+    }
+
+    /// The samples for this flush interval, starting the next one.
+    fn flush(&mut self, percentiles: &[f64]) -> Vec<Sample> {
+        let mut samples = Vec::new();
+        let mut push = |name: &str, labels: &Labels, value: f64| {
+            samples.push(Sample {
+                metric: name.to_string(),
+                labels: labels.clone(),
+                value,
+            });
+        };
+        for ((name, labels), counter) in &self.counters {
+            push(&format!("{name}_total"), labels, counter.value);
+        }
+        for ((name, labels), gauge) in &self.gauges {
+            push(name, labels, gauge.value);
+        }
+        for series in [&mut self.counters, &mut self.gauges] {
+            series.retain(|_, s| {
+                s.idle += 1;
+                s.idle < IDLE_FLUSHES
+            });
+        }
+        for ((name, labels), mut timing) in self.timings.drain() {
+            timing.values.sort_by(f64::total_cmp);
+            for p in percentiles {
+                let mut labels = labels.clone();
+                labels.insert("quantile".to_string(), (p / 100.0).to_string());
+                push(&name, &labels, percentile(&timing.values, *p));
+            }
+            push(&format!("{name}_count"), &labels, timing.count);
+            push(&format!("{name}_sum"), &labels, timing.sum);
+        }
+        for ((name, labels), values) in self.sets.drain() {
+            push(&name, &labels, values.len() as f64);
+        }
+        if self.lines > 0 {
+            samples.push(sample("statsd_lines_total", &[], self.lines as f64));
+            samples.push(sample(
+                "statsd_invalid_lines_total",
+                &[],
+                self.invalid as f64,
+            ));
+        }
+        samples
+    }
+}
+
+fn record(samples: &[Sample]) {
+    if samples.is_empty() {
+        return;
+    }
+    if let Err(e) = insert_samples(utils::unix_timestamp(), samples) {
+        eprintln!("Error inserting StatsD samples: {e}");
+    }
+}
+
+/// Listens for StatsD metrics on UDP, recording them every flush interval until shutdown.
+pub async fn run(port: u16, shutdown: CancellationToken) {
+    let config = Options::new();
+    let StatsdProps {
+        flush_interval,
+        percentiles,
+        ..
+    } = config.statsd;
+    let host: IpAddr = config.host.parse().expect("Failed to parse");
+    let socket = match UdpSocket::bind((host, port)).await {
+        Ok(socket) => socket,
+        Err(e) => {
+            eprintln!("Error binding StatsD listener to {host}:{port}: {e}");
+            return;
+        }
+    };
+    println!("StatsD listener is listening on: udp://{host}:{port}");
+
+    let mut aggregator = Aggregator::default();
+    let mut flush = time::interval(flush_interval);
+    flush.tick().await;
+    // Large enough for any UDP datagram
+    let mut buf = vec![0; 65_535];
+    loop {
+        tokio::select! {
+            received = socket.recv_from(&mut buf) => match received {
+                Ok((len, _)) => aggregator.add_packet(&String::from_utf8_lossy(&buf[..len])),
+                Err(e) => eprintln!("Error receiving StatsD packet: {e}"),
+            },
+            _ = flush.tick() => record(&aggregator.flush(&percentiles)),
+            () = shutdown.cancelled() => break,
+        }
+    }
+
+    // Keep what arrived since the last flush
+    record(&aggregator.flush(&percentiles));
+    println!("StatsD listener exiting");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn id(sample: &Sample) -> Option<&str> {
+        sample.labels.get("id").map(String::as_str)
+    }
+
+    fn value(samples: &[Sample], metric: &str) -> Option<f64> {
+        samples.iter().find(|s| s.metric == metric).map(|s| s.value)
+    }
+
+    #[test]
+    fn counters_add_up_across_flushes() {
+        let mut aggregator = Aggregator::default();
+        aggregator.add_packet("jobs:2|c\njobs:1|c|@0.5");
+        assert_eq!(value(&aggregator.flush(&[]), "jobs_total"), Some(4.0));
+        aggregator.add_packet("jobs:1|c");
+        assert_eq!(value(&aggregator.flush(&[]), "jobs_total"), Some(5.0));
+        assert_eq!(value(&aggregator.flush(&[]), "jobs_total"), Some(5.0));
+    }
+
+    #[test]
+    fn idle_series_are_dropped() {
+        let mut aggregator = Aggregator::default();
+        aggregator.add_packet("jobs:1|c|#id:a\nqueue:3|g|#id:a");
+        for _ in 0..IDLE_FLUSHES {
+            aggregator.add_packet("jobs:1|c|#id:b\nqueue:3|g|#id:b");
+            let samples = aggregator.flush(&[]);
+            assert_eq!(samples.iter().filter(|s| id(s) == Some("a")).count(), 2);
+        }
+        aggregator.add_packet("jobs:1|c|#id:b\nqueue:3|g|#id:b");
+        let samples = aggregator.flush(&[]);
+        assert!(samples.iter().all(|s| id(s) != Some("a")));
+        assert_eq!(aggregator.counters.len(), 1);
+        assert_eq!(aggregator.gauges.len(), 1);
+        // Sent again, a dropped counter starts over
+        aggregator.add_packet("jobs:1|c|#id:a");
+        let samples = aggregator.flush(&[]);
+        let again = samples
+            .iter()
+            .find(|s| s.metric == "jobs_total" && id(s) == Some("a"));
+        assert_eq!(again.map(|s| s.value), Some(1.0));
+    }
+}
@@ -1,11 +1,34 @@
-use std::sync::{
-    atomic::{AtomicBool, Ordering},
-    Arc,
-};
-use tokio::time::{sleep, Duration};
-
-pub async fn wait_for_running_to_be_false(running: Arc<AtomicBool>) {
-    while running.load(Ordering::SeqCst) {
-        sleep(Duration::from_millis(100)).await;
-    }
+use std::time::SystemTime;
+
+/// Seconds since the unix epoch.
+pub fn unix_timestamp() -> i64 {
+    let secs = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .expect("Time went backwards")
+        .as_secs();
+    i64::try_from(secs).unwrap_or_else(|_| panic!("Timestamp is too large to fit in an i64"))
+}
+
+/// Formats a unix timestamp as an RFC 3339 UTC string, e.g. `2024-11-02T13:04:05Z`.
+pub fn to_rfc3339(timestamp: i64) -> String {
+    let days = timestamp.div_euclid(86400);
+    let secs = timestamp.rem_euclid(86400);
+
+    // Civil date from days since epoch (Howard Hinnant's algorithm)
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+
+    format!(
+        "{year:04}-{month:02}-{day:02}T{:02}:{:02}:{:02}Z",
+        secs / 3600,
+        secs % 3600 / 60,
+        secs % 60
+    )
 }
@@ -1,20 +1,28 @@
+use crate::alerts;
 use crate::clients;
 use crate::config::Options;
+use crate::db::DatabaseError;
+use crate::events;
+use crate::fleet;
+use crate::line_protocol::{self, WRITE_LIMIT};
+use crate::logs;
+use crate::otlp;
+use crate::prometheus;
+use crate::protocol::ProxyParams;
 use crate::proxy;
 use crate::proxy::client_response_handler;
-use crate::proxy::{ParseError, RequestIdNotFound};
-use crate::websocket_server::user_connected;
+use crate::proxy::{
+    ClientTimeout, Draining, NotConnected, ParseError, PeerError, RequestIdNotFound,
+};
+use crate::query::{self, QueryError};
+use crate::registry;
 use crate::websocket_server::Users;
+use crate::websocket_server::{going_away, user_connected};
 use serde::Serialize;
 use std::error::Error;
 use std::net::SocketAddr;
-use std::{
-    net::{IpAddr, Ipv4Addr},
-    sync::{
-        atomic::{AtomicBool, Ordering},
-        Arc,
-    },
-};
+use std::net::{IpAddr, Ipv4Addr};
+use tokio_util::sync::CancellationToken;
 use warp::http::StatusCode;
 use warp::{Filter, Rejection, Reply};
 
@@ -30,12 +38,30 @@ async fn handle_rejection(err: Rejection) -> Result<impl Reply, std::convert::In
     if err.is_not_found() {
         code = StatusCode::NOT_FOUND;
         message = "NOT_FOUND";
+    } else if err.find::<NotConnected>().is_some() {
+        code = StatusCode::NOT_FOUND;
+        message = "The client is not connected.";
     } else if err.find::<ParseError>().is_some() {
         code = StatusCode::BAD_REQUEST;
         message = "Invalid ID Format";
     } else if err.find::<RequestIdNotFound>().is_some() {
         code = StatusCode::BAD_REQUEST;
         message = "The supplied request_id was not found.";
+    } else if err.find::<ClientTimeout>().is_some() {
+        code = StatusCode::GATEWAY_TIMEOUT;
+        message = "The client did not respond in time.";
+    } else if err.find::<Draining>().is_some() {
+        code = StatusCode::SERVICE_UNAVAILABLE;
+        message = "The hub is shutting down.";
+    } else if err.find::<PeerError>().is_some() {
+        code = StatusCode::BAD_GATEWAY;
+        message = "The hub instance holding the client could not be reached.";
+    } else if let Some(QueryError(e)) = err.find() {
+        code = StatusCode::BAD_REQUEST;
+        message = e.as_str();
+    } else if err.find::<DatabaseError>().is_some() {
+        code = StatusCode::INTERNAL_SERVER_ERROR;
+        message = "DATABASE_ERROR";
     } else {
         eprintln!("unhandled rejection: {err:?}");
         code = StatusCode::INTERNAL_SERVER_ERROR;
@@ -49,7 +75,8 @@ async fn handle_rejection(err: Rejection) -> Result<impl Reply, std::convert::In
 }
 
 pub async fn run_server(
-    running: Arc<AtomicBool>,
+    users: Users,
+    shutdown: CancellationToken,
 ) -> Result<(), Box<dyn Error + Send + Sync + 'static>> {
     let config = Options::new();
     let log = warp::log::custom(|info| {
@@ -60,10 +87,19 @@ pub async fn run_server(
             info.elapsed()
         );
     });
-    let users = Users::default();
+    let connected = users.clone();
     let users = warp::any().map(move || users.clone());
     let proxy_route = warp::path!("api" / "proxy" / String)
         .and(warp::get())
+        .and(warp::query::<ProxyParams>())
+        .and(users.clone())
+        .and_then(proxy::handler);
+
+    // Another hub instance forwards writes in the body, as they are too large for a query string
+    let proxy_post_route = warp::path!("api" / "proxy" / String)
+        .and(warp::post())
+        .and(warp::body::content_length_limit(WRITE_LIMIT))
+        .and(warp::body::json())
         .and(users.clone())
         .and_then(proxy::handler);
 
@@ -72,11 +108,125 @@ pub async fn run_server(
         .and(warp::body::bytes())
         .and_then(client_response_handler);
 
+    let fleet_route = warp::path!("api" / "fleet")
+        .and(warp::get())
+        .and(warp::query::<fleet::FleetQuery>())
+        .and(users.clone())
+        .and_then(fleet::handler);
+
+    let query_route = warp::path!("api" / "query")
+        .and(warp::get())
+        .and(warp::query::<query::InstantQuery>())
+        .and(users.clone())
+        .and_then(query::handler);
+
+    let query_range_route = warp::path!("api" / "query_range")
+        .and(warp::get())
+        .and(warp::query::<query::RangeQuery>())
+        .and(users.clone())
+        .and_then(query::range_handler);
+
+    // The Prometheus API takes its parameters from the query string or a form body
+    let prometheus_params = warp::get()
+        .and(warp::query::raw().or(warp::any().map(String::new)).unify())
+        .map(|raw: String| prometheus::parse_params(raw.as_bytes()))
+        .or(warp::post()
+            .and(warp::body::content_length_limit(prometheus::PARAMS_LIMIT))
+            .and(warp::body::bytes())
+            .map(|body: warp::hyper::body::Bytes| prometheus::parse_params(&body)))
+        .unify();
+
+    let prometheus_query_route = warp::path!("api" / "v1" / "query")
+        .and(prometheus_params)
+        .and(users.clone())
+        .and_then(prometheus::query_handler);
+
+    let prometheus_query_range_route = warp::path!("api" / "v1" / "query_range")
+        .and(prometheus_params)
+        .and(users.clone())
+        .and_then(prometheus::query_range_handler);
+
+    let prometheus_series_route = warp::path!("api" / "v1" / "series")
+        .and(prometheus_params)
+        .and(users.clone())
+        .and_then(prometheus::series_handler);
+
+    let prometheus_labels_route = warp::path!("api" / "v1" / "labels")
+        .and(prometheus_params)
+        .and(users.clone())
+        .and_then(prometheus::labels_handler);
+
+    let prometheus_label_values_route = warp::path!("api" / "v1" / "label" / String / "values")
+        .and(prometheus_params)
+        .and(users.clone())
+        .and_then(prometheus::label_values_handler);
+
     let clients_route = warp::path!("api" / "clients")
         .and(warp::get())
         .and(users.clone())
         .and_then(clients::handler);
 
+    // What this instance holds, for its peers to route by
+    let registry_clients_route = warp::path!("api" / "registry" / "clients")
+        .and(warp::get())
+        .and(users.clone())
+        .and_then(registry::clients_handler);
+
+    let registry_request_route = warp::path!("api" / "registry" / "requests" / String)
+        .and(warp::get())
+        .and_then(registry::request_handler);
+
+    let delete_client_route = warp::path!("api" / "clients" / String)
+        .and(warp::delete())
+        .and_then(clients::delete_handler);
+
+    let events_route = warp::path!("api" / "events")
+        .and(warp::get())
+        .and(warp::query::<events::EventFilter>())
+        .and_then(events::handler);
+
+    let alerts_route = warp::path!("api" / "alerts")
+        .and(warp::get())
+        .and_then(alerts::handler);
+
+    let deliveries_route = warp::path!("api" / "alerts" / "deliveries")
+        .and(warp::get())
+        .and_then(alerts::deliveries_handler);
+
+    let post_alert_route = warp::path!("api" / "alerts")
+        .and(warp::post())
+        .and(warp::body::json())
+        .and_then(alerts::post_handler);
+
+    // InfluxDB's v1 and v2 write endpoints, so Telegraf can write through the hub
+    let write_route = warp::path!("write")
+        .or(warp::path!("api" / "v2" / "write"))
+        .unify()
+        .and(warp::post())
+        .and(warp::query::<line_protocol::WriteQuery>())
+        .and(warp::body::content_length_limit(WRITE_LIMIT))
+        .and(warp::body::bytes())
+        .and(users.clone())
+        .and_then(line_protocol::relay_handler);
+
+    // Follows a client's log lines as server-sent events
+    let token = shutdown.clone();
+    let logs_route = warp::path!("api" / "logs" / String)
+        .and(warp::get())
+        .and(warp::query::<ProxyParams>())
+        .and(users.clone())
+        .and(warp::any().map(move || token.clone()))
+        .and_then(logs::follow_handler);
+
+    // OpenTelemetry's OTLP/HTTP metrics export, in protobuf or JSON
+    let otlp_route = warp::path!("v1" / "metrics")
+        .and(warp::post())
+        .and(warp::header::optional::<String>("content-type"))
+        .and(warp::header::optional::<String>("content-encoding"))
+        .and(warp::body::content_length_limit(otlp::BODY_LIMIT))
+        .and(warp::body::bytes())
+        .and_then(otlp::handler);
+
     let ws_route = warp::path!("ws")
         .and(warp::ws())
         .and(warp::addr::remote())
@@ -88,8 +238,27 @@ pub async fn run_server(
     // Serve files from the "public" directory
     let static_route = warp::fs::dir("public").with(log);
     let routes = proxy_route
+        .or(proxy_post_route)
+        .or(write_route)
+        .or(otlp_route)
+        .or(logs_route)
         .or(clients_route)
+        .or(fleet_route)
+        .or(query_route)
+        .or(query_range_route)
+        .or(prometheus_query_route)
+        .or(prometheus_query_range_route)
+        .or(prometheus_series_route)
+        .or(prometheus_labels_route)
+        .or(prometheus_label_values_route)
+        .or(delete_client_route)
+        .or(registry_clients_route)
+        .or(registry_request_route)
         .or(response_route)
+        .or(events_route)
+        .or(alerts_route)
+        .or(deliveries_route)
+        .or(post_alert_route)
         .or(ws_route)
         .or(static_route)
         .recover(handle_rejection);
@@ -105,7 +274,7 @@ pub async fn run_server(
     // Bind the server with graceful shutdown, getting the address and the future
     let (addr, server_future) = warp::serve(routes).bind_with_graceful_shutdown(
         (host, config.http_server.port),
-        shutdown_signal(running.clone()),
+        shutdown_signal(connected, shutdown),
     );
 
     println!("Web server is listening on: http://{addr}");
@@ -116,9 +285,12 @@ pub async fn run_server(
     Ok(())
 }
 
-async fn shutdown_signal(running: Arc<AtomicBool>) {
-    while running.load(Ordering::SeqCst) {
-        tokio::time::sleep(tokio::time::Duration::from_secs(1)).await;
-    }
+async fn shutdown_signal(users: Users, shutdown: CancellationToken) {
+    shutdown.cancelled().await;
+    // Let in-flight proxy requests finish while clients are still connected,
+    // leaving the rest of the drain deadline for everything else
+    let config = Options::new();
+    proxy::drain(config.shutdown_drain / 2).await;
+    going_away(&users).await;
     println!("Shutting down static web server...");
 }
@@ -0,0 +1,201 @@
+use crate::alerts;
+use crate::collectors::{sample, Collector};
+use crate::config::WatchProps;
+use crate::db::Sample;
+use crate::protocol::WatchedProcess;
+use once_cell::sync::Lazy;
+use std::collections::{BTreeMap, HashSet};
+use std::error::Error;
+use std::sync::RwLock;
+use std::time::Duration;
+use sysinfo::{Pid, Process, ProcessRefreshKind, ProcessesToUpdate, System, UpdateKind};
+
+pub const PROCESS_DOWN: &str = "ProcessDown";
+
+pub const METRICS: &[(&str, &[&str])] = &[
+    ("watch_up", &["process"]),
+    ("watch_restarts", &["process"]),
+    ("watch_cpu_usage", &["process"]),
+    ("watch_memory_bytes", &["process"]),
+];
+
+// The latest state of every watched process, reported to the hub by the websocket client
+static WATCHED: Lazy<RwLock<Vec<WatchedProcess>>> = Lazy::new(|| RwLock::new(Vec::new()));
+
+pub fn watched() -> Vec<WatchedProcess> {
+    WATCHED.read().unwrap().clone()
+}
+
+fn alert_labels(client: &str, process: &str) -> BTreeMap<String, String> {
+    BTreeMap::from([
+        ("client".to_string(), client.to_string()),
+        ("process".to_string(), process.to_string()),
+    ])
+}
+
+/// Raises an alert for each watched process of the client that is down, and
+/// resolves those that are back up or are no longer watched. A client that
+/// disconnects no longer watches anything.
+pub async fn update_alerts(client: &str, previous: &[WatchedProcess], current: &[WatchedProcess]) {
+    for process in current {
+        let labels = alert_labels(client, &process.name);
+        if process.up {
+            alerts::resolve(PROCESS_DOWN, &labels).await;
+        } else {
+            let summary = format!("{} is not running on {client}", process.name);
+            alerts::fire(PROCESS_DOWN, labels, &summary).await;
+        }
+    }
+    for process in previous {
+        if !current.iter().any(|p| p.name == process.name) {
+            alerts::resolve(PROCESS_DOWN, &alert_labels(client, &process.name)).await;
+        }
+    }
+}
+
+fn refresh(sys: &mut System) {
+    let kind = ProcessRefreshKind::new()
+        .with_cpu()
+        .with_memory()
+        .with_exe(UpdateKind::OnlyIfNotSet);
+    sys.refresh_processes_specifics(ProcessesToUpdate::All, true, kind);
+}
+
+/// Whether the process goes by `name`, either as its own name or its executable's.
+fn is_named(process: &Process, name: &str) -> bool {
+    process.name() == name
+        || process
+            .exe()
+            .and_then(|exe| exe.file_name())
+            .is_some_and(|exe| exe == name)
+}
+
+/// The processes a watch entry covers: every process with the name, or the
+/// process whose pid is in the pid file.
+fn find<'a>(sys: &'a System, name: &str, pid_file: bool) -> Vec<&'a Process> {
+    if pid_file {
+        let pid = std::fs::read_to_string(name)
+            .ok()
+            .and_then(|s| s.trim().parse::<u32>().ok());
+        return pid
+            .and_then(|pid| sys.process(Pid::from_u32(pid)))
+            .into_iter()
+            .collect();
+    }
+    sys.processes()
+        .values()
+        .filter(|p| p.thread_kind().is_none() && is_named(p, name))
+        .collect()
+}
+
+/// Tracks the pid of a watched process across checks to count restarts.
+struct Tracker {
+    name: String,
+    pid_file: bool,
+    last_pid: Option<u32>,
+    restarts: u64,
+}
+
+impl Tracker {
+    fn check(&mut self, sys: &System) -> WatchedProcess {
+        let processes = find(sys, &self.name, self.pid_file);
+        // With several matches, e.g. a master and its workers, follow the oldest
+        let pid = processes
+            .iter()
+            .min_by_key(|p| (p.start_time(), p.pid()))
+            .map(|p| p.pid().as_u32());
+        if let Some(pid) = pid {
+            if self.last_pid.is_some_and(|last| last != pid) {
+                self.restarts += 1;
+            }
+            self.last_pid = Some(pid);
+        }
+        WatchedProcess {
+            name: self.name.clone(),
+            up: pid.is_some(),
+            pid,
+            restarts: self.restarts,
+            cpu_usage: processes.iter().fold(0.0, |sum, p| sum + p.cpu_usage()),
+            memory: processes.iter().map(|p| p.memory()).sum(),
+        }
+    }
+}
+
+fn samples(processes: &[WatchedProcess]) -> Vec<Sample> {
+    let mut samples = Vec::new();
+    for process in processes {
+        let labels = [("process", process.name.as_str())];
+        let values: [(&'static str, f64); 4] = [
+            ("watch_up", f64::from(u8::from(process.up))),
+            ("watch_restarts", process.restarts as f64),
+            ("watch_cpu_usage", f64::from(process.cpu_usage)),
+            ("watch_memory_bytes", process.memory as f64),
+        ];
+        for (metric, value) in values {
+            samples.push(sample(metric, &labels, value));
+        }
+    }
+    samples
+}
+
+/// Checks the configured processes, recording their state as metrics and
+/// keeping it ready to report to the hub.
+pub struct WatchCollector {
+    interval: Duration,
+    sys: System,
+    trackers: Vec<Tracker>,
+}
+
+impl WatchCollector {
+    /// Returns `None` when no processes are watched.
+    pub fn new(config: &WatchProps, interval: Duration) -> Option<Self> {
+        let mut trackers: Vec<Tracker> = config
+            .processes
+            .iter()
+            .map(|name| (name, false))
+            .chain(config.pid_files.iter().map(|path| (path, true)))
+            .map(|(name, pid_file)| Tracker {
+                name: name.clone(),
+                pid_file,
+                last_pid: None,
+                restarts: 0,
+            })
+            .collect();
+        if trackers.is_empty() {
+            return None;
+        }
+        // The same name may be listed twice; only watch it once
+        let mut seen = HashSet::new();
+        trackers.retain(|t| seen.insert(t.name.clone()));
+        Some(WatchCollector {
+            interval,
+            sys: System::new(),
+            trackers,
+        })
+    }
+}
+
+impl Collector for WatchCollector {
+    fn name(&self) -> &str {
+        "watch"
+    }
+
+    fn interval(&self) -> Duration {
+        self.interval
+    }
+
+    fn collect(&mut self) -> Result<Vec<Sample>, Box<dyn Error + Send + Sync>> {
+        refresh(&mut self.sys);
+        let processes: Vec<WatchedProcess> = self
+            .trackers
+            .iter_mut()
+            .map(|t| t.check(&self.sys))
+            .collect();
+        for process in processes.iter().filter(|p| !p.up) {
+            println!("Watched process {} is not running", process.name);
+        }
+        let samples = samples(&processes);
+        *WATCHED.write().unwrap() = processes;
+        Ok(samples)
+    }
+}
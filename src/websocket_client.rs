@@ -1,84 +1,238 @@
-use crate::config::Options;
-use crate::db::get_all_stats;
+use crate::backoff::BackoffPolicy;
+use crate::checks;
+use crate::clients;
+use crate::config::{HubMode, HubSelection, Options};
+use crate::db::{
+    get_all_stats, get_process_snapshot, get_samples_since, get_series_since, get_stats_since,
+};
+use crate::line_protocol;
+use crate::logs;
+use crate::protocol::{
+    CheckState, ClientEvent, HubEvent, ProxyFailure, ProxyParams, WatchedProcess,
+};
+use crate::proxy;
 use crate::utils;
-use futures_util::StreamExt;
+use crate::watch;
+use crate::websocket_server::Users;
+use futures_util::future::join_all;
+use futures_util::{SinkExt, StreamExt};
+use once_cell::sync::Lazy;
+use rand::seq::SliceRandom;
 use reqwest::Client;
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 use serde_json::from_str;
-use std::{
-    error::Error,
-    sync::{
-        atomic::{AtomicBool, Ordering},
-        Arc,
-    },
-};
-use tokio::time::{sleep, Duration};
+use std::collections::BTreeMap;
+use std::error::Error;
+use tokio::sync::RwLock;
+use tokio::time::{sleep, Duration, Instant};
 use tokio_tungstenite::{connect_async, tungstenite, tungstenite::protocol::Message};
+use tokio_util::sync::CancellationToken;
 
 #[derive(Debug, Deserialize)]
 struct ReqMsg {
     request_id: String,
+    #[serde(default)]
+    target: Option<String>,
+    #[serde(flatten)]
+    params: ProxyParams,
+}
+
+/// What is connecting to the hub: an agent answering with its own stats, or a
+/// downstream hub relaying requests to its clients.
+#[derive(Clone)]
+pub enum Role {
+    Agent,
+    Hub(Users),
+}
+
+const ADVERTISE_INTERVAL: u64 = 5;
+
+/// Where and when to reconnect, as asked for by a hub that is going away.
+struct Reconnect {
+    uri: Option<String>,
+    delay: Duration,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+enum HubState {
+    #[default]
+    Disconnected,
+    Connecting,
+    Connected,
+    Failed,
+}
+
+#[derive(Clone, Default, Serialize)]
+struct HubStatus {
+    state: HubState,
+    connected_since: Option<i64>,
+    failures: u64,
+    last_error: Option<String>,
+}
+
+static HUB_STATUS: Lazy<RwLock<BTreeMap<String, HubStatus>>> =
+    Lazy::new(|| RwLock::new(BTreeMap::new()));
+
+/// The proxy response endpoint of the hub at `ws_uri`. `HUB_PROXY_RESPONSE_URI`
+/// entries are matched to `HUB_WS_URI` entries by position; hubs without one,
+/// such as redirect targets, are assumed to serve it next to their websocket route.
+fn proxy_response_uri(config: &Options, ws_uri: &str) -> String {
+    let configured = config
+        .hub
+        .ws_uris
+        .iter()
+        .position(|u| u == ws_uri)
+        .and_then(|i| config.hub.proxy_response_uris.get(i));
+    if let Some(uri) = configured {
+        return uri.clone();
+    }
+    let base = ws_uri
+        .replacen("wss://", "https://", 1)
+        .replacen("ws://", "http://", 1);
+    let base = base.strip_suffix("/ws").unwrap_or(&base);
+    format!("{base}/api/proxy/response")
 }
 
 async fn sleep_until_interrupted(
     delay: Duration,
-    running: Arc<AtomicBool>,
+    shutdown: &CancellationToken,
 ) -> Result<(), Box<dyn Error + Send + Sync>> {
-    let mut elapsed = Duration::from_secs(0);
-    let interval = Duration::from_secs(1);
-
-    while elapsed < delay {
-        if !running.load(Ordering::SeqCst) {
-            return Err(Box::<dyn Error + Send + Sync>::from(
-                "Interrupted during sleep",
-            ));
+    tokio::select! {
+        () = sleep(delay) => Ok(()),
+        () = shutdown.cancelled() => Err(
+            Box::<dyn Error + Send + Sync>::from("Interrupted during sleep"),
+        ),
+    }
+}
+
+/// What an agent answers a proxy request with: a process snapshot, the series
+/// of a metric, or its overall CPU usage.
+fn agent_response(params: &ProxyParams) -> Result<serde_json::Value, Box<dyn Error + Send + Sync>> {
+    if let Some(at) = params.processes_at {
+        return Ok(serde_json::to_value(get_process_snapshot(at)?)?);
+    }
+    if let Some(lines) = &params.write {
+        let precision = params.precision.unwrap_or_default();
+        return Ok(serde_json::to_value(line_protocol::write(
+            lines, precision,
+        )?)?);
+    }
+    if params.series {
+        return Ok(serde_json::to_value(get_series_since(
+            params.since.unwrap_or_default(),
+        )?)?);
+    }
+    Ok(match (&params.metric, params.since) {
+        (Some(metric), since) => {
+            serde_json::to_value(get_samples_since(metric, since.unwrap_or_default())?)?
         }
-        sleep(interval).await;
-        elapsed += interval;
+        (None, Some(since)) => serde_json::to_value(get_stats_since(since)?)?,
+        (None, None) => serde_json::to_value(get_all_stats()?)?,
+    })
+}
+
+/// The response to a proxy request, or why there is none.
+async fn answer(
+    role: &Role,
+    target: Option<String>,
+    params: ProxyParams,
+) -> Result<serde_json::Value, &'static str> {
+    if let Role::Hub(users) = role {
+        let Some(target) = target else {
+            eprintln!("Relay request without a target");
+            return Err("error");
+        };
+        return proxy::request(&target, users, params).await.map_err(|e| {
+            let reason = proxy::failure_reason(&e);
+            eprintln!("Relay to {target} failed: {reason}");
+            reason
+        });
     }
+    // Log requests may wait for new lines, so are answered apart from the rest
+    let response = if params.logs {
+        serde_json::to_value(logs::query(&params).await).map_err(Into::into)
+    } else {
+        agent_response(&params)
+    };
+    response.map_err(|e| {
+        eprintln!("Error answering proxy request: {e}");
+        "error"
+    })
+}
 
+/// Answers a proxy request and posts the response back to the hub, or why
+/// there is none, so the hub isn't left waiting for it.
+async fn respond(
+    role: Role,
+    req: ReqMsg,
+    response_uri: String,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let response = match answer(&role, req.target, req.params).await {
+        Ok(response) => response,
+        Err(reason) => serde_json::to_value(ProxyFailure {
+            error: reason.to_string(),
+        })?,
+    };
+    let server_url = format!("{response_uri}/{}", req.request_id);
+    let res = Client::new()
+        .post(server_url)
+        .json(&response)
+        .send()
+        .await?;
+    println!("{}", res.text().await?);
     Ok(())
 }
 
 async fn handle_messages(
     mut read: impl StreamExt<Item = Result<Message, tungstenite::Error>> + Unpin,
-    running: Arc<AtomicBool>,
-) -> Result<(), Box<dyn Error + Send + Sync>> {
+    response_uri: String,
+    heartbeat_timeout: Duration,
+    role: Role,
+    shutdown: CancellationToken,
+) -> Result<Option<Reconnect>, Box<dyn Error + Send + Sync>> {
+    // The hub pings regularly, so silence means the connection is dead
+    let deadline = sleep(heartbeat_timeout);
+    tokio::pin!(deadline);
+    let mut reconnect = None;
     loop {
         tokio::select! {
             msg = read.next() => {
+                deadline.as_mut().reset(Instant::now() + heartbeat_timeout);
                 match msg {
                     Some(Ok(msg)) => match msg {
                         Message::Text(text) => {
                             println!("Received: {text}");
-                            let result = from_str::<ReqMsg>(&text).unwrap();
-                            let client = Client::new();
-                            match get_all_stats() {
-                                Ok(response) => {
-                                    let config = Options::new();
-                                    let server_url = format!(
-                                        "{}/{}",
-                                        config.hub.proxy_response_uri,
-                                        result.request_id,
-                                    );
-                                    let res = client
-                                        .post(server_url)
-                                        .json(&response)
-                                        .send()
-                                        .await?;
-                                    println!("{}", res.text().await?);
-                                },
-                                Err(_e) => {
-                                    eprintln!("An error occurred");
+                            if let Ok(event) = from_str::<HubEvent>(&text) {
+                                match event {
+                                    HubEvent::GoingAway { redirect_uri, reconnect_delay_ms } => {
+                                        println!("Hub is going away, reconnecting in {reconnect_delay_ms} ms");
+                                        reconnect = Some(Reconnect {
+                                            uri: redirect_uri,
+                                            delay: Duration::from_millis(reconnect_delay_ms),
+                                        });
+                                    }
                                 }
+                                continue;
                             }
+                            let Ok(result) = from_str::<ReqMsg>(&text) else {
+                                eprintln!("Ignoring unrecognized message");
+                                continue;
+                            };
+                            // Answer in the background so relayed requests don't hold up pings
+                            let response = respond(role.clone(), result, response_uri.clone());
+                            tokio::spawn(async move {
+                                if let Err(e) = response.await {
+                                    eprintln!("An error occurred: {e}");
+                                }
+                            });
                         },
                         Message::Binary(data) => println!("Received binary data: {data:?}"),
                         Message::Ping(_) => println!("Received ping"),
                         Message::Pong(_) => println!("Received pong"),
                         Message::Close(_) => {
                             println!("Server closed connection");
-                            return Ok(());
+                            return Ok(reconnect);
                         },
                         Message::Frame(_) => println!("Received raw frame"),
                     },
@@ -92,90 +246,403 @@ async fn handle_messages(
                     }
                 }
             }
-            () = utils::wait_for_running_to_be_false(running.clone()) => {
+            () = &mut deadline => {
+                eprintln!("No message from hub in {} seconds", heartbeat_timeout.as_secs());
+                return Err(Box::<dyn Error + Send + Sync>::from("Heartbeat timeout"));
+            }
+            () = shutdown.cancelled() => {
                 println!("Receive task interrupted");
                 break;
             }
         }
     }
-    Ok(())
+    Ok(reconnect)
 }
 
-// async fn send_test_message(
-//     write: &mut (impl SinkExt<Message> + Unpin),
-// ) -> Result<(), Box<dyn Error + Send + Sync>> {
-//     write
-//         .send(Message::Text("Hello, WebSocket Server!".to_string()))
-//         .await
-//         .map_err(|_| {
-//             println!("Error sending message");
-//             Box::<dyn Error + Send + Sync>::from("Error sending test message")
-//         })
-// }
+async fn send_hello(
+    write: &mut (impl SinkExt<Message> + Unpin),
+    config: &Options,
+    role: &Role,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let hello = match role {
+        Role::Agent => ClientEvent::Hello {
+            name: config.client_name.clone(),
+            token: config.auth_token.clone(),
+            hub: false,
+            labels: config.labels.clone(),
+        },
+        Role::Hub(_) => ClientEvent::Hello {
+            name: config.site.clone().unwrap_or(config.client_name.clone()),
+            token: config.auth_token.clone(),
+            hub: true,
+            labels: BTreeMap::new(),
+        },
+    };
+    write
+        .send(Message::Text(serde_json::to_string(&hello)?))
+        .await
+        .map_err(|_| {
+            println!("Error sending message");
+            Box::<dyn Error + Send + Sync>::from("Error sending hello message")
+        })
+}
 
-pub async fn connect_with_retry(
-    running: Arc<AtomicBool>,
+/// Tells the upstream hub about every client below this hub.
+async fn advertise_clients(
+    write: &mut (impl SinkExt<Message> + Unpin),
+    users: &Users,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let event = ClientEvent::Clients {
+        clients: clients::list(users).await?,
+    };
+    write
+        .send(Message::Text(serde_json::to_string(&event)?))
+        .await
+        .map_err(|_| Box::<dyn Error + Send + Sync>::from("Error sending client list"))
+}
+
+/// Tells the hub the state of the processes this agent watches.
+async fn report_watched(
+    write: &mut (impl SinkExt<Message> + Unpin),
+    processes: &[WatchedProcess],
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let event = ClientEvent::Watched {
+        processes: processes.to_vec(),
+    };
+    write
+        .send(Message::Text(serde_json::to_string(&event)?))
+        .await
+        .map_err(|_| Box::<dyn Error + Send + Sync>::from("Error sending watched processes"))
+}
+
+/// Tells the hub how the checks this agent runs last went.
+async fn report_checks(
+    write: &mut (impl SinkExt<Message> + Unpin),
+    checks: &[CheckState],
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let event = ClientEvent::Checks {
+        checks: checks.to_vec(),
+    };
+    write
+        .send(Message::Text(serde_json::to_string(&event)?))
+        .await
+        .map_err(|_| Box::<dyn Error + Send + Sync>::from("Error sending check states"))
+}
+
+/// Candidate hubs for the next round of connection attempts.
+fn hub_order(uris: &[String], selection: HubSelection) -> Vec<String> {
+    let mut order = uris.to_vec();
+    if selection == HubSelection::Random {
+        order.shuffle(&mut rand::thread_rng());
+    }
+    order
+}
+
+async fn set_status(url: &str, state: HubState, error: Option<String>) {
+    let mut statuses = HUB_STATUS.write().await;
+    let status = statuses.entry(url.to_string()).or_default();
+    if state == HubState::Connected {
+        status.connected_since = Some(utils::unix_timestamp());
+    } else if state != HubState::Connecting {
+        status.connected_since = None;
+    }
+    if state == HubState::Failed {
+        status.failures += 1;
+    }
+    if error.is_some() {
+        status.last_error = error;
+    }
+    status.state = state;
+}
+
+/// Keeps a connection open to one of `uris`, failing over to the next hub when
+/// one can't be reached and backing off once every hub has failed.
+async fn maintain_connection(
+    uris: Vec<String>,
+    role: Role,
+    policy: BackoffPolicy,
+    shutdown: CancellationToken,
 ) -> Result<(), Box<dyn Error + Send + Sync>> {
     let mut retry_count = 0;
-    let max_retries = None; // Set to None for infinite retries
     let config = Options::new();
+    let mut redirect: Option<String> = None;
+    let mut order = hub_order(&uris, config.hub.selection);
+    let mut next_hub = 0;
 
     loop {
-        if !running.load(Ordering::SeqCst) {
+        if shutdown.is_cancelled() {
             println!("Shutting down WebSocket client...");
             break;
         }
 
-        let url = &config.hub.ws_uri;
-        println!("Attempting to connect to {url}");
+        let url = redirect
+            .take()
+            .unwrap_or_else(|| order[next_hub % order.len()].clone());
+        println!("[{url}] Attempting to connect");
+        set_status(&url, HubState::Connecting, None).await;
+        let mut requested_delay = None;
 
-        match connect_async(url).await {
+        match connect_async(&url).await {
             Ok((ws_stream, _)) => {
-                println!("WebSocket connection established");
-                // let (mut write, read) = ws_stream.split();
-                let (_, read) = ws_stream.split();
+                println!("[{url}] WebSocket connection established");
+                set_status(&url, HubState::Connected, None).await;
+                let (mut write, read) = ws_stream.split();
 
                 // Reset retry count on successful connection
                 retry_count = 0;
 
-                let receive_task = tokio::spawn(handle_messages(read, running.clone()));
+                let mut receive_task = tokio::spawn(handle_messages(
+                    read,
+                    proxy_response_uri(&config, &url),
+                    config.heartbeat.timeout,
+                    role.clone(),
+                    shutdown.clone(),
+                ));
+
+                // Identify ourselves so the hub can track us across reconnects
+                if let Err(e) = send_hello(&mut write, &config, &role).await {
+                    eprintln!("[{url}] Error sending message: {e}");
+                }
 
-                // Send a test message with error handling
-                // if let Err(e) = send_test_message(&mut write).await {
-                //     eprintln!("Error sending message: {e}");
-                // }
+                // An agent keeps the hub's view of its watched processes and checks
+                // current, and a downstream hub the upstream hub's view of its clients
+                let result = match &role {
+                    Role::Agent => {
+                        let mut report =
+                            tokio::time::interval(Duration::from_secs(ADVERTISE_INTERVAL));
+                        // The hub assumes nothing is watched or checked until told otherwise
+                        let mut reported = Vec::new();
+                        let mut reported_checks = Vec::new();
+                        loop {
+                            tokio::select! {
+                                result = &mut receive_task => break result,
+                                _ = report.tick() => {
+                                    let processes = watch::watched();
+                                    if processes != reported {
+                                        match report_watched(&mut write, &processes).await {
+                                            Ok(()) => reported = processes,
+                                            Err(e) => eprintln!("[{url}] {e}"),
+                                        }
+                                    }
+                                    let checks = checks::states();
+                                    if checks != reported_checks {
+                                        match report_checks(&mut write, &checks).await {
+                                            Ok(()) => reported_checks = checks,
+                                            Err(e) => eprintln!("[{url}] {e}"),
+                                        }
+                                    }
+                                }
+                            }
+                        }
+                    }
+                    Role::Hub(users) => {
+                        let mut advertise =
+                            tokio::time::interval(Duration::from_secs(ADVERTISE_INTERVAL));
+                        loop {
+                            tokio::select! {
+                                result = &mut receive_task => break result,
+                                _ = advertise.tick() => {
+                                    if let Err(e) = advertise_clients(&mut write, users).await {
+                                        eprintln!("[{url}] {e}");
+                                    }
+                                }
+                            }
+                        }
+                    }
+                };
 
                 // Wait for the receive task to complete or error
-                match receive_task.await {
-                    Ok(Ok(())) => println!("Connection closed gracefully"),
-                    Ok(Err(e)) => eprintln!("Connection error: {e}"),
-                    Err(e) => eprintln!("Task error: {e}"),
-                }
+                let error = match result {
+                    Ok(Ok(Some(reconnect))) => {
+                        println!("[{url}] Connection closed by hub");
+                        redirect = reconnect.uri;
+                        requested_delay = Some(reconnect.delay);
+                        None
+                    }
+                    Ok(Ok(None)) => {
+                        println!("[{url}] Connection closed gracefully");
+                        None
+                    }
+                    Ok(Err(e)) => {
+                        eprintln!("[{url}] Connection error: {e}");
+                        Some(e.to_string())
+                    }
+                    Err(e) => {
+                        eprintln!("[{url}] Task error: {e}");
+                        Some(e.to_string())
+                    }
+                };
+                set_status(&url, HubState::Disconnected, error).await;
+                // Start over from the preferred hub
+                order = hub_order(&uris, config.hub.selection);
+                next_hub = 0;
             }
             Err(e) => {
-                eprintln!("Failed to connect: {e}");
+                eprintln!("[{url}] Failed to connect: {e}");
+                set_status(&url, HubState::Failed, Some(e.to_string())).await;
+                // Fail over to the next hub straight away until every hub has been tried
+                next_hub += 1;
+                if next_hub < order.len() && !shutdown.is_cancelled() {
+                    continue;
+                }
+                next_hub = 0;
             }
         }
 
+        if shutdown.is_cancelled() {
+            println!("Shutting down WebSocket client...");
+            break;
+        }
+
+        // A hub going away tells us when to come back, which isn't a failed attempt
+        if let Some(delay) = requested_delay {
+            println!("Reconnecting in {:.1} seconds...", delay.as_secs_f64());
+            sleep_until_interrupted(delay, &shutdown).await?;
+            continue;
+        }
+
         // Handle reconnection
         retry_count += 1;
-        if let Some(max) = max_retries {
-            if retry_count >= max {
-                eprintln!("Max retry attempts ({max}) reached. Exiting.");
-                return Err(Box::<dyn Error + Send + Sync>::from("Max retries exceeded"));
-            }
+        if policy.exhausted(retry_count) {
+            eprintln!("Max retry attempts ({retry_count}) reached. Exiting.");
+            return Err(Box::<dyn Error + Send + Sync>::from("Max retries exceeded"));
         }
 
-        if !running.load(Ordering::SeqCst) {
-            println!("Shutting down WebSocket client...");
-            break;
+        let delay = policy.delay(retry_count, &mut rand::thread_rng());
+        println!("Retrying in {:.1} seconds...", delay.as_secs_f64());
+        sleep_until_interrupted(delay, &shutdown).await?;
+    }
+
+    Ok(())
+}
+
+pub async fn connect_with_retry(
+    shutdown: CancellationToken,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let config = Options::new();
+    if config.hub.ws_uris.is_empty() {
+        return Err(Box::<dyn Error + Send + Sync>::from(
+            "No hub URIs configured",
+        ));
+    }
+    for url in &config.hub.ws_uris {
+        set_status(url, HubState::Disconnected, None).await;
+    }
+    let policy = config.reconnect;
+
+    match config.hub.mode {
+        HubMode::Failover => {
+            maintain_connection(config.hub.ws_uris, Role::Agent, policy, shutdown).await
+        }
+        HubMode::All => {
+            // One independent connection per hub, only giving up once all of them have
+            let results = join_all(config.hub.ws_uris.iter().map(|url| {
+                maintain_connection(vec![url.clone()], Role::Agent, policy, shutdown.clone())
+            }))
+            .await;
+            if results.iter().any(Result::is_ok) {
+                Ok(())
+            } else {
+                Err(Box::<dyn Error + Send + Sync>::from(
+                    "Lost every hub connection",
+                ))
+            }
         }
+    }
+}
+
+/// Connects this hub to its upstream hubs, failing over between them in order.
+pub async fn connect_upstream(
+    users: Users,
+    shutdown: CancellationToken,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let config = Options::new();
+    maintain_connection(
+        config.upstream_uris,
+        Role::Hub(users),
+        config.reconnect,
+        shutdown,
+    )
+    .await
+}
 
-        // Exponential backoff: 1s, 2s, 4s, 8s, etc.
-        let delay = Duration::from_secs(2u64.pow(retry_count - 1));
-        println!("Retrying in {} seconds...", delay.as_secs());
-        sleep_until_interrupted(delay, running.clone()).await?;
+#[derive(Serialize)]
+struct StatusResponse {
+    name: String,
+    hubs: BTreeMap<String, HubStatus>,
+}
+
+/// Reports the state of the client's connection to each hub.
+pub async fn status_handler() -> Result<impl warp::Reply, warp::Rejection> {
+    let config = Options::new();
+    let res = StatusResponse {
+        name: config.client_name,
+        hubs: HUB_STATUS.read().await.clone(),
+    };
+    Ok(warp::reply::json(&res))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::TcpListener;
+    use tokio::sync::mpsc;
+
+    #[tokio::test(start_paused = true)]
+    async fn reconnects_on_the_backoff_schedule_until_out_of_retries() {
+        // A "hub" that hangs up on every connection, noting when each arrived
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("ws://{}/ws", listener.local_addr().unwrap());
+        let (attempts, mut arrived) = mpsc::unbounded_channel();
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                attempts.send(Instant::now()).unwrap();
+                drop(stream);
+            }
+        });
+
+        let policy = BackoffPolicy {
+            base: Duration::from_secs(1),
+            max: Duration::from_secs(3),
+            jitter: false,
+            max_retries: Some(4),
+        };
+        let start = Instant::now();
+        let result =
+            maintain_connection(vec![url], Role::Agent, policy, CancellationToken::new()).await;
+        assert!(result.is_err());
+
+        let mut schedule = Vec::new();
+        while let Ok(at) = arrived.try_recv() {
+            schedule.push(at.duration_since(start).as_secs());
+        }
+        // Waits of 1s, 2s, then 3s once capped, and no attempt after the fourth
+        assert_eq!(schedule, [0, 1, 3, 6]);
+        assert_eq!(start.elapsed().as_secs(), 6);
     }
 
-    Ok(())
+    #[tokio::test(start_paused = true)]
+    async fn shutdown_interrupts_the_backoff() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("ws://{}/ws", listener.local_addr().unwrap());
+        // Nothing listens any more, so every attempt is refused
+        drop(listener);
+
+        let policy = BackoffPolicy {
+            base: Duration::from_secs(3600),
+            max: Duration::from_secs(3600),
+            jitter: false,
+            max_retries: None,
+        };
+        let shutdown = CancellationToken::new();
+        let trigger = shutdown.clone();
+        tokio::spawn(async move {
+            sleep(Duration::from_secs(10)).await;
+            trigger.cancel();
+        });
+        let start = Instant::now();
+        let result = maintain_connection(vec![url], Role::Agent, policy, shutdown).await;
+        assert!(result.is_err());
+        assert_eq!(start.elapsed().as_secs(), 10);
+    }
 }
@@ -1,35 +1,118 @@
+use crate::alerts;
+use crate::config::Options;
+use crate::db;
+use crate::events::{self, EventKind};
+use crate::liveness;
+use crate::protocol::{CheckState, ClientEvent, ClientInfo, HubEvent, WatchedProcess};
+use crate::registry;
+use crate::utils;
+use crate::watch;
+use futures_util::stream::SplitStream;
 use futures_util::{SinkExt, StreamExt, TryFutureExt};
-use std::collections::HashMap;
+use rand::Rng;
+use std::collections::{BTreeMap, HashMap};
 use std::net::SocketAddr;
-use std::sync::{
-    atomic::{AtomicUsize, Ordering},
-    Arc,
-};
+use std::sync::Arc;
 use tokio::sync::{mpsc, RwLock};
+use tokio::time::{self, Duration, Instant};
 use tokio_stream::wrappers::UnboundedReceiverStream;
 use warp::ws::{Message, WebSocket};
 
-static NEXT_USER_ID: AtomicUsize = AtomicUsize::new(1);
-
 pub struct Client {
     pub addr: Option<SocketAddr>,
+    pub checks: Vec<CheckState>,
+    /// Clients advertised by a downstream hub
+    pub children: Vec<ClientInfo>,
+    pub hub: bool,
+    pub labels: BTreeMap<String, String>,
+    pub latency: Option<Duration>,
+    pub name: Option<String>,
     pub sender: mpsc::UnboundedSender<Message>,
+    pub watched: Vec<WatchedProcess>,
+}
+
+fn format_address(addr: Option<SocketAddr>) -> String {
+    addr.map_or_else(
+        || "unknown".to_string(),
+        |a| format!("{}:{}", a.ip(), a.port()),
+    )
+}
+
+impl Client {
+    pub fn address(&self) -> String {
+        format_address(self.addr)
+    }
 }
 
 pub type Users = Arc<RwLock<HashMap<usize, Client>>>;
 
+// How long a new connection has to identify itself before it's dropped
+const HELLO_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Waits for the message a client identifies itself with, which has to be
+/// its first. Anything else, or nothing within `HELLO_TIMEOUT`, is `None`.
+async fn hello(rx: &mut SplitStream<WebSocket>) -> Option<ClientEvent> {
+    let first_text = async {
+        while let Some(Ok(msg)) = rx.next().await {
+            if let Ok(text) = msg.to_str() {
+                return serde_json::from_str::<ClientEvent>(text).ok();
+            }
+        }
+        None
+    };
+    match time::timeout(HELLO_TIMEOUT, first_text).await {
+        Ok(Some(event @ ClientEvent::Hello { .. })) => Some(event),
+        _ => None,
+    }
+}
+
 pub async fn user_connected(ws: WebSocket, addr: Option<SocketAddr>, users: Users) {
-    // Use a counter to assign a new unique ID for this user.
-    let my_id = NEXT_USER_ID.fetch_add(1, Ordering::Relaxed);
     if let Some(addr) = addr {
         println!("Client connected from {}:{}", addr.ip(), addr.port());
     }
-
-    eprintln!("new chat user: {my_id}");
+    let address = format_address(addr);
 
     // Split the socket into a sender and receive of messages.
     let (mut user_ws_tx, mut user_ws_rx) = ws.split();
 
+    // Until it has identified itself, and presented the token if one is set,
+    // the connection isn't a client: it isn't listed, proxied to or pinged
+    let Some(ClientEvent::Hello {
+        name,
+        token,
+        hub,
+        labels,
+    }) = hello(&mut user_ws_rx).await
+    else {
+        eprintln!("Client at {address} didn't identify itself, disconnecting");
+        let _ = user_ws_tx.send(Message::close()).await;
+        return;
+    };
+    let config = Options::new();
+    if config.auth_token.is_some() && token != config.auth_token {
+        eprintln!("Client at {address} ({name}) failed authentication");
+        let reason = if token.is_some() {
+            "invalid token"
+        } else {
+            "missing token"
+        };
+        events::record(EventKind::AuthFailure, Some(&name), &address, reason);
+        let _ = user_ws_tx.send(Message::close()).await;
+        return;
+    }
+
+    // The registry assigns ids so that they are unique across hub instances
+    let registered = address.clone();
+    let my_id = match registry::call(move |registry| registry.register_client(&registered)).await {
+        Ok(id) => id,
+        Err(e) => {
+            eprintln!("Error registering client: {e}");
+            return;
+        }
+    };
+
+    eprintln!("new chat user: {my_id}");
+
     // Use an unbounded channel to handle buffering and flushing of messages
     // to the websocket...
     let (tx, rx) = mpsc::unbounded_channel();
@@ -47,30 +130,123 @@ pub async fn user_connected(ws: WebSocket, addr: Option<SocketAddr>, users: User
     });
 
     // Save the sender in our list of connected users.
-    users
-        .write()
-        .await
-        .insert(my_id, Client { addr, sender: tx });
+    users.write().await.insert(
+        my_id,
+        Client {
+            addr,
+            checks: Vec::new(),
+            children: Vec::new(),
+            hub,
+            labels: labels.clone(),
+            latency: None,
+            name: Some(name.clone()),
+            sender: tx.clone(),
+            watched: Vec::new(),
+        },
+    );
+    identified(my_id, &name, &labels, &address).await;
 
     // Return a `Future` that is basically a state machine managing
     // this specific user's connection.
 
-    // Every time the user sends a message, broadcast it to
-    // all other users...
-    while let Some(result) = user_ws_rx.next().await {
-        let msg = match result {
-            Ok(msg) => msg,
-            Err(e) => {
-                eprintln!("websocket error(uid={my_id}): {e}");
-                break;
+    // Every time the user sends a message, handle it, and ping them
+    // periodically so a half-open connection doesn't linger...
+    let mut heartbeat = time::interval(config.heartbeat.interval);
+    let mut last_heard = Instant::now();
+    let mut ping_seq: u64 = 0;
+    let mut ping_sent: Option<Instant> = None;
+    let mut reason = "connection closed".to_string();
+    loop {
+        tokio::select! {
+            result = user_ws_rx.next() => {
+                let msg = match result {
+                    Some(Ok(msg)) => msg,
+                    Some(Err(e)) => {
+                        eprintln!("websocket error(uid={my_id}): {e}");
+                        reason = e.to_string();
+                        break;
+                    }
+                    None => break,
+                };
+                last_heard = Instant::now();
+                if msg.is_pong() {
+                    // Only the reply to the latest ping gives a meaningful round trip
+                    if msg.as_bytes() == ping_seq.to_be_bytes() {
+                        if let Some(sent) = ping_sent.take() {
+                            record_latency(my_id, sent.elapsed(), &users).await;
+                        }
+                    }
+                    continue;
+                }
+                user_message(my_id, msg, &users).await;
             }
-        };
-        user_message(my_id, msg, &users).await;
+            _ = heartbeat.tick() => {
+                if last_heard.elapsed() > config.heartbeat.timeout {
+                    eprintln!("heartbeat timeout(uid={my_id})");
+                    reason = "heartbeat timeout".to_string();
+                    break;
+                }
+                ping_seq += 1;
+                ping_sent = Some(Instant::now());
+                let _ = tx.send(Message::ping(ping_seq.to_be_bytes()));
+            }
+        }
     }
 
     // user_ws_rx stream will keep processing as long as the user stays
     // connected. Once they disconnect, then...
-    user_disconnected(my_id, &users).await;
+    user_disconnected(my_id, &users, &reason).await;
+}
+
+/// Tells every client the hub is going away, then closes their connections.
+/// Each client is given a random delay up to the configured reconnect delay so
+/// they don't all reconnect at once.
+pub async fn going_away(users: &Users) {
+    let config = Options::new();
+    let max_delay = u64::try_from(config.hub.reconnect_delay.as_millis()).unwrap_or(u64::MAX);
+    let user_map = users.read().await;
+    println!("Sending going away to {} client(s)", user_map.len());
+    let mut rng = rand::thread_rng();
+    for client in user_map.values() {
+        let event = HubEvent::GoingAway {
+            redirect_uri: config.hub.redirect_uri.clone(),
+            reconnect_delay_ms: rng.gen_range(0..=max_delay),
+        };
+        let msg = Message::text(serde_json::to_string(&event).unwrap());
+        // The client may already be gone, nothing to do then
+        let _ = client.sender.send(msg);
+        let _ = client.sender.send(Message::close());
+    }
+}
+
+async fn record_latency(my_id: usize, latency: Duration, users: &Users) {
+    if let Some(client) = users.write().await.get_mut(&my_id) {
+        client.latency = Some(latency);
+    }
+}
+
+/// Records a client that has just identified itself as `name`.
+async fn identified(my_id: usize, name: &str, labels: &BTreeMap<String, String>, address: &str) {
+    println!("User {my_id} identified as {name}");
+    let (owned_name, owned_labels) = (name.to_string(), labels.clone());
+    let identify =
+        registry::call(move |registry| registry.identify_client(my_id, &owned_name, &owned_labels));
+    if let Err(e) = identify.await {
+        eprintln!("Error updating hub registry: {e}");
+    }
+    let kind = match db::is_known_client(name) {
+        Ok(true) => EventKind::Reconnect,
+        Ok(false) => EventKind::Connect,
+        Err(e) => {
+            eprintln!("Error reading known clients: {e}");
+            EventKind::Connect
+        }
+    };
+    events::record(kind, Some(name), address, "");
+    if let Err(e) = db::upsert_known_client(name, address, utils::unix_timestamp()) {
+        eprintln!("Error recording known client: {e}");
+    }
+    alerts::resolve(liveness::HOST_DOWN, &liveness::alert_labels(name)).await;
 }
 
 pub async fn user_message(my_id: usize, msg: Message, users: &Users) {
@@ -79,23 +255,67 @@ pub async fn user_message(my_id: usize, msg: Message, users: &Users) {
         return;
     };
 
-    let new_msg = format!("<User#{my_id}>: {msg}");
+    let event = match serde_json::from_str::<ClientEvent>(msg) {
+        Ok(event) => event,
+        Err(e) => {
+            eprintln!("Ignoring unrecognized message from user {my_id}: {e}");
+            return;
+        }
+    };
 
-    // New message from this user, send it to everyone else (except same uid)...
-    for (&uid, client) in users.read().await.iter() {
-        if my_id != uid {
-            if let Err(_disconnected) = client.sender.send(Message::text(new_msg.clone())) {
-                // The tx is disconnected, our `user_disconnected` code
-                // should be happening in another task, nothing more to
-                // do here.
+    match event {
+        // Only the first message identifies the client, see `hello`
+        ClientEvent::Hello { name, .. } => {
+            eprintln!("Ignoring repeated hello from user {my_id} ({name})");
+        }
+        ClientEvent::Clients { clients } => {
+            if let Some(client) = users.write().await.get_mut(&my_id) {
+                if client.hub {
+                    client.children = clients;
+                }
+            }
+        }
+        ClientEvent::Watched { processes } => {
+            let mut user_map = users.write().await;
+            let Some(client) = user_map.get_mut(&my_id) else {
+                return;
+            };
+            let previous = std::mem::replace(&mut client.watched, processes.clone());
+            let name = client.name.clone();
+            drop(user_map);
+            if let Some(name) = name {
+                watch::update_alerts(&name, &previous, &processes).await;
+            }
+        }
+        ClientEvent::Checks { checks } => {
+            if let Some(client) = users.write().await.get_mut(&my_id) {
+                client.checks = checks;
             }
         }
     }
 }
 
-pub async fn user_disconnected(my_id: usize, users: &Users) {
+pub async fn user_disconnected(my_id: usize, users: &Users, reason: &str) {
     eprintln!("good bye user: {my_id}");
+    if let Err(e) = registry::call(move |registry| registry.unregister_client(my_id)).await {
+        eprintln!("Error updating hub registry: {e}");
+    }
 
     // Stream closed up, so remove from the user list
-    users.write().await.remove(&my_id);
+    let Some(client) = users.write().await.remove(&my_id) else {
+        return;
+    };
+    events::record(
+        EventKind::Disconnect,
+        client.name.as_deref(),
+        &client.address(),
+        reason,
+    );
+    if let Some(name) = client.name {
+        if let Err(e) = db::touch_known_client(&name, utils::unix_timestamp()) {
+            eprintln!("Error updating last seen time: {e}");
+        }
+        // Its processes can't be told apart from the client being down, which HostDown covers
+        watch::update_alerts(&name, &client.watched, &[]).await;
+    }
 }